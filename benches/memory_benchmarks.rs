@@ -5,7 +5,7 @@ use criterion::BenchmarkId;
 use criterion::Throughput;
 
 fn benchmark_cache_retrieval(c: &mut Criterion) {
-    let mut cache = MemoryCache::new(100);
+    let cache = MemoryCache::new(100, 0.0);
 
     // Add memories to cache
     for i in 0..100 {
@@ -23,7 +23,7 @@ fn benchmark_cache_retrieval(c: &mut Criterion) {
 
 // Add more benchmark functions here
 fn benchmark_cache_insertion(c: &mut Criterion) {
-    let mut cache = MemoryCache::new(100);
+    let cache = MemoryCache::new(100, 0.0);
     let mut i = 0;
 
     c.bench_function("cache insertion", |b| {
@@ -42,7 +42,7 @@ fn benchmark_cache_sizes(c: &mut Criterion) {
     for size in [10, 100, 1000].iter() {
         group.throughput(Throughput::Elements(*size as u64));
         group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, &size| {
-            let mut cache = MemoryCache::new(size);
+            let cache = MemoryCache::new(size, 0.0);
             // Setup cache with 'size' elements
             for i in 0..size {
                 let entry = MemoryEntry::new(i as u16, 500);
@@ -60,10 +60,34 @@ fn benchmark_cache_sizes(c: &mut Criterion) {
     group.finish();
 }
 
+fn benchmark_cache_eviction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache_eviction");
+
+    for size in [100, 1000, 10000].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, &size| {
+            let cache = MemoryCache::new(size, 0.0);
+            for i in 0..size {
+                let entry = MemoryEntry::with_links(i as u32, 0, (i % 30000) as i16, 0, 0);
+                cache.add_memory(entry, HashSet::new());
+            }
+
+            // Every insert lands in a full cache and evicts one memory
+            let mut epoch = size as u32;
+            b.iter(|| {
+                let entry = MemoryEntry::with_links(epoch, 0, 30000, 0, 0);
+                cache.add_memory(entry, HashSet::new());
+                epoch += 1;
+            });
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(
     benches,
     benchmark_cache_retrieval,
     benchmark_cache_insertion,
-    benchmark_cache_sizes
+    benchmark_cache_sizes,
+    benchmark_cache_eviction
 );
 criterion_main!(benches); 
\ No newline at end of file
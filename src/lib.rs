@@ -4,8 +4,6 @@ pub mod utils;
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn test_example() {
         assert_eq!(2 + 2, 4);
@@ -0,0 +1,194 @@
+//! Single-file export of a whole memory store.
+//!
+//! An archive is the magic bytes and a format version, a header naming the
+//! compression algorithm, entry count and payload CRC, then the compressed,
+//! serialized entries. Everything after the version is only parsed once the
+//! version is known to be supported.
+
+use super::compression::{CompressionAlgorithm, CompressionError, Compressor};
+use super::entry::{ExpiringMemoryEntry, MemoryEntry};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use thiserror::Error;
+
+/// First bytes of every archive
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"MEM8";
+
+/// Format version written by `write_archive`
+///
+/// Version 1 archives, whose entries predate pinning, are still read.
+pub const ARCHIVE_VERSION: u16 = 2;
+
+const PREFIX_SIZE: usize = ARCHIVE_MAGIC.len() + std::mem::size_of::<u16>();
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Not a memory archive")]
+    BadMagic,
+    #[error("Unsupported archive version {found}, expected {ARCHIVE_VERSION}")]
+    UnsupportedVersion { found: u16 },
+    #[error("Archive payload checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Archive holds {found} entries, header says {expected}")]
+    EntryCountMismatch { expected: u64, found: u64 },
+    #[error("Decompression error: {0}")]
+    Decompression(#[from] CompressionError),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveHeader {
+    algorithm: CompressionAlgorithm,
+    entry_count: u64,
+    payload_len: u64,
+    payload_crc: u32,
+}
+
+/// Writes `entries` to `path` as a single archive compressed with
+/// `algorithm`, replacing any existing file
+pub fn write_archive(path: &Path, entries: &[MemoryEntry], algorithm: CompressionAlgorithm) -> io::Result<()> {
+    let to_io = |e: bincode::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let (payload, metrics) = Compressor::new(algorithm).compress(&serialize(entries).map_err(to_io)?);
+    let header = ArchiveHeader {
+        algorithm: metrics.algorithm,
+        entry_count: entries.len() as u64,
+        payload_len: payload.len() as u64,
+        payload_crc: crc32fast::hash(&payload),
+    };
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&ARCHIVE_MAGIC)?;
+    writer.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+    writer.write_all(&serialize(&header).map_err(to_io)?)?;
+    writer.write_all(&payload)?;
+    writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()
+}
+
+/// Reads every entry from an archive written by `write_archive`
+///
+/// The magic bytes, version and payload CRC are all checked before anything
+/// is decompressed.
+pub fn read_archive(path: &Path) -> Result<Vec<MemoryEntry>, ArchiveError> {
+    let bytes = std::fs::read(path)?;
+    if bytes.len() < PREFIX_SIZE || bytes[..ARCHIVE_MAGIC.len()] != ARCHIVE_MAGIC {
+        return Err(ArchiveError::BadMagic);
+    }
+    let version = u16::from_le_bytes([bytes[ARCHIVE_MAGIC.len()], bytes[ARCHIVE_MAGIC.len() + 1]]);
+    if !(1..=ARCHIVE_VERSION).contains(&version) {
+        return Err(ArchiveError::UnsupportedVersion { found: version });
+    }
+
+    let rest = &bytes[PREFIX_SIZE..];
+    let header: ArchiveHeader = deserialize(rest)?;
+    let header_len = bincode::serialized_size(&header)? as usize;
+    let payload = &rest[header_len..];
+    if payload.len() as u64 != header.payload_len || crc32fast::hash(payload) != header.payload_crc {
+        return Err(ArchiveError::ChecksumMismatch);
+    }
+
+    let raw = Compressor::new(header.algorithm).decompress(payload)?;
+    let entries: Vec<MemoryEntry> = if version == 1 {
+        let old: Vec<ExpiringMemoryEntry> = deserialize(&raw)?;
+        old.into_iter().map(MemoryEntry::from).collect()
+    } else {
+        deserialize(&raw)?
+    };
+    if entries.len() as u64 != header.entry_count {
+        return Err(ArchiveError::EntryCountMismatch {
+            expected: header.entry_count,
+            found: entries.len() as u64,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_archive_round_trip() -> Result<(), ArchiveError> {
+        let dir = tempdir()?;
+        let entries: Vec<MemoryEntry> = (1..=50)
+            .map(|epoch| MemoryEntry::with_links(epoch, (epoch % 7) as u16, 500, epoch - 1, 0))
+            .collect();
+
+        for algorithm in [
+            CompressionAlgorithm::None,
+            CompressionAlgorithm::LZ4,
+            CompressionAlgorithm::zstd(),
+            CompressionAlgorithm::gzip(),
+        ] {
+            let path = dir.path().join("store.mem8");
+            write_archive(&path, &entries, algorithm)?;
+
+            let restored = read_archive(&path)?;
+            assert_eq!(restored.len(), entries.len());
+            for (a, b) in entries.iter().zip(&restored) {
+                assert_eq!(a.epoch(), b.epoch());
+                assert_eq!(a.token(), b.token());
+                assert_eq!(a.links(), b.links());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_archive_rejected() -> Result<(), ArchiveError> {
+        let dir = tempdir()?;
+        let path = dir.path().join("store.mem8");
+        write_archive(&path, &[MemoryEntry::with_links(1, 100, 500, 0, 0)], CompressionAlgorithm::zstd())?;
+        let original = std::fs::read(&path)?;
+
+        let mut bytes = original.clone();
+        bytes[0] = b'X';
+        std::fs::write(&path, &bytes)?;
+        assert!(matches!(read_archive(&path), Err(ArchiveError::BadMagic)));
+
+        let mut bytes = original.clone();
+        bytes[ARCHIVE_MAGIC.len()..PREFIX_SIZE].copy_from_slice(&3u16.to_le_bytes());
+        std::fs::write(&path, &bytes)?;
+        assert!(matches!(read_archive(&path), Err(ArchiveError::UnsupportedVersion { found: 3 })));
+
+        let mut bytes = original;
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(&path, &bytes)?;
+        assert!(matches!(read_archive(&path), Err(ArchiveError::ChecksumMismatch)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reads_version_1_archive() -> Result<(), ArchiveError> {
+        // Entries as serialized before they could be pinned
+        let old_entries = vec![(7u32, 100u16, 500i16, 0u32, 0u32, Some(90u32))];
+        let payload = serialize(&old_entries)?;
+        let header = ArchiveHeader {
+            algorithm: CompressionAlgorithm::None,
+            entry_count: 1,
+            payload_len: payload.len() as u64,
+            payload_crc: crc32fast::hash(&payload),
+        };
+        let mut bytes = ARCHIVE_MAGIC.to_vec();
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&serialize(&header)?);
+        bytes.extend_from_slice(&payload);
+
+        let dir = tempdir()?;
+        let path = dir.path().join("old.mem8");
+        std::fs::write(&path, &bytes)?;
+        let entries = read_archive(&path)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].epoch(), entries[0].expires_at()), (7, Some(90)));
+        assert!(!entries[0].is_pinned());
+        Ok(())
+    }
+}
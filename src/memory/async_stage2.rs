@@ -0,0 +1,418 @@
+//! Stage 2 storage on top of `tokio::fs`, for callers inside an async runtime.
+//!
+//! Files use the same block, checksum and write-ahead log format and the
+//! same `shard_by` layout as `Stage2`, so either can open a directory
+//! written by the other. Content
+//! deduplication is not supported: a config with `dedup_by_content`, or a
+//! directory holding a `Stage2` alias log, is rejected on open.
+
+use super::entry::MemoryEntry;
+use super::stage2::{
+    shard_path, MemoryBlock, ShardBy, Stage2Config, Stage2Error, WalRecord, WriteAheadLog, ALIAS_FILE_NAME,
+    INDEX_FILE_NAME, LENGTH_PREFIX_SIZE,
+};
+use super::token_index::TokenIndex;
+use bincode::{deserialize, serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Async counterpart of `Stage2`
+pub struct AsyncStage2 {
+    config: Stage2Config,
+    // In-memory index of epoch -> (file, offset, serialized length)
+    index: BTreeMap<u32, (PathBuf, u64, u64)>,
+    tokens: TokenIndex,
+    current_file: Option<File>,
+    current_file_path: Option<PathBuf>,
+    current_file_entries: usize,
+    wal: WriteAheadLog,
+}
+
+impl AsyncStage2 {
+    /// Opens or creates a store, failing with `ErrorKind::Unsupported` for
+    /// deduplicated stores, whose aliased epochs this type cannot index
+    pub async fn new(config: Stage2Config) -> io::Result<Self> {
+        if config.dedup_by_content {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "async stage 2 does not deduplicate content"));
+        }
+        fs::create_dir_all(&config.storage_path).await?;
+        if fs::try_exists(config.storage_path.join(ALIAS_FILE_NAME)).await? {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "store holds deduplicated entries"));
+        }
+
+        // Undo any write that was interrupted before the index is rebuilt
+        let wal = WriteAheadLog::new(&config.storage_path);
+        Self::recover_wal(&wal).await?;
+
+        let mut stage2 = Self {
+            wal,
+            config,
+            index: BTreeMap::new(),
+            tokens: TokenIndex::new(),
+            current_file: None,
+            current_file_path: None,
+            current_file_entries: 0,
+        };
+
+        stage2.load_index().await?;
+        Ok(stage2)
+    }
+
+    /// Accepts aged entries from Stage 1
+    pub async fn accept_entries(&mut self, entries: Vec<MemoryEntry>) -> Result<(), Stage2Error> {
+        for entry in entries {
+            self.store_entry(entry).await?;
+        }
+        Ok(())
+    }
+
+    /// Stores a single memory entry
+    pub async fn store_entry(&mut self, entry: MemoryEntry) -> Result<(), Stage2Error> {
+        // Read before writing, so the old token can be dropped below
+        let previous = if self.index.contains_key(&entry.epoch()) {
+            Some(self.get_entry(entry.epoch()).await)
+        } else {
+            None
+        };
+
+        let mut shard_file;
+        let (file, current_path) = match self.config.shard_by {
+            ShardBy::Time => {
+                if self.current_file.is_none()
+                    || self.current_file_entries >= self.config.entries_per_file {
+                    self.rotate_file().await?;
+                }
+                (self.current_file.as_mut().unwrap(), self.current_file_path.clone().unwrap())
+            }
+            // A stored epoch stays in its shard, as in Stage2
+            ShardBy::TokenRange(shards) => {
+                let path = match self.index.get(&entry.epoch()) {
+                    Some((path, _, _)) => path.clone(),
+                    None => shard_path(&self.config.storage_path, entry.token() % shards.max(1)),
+                };
+                shard_file = OpenOptions::new().create(true).append(true).open(&path).await?;
+                (&mut shard_file, path)
+            }
+        };
+        let block = MemoryBlock::new(&entry, self.config.checksum_algorithm)?;
+        let pos = file.seek(SeekFrom::End(0)).await?;
+        let encoded = serialize(&block)?;
+
+        Self::begin_wal(&self.wal, &WalRecord {
+            epoch: entry.epoch(),
+            file: current_path.clone(),
+            offset: pos,
+            len: LENGTH_PREFIX_SIZE + encoded.len() as u64,
+        }).await?;
+
+        file.write_all(&(encoded.len() as u64).to_le_bytes()).await?;
+        file.write_all(&encoded).await?;
+        file.flush().await?;
+        file.sync_data().await?;
+
+        Self::commit_wal(&self.wal).await?;
+
+        self.index.insert(entry.epoch(), (current_path, pos + LENGTH_PREFIX_SIZE, encoded.len() as u64));
+        match previous {
+            Some(Ok(previous)) => self.tokens.remove(previous.token(), entry.epoch()),
+            // The old block is unreadable, so its token is unknown
+            Some(Err(_)) => self.tokens.remove_epoch(entry.epoch()),
+            None => {}
+        }
+        self.tokens.insert(entry.token(), entry.epoch());
+        self.current_file_entries += 1;
+
+        Ok(())
+    }
+
+    /// Retrieves a memory entry by epoch
+    pub async fn get_entry(&self, epoch: u32) -> Result<MemoryEntry, Stage2Error> {
+        let (path, pos, len) = self.index.get(&epoch)
+            .ok_or(Stage2Error::NotFound(epoch))?;
+
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(*pos)).await?;
+        let mut buffer = vec![0u8; *len as usize];
+        file.read_exact(&mut buffer).await?;
+
+        deserialize::<MemoryBlock>(&buffer)?
+            .verified_entry()?
+            .ok_or(Stage2Error::ChecksumMismatch(epoch))
+    }
+
+    /// Returns every stored epoch for `token`, in epoch order
+    pub fn find_epochs_by_token(&self, token: u16) -> Vec<u32> {
+        self.tokens.epochs(token).collect()
+    }
+
+    async fn begin_wal(wal: &WriteAheadLog, record: &WalRecord) -> Result<(), Stage2Error> {
+        let mut file = File::create(&wal.path).await?;
+        file.write_all(&serialize(record)?).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn commit_wal(wal: &WriteAheadLog) -> io::Result<()> {
+        File::create(&wal.path).await?.sync_all().await
+    }
+
+    /// Truncates a torn block left behind by a crash mid-write
+    async fn recover_wal(wal: &WriteAheadLog) -> io::Result<()> {
+        let bytes = match fs::read(&wal.path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if let Ok(record) = deserialize::<WalRecord>(&bytes) {
+            if let Ok(file) = OpenOptions::new().write(true).open(&record.file).await {
+                let file_len = file.metadata().await?.len();
+                if file_len > record.offset && file_len < record.offset + record.len {
+                    file.set_len(record.offset).await?;
+                    file.sync_all().await?;
+                }
+            }
+        }
+
+        Self::commit_wal(wal).await
+    }
+
+    async fn rotate_file(&mut self) -> io::Result<()> {
+        let path = self.new_file_path().await?;
+        self.current_file = Some(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?);
+        self.current_file_path = Some(path);
+        self.current_file_entries = 0;
+        Ok(())
+    }
+
+    async fn new_file_path(&self) -> io::Result<PathBuf> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut path = self.config.storage_path.join(format!("mem_{}.bin", timestamp));
+        let mut sequence = 1;
+        while fs::try_exists(&path).await? {
+            path = self.config.storage_path.join(format!("mem_{}_{}.bin", timestamp, sequence));
+            sequence += 1;
+        }
+        Ok(path)
+    }
+
+    async fn load_index(&mut self) -> io::Result<()> {
+        let mut paths = Vec::new();
+        let mut dir = fs::read_dir(&self.config.storage_path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            // The saved index goes stale once this store appends; Stage2
+            // notices that from the file sizes and rescans
+            if path.extension().is_some_and(|ext| ext == "bin") && entry.file_name() != INDEX_FILE_NAME {
+                paths.push(path);
+            }
+        }
+        // Later files win for an epoch stored more than once, as in Stage2
+        paths.sort();
+
+        // Token of the block each epoch ends up pointing at
+        let mut tokens = HashMap::new();
+        for path in paths {
+            self.index_file(&path, &mut tokens).await?;
+        }
+        for (epoch, token) in tokens {
+            self.tokens.insert(token, epoch);
+        }
+        Ok(())
+    }
+
+    async fn index_file(&mut self, path: &Path, tokens: &mut HashMap<u32, u16>) -> io::Result<()> {
+        let bytes = fs::read(path).await?;
+        let file_len = bytes.len() as u64;
+        let mut pos = 0;
+
+        while file_len - pos >= LENGTH_PREFIX_SIZE {
+            let prefix = &bytes[pos as usize..(pos + LENGTH_PREFIX_SIZE) as usize];
+            let len = u64::from_le_bytes(prefix.try_into().unwrap());
+            let block_pos = pos + LENGTH_PREFIX_SIZE;
+            if len > file_len - block_pos {
+                // Trailing partial block
+                break;
+            }
+
+            let buffer = &bytes[block_pos as usize..(block_pos + len) as usize];
+            let entry = deserialize::<MemoryBlock>(buffer)
+                .ok()
+                .and_then(|block| block.raw_payload().ok())
+                .and_then(|raw| MemoryEntry::from_bytes(&raw).ok());
+            if let Some(entry) = entry {
+                self.index.insert(entry.epoch(), (path.to_path_buf(), block_pos, len));
+                tokens.insert(entry.epoch(), entry.token());
+            }
+            pos = block_pos + len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::stage2::Stage2;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_async_round_trip_interchangeable_with_sync() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 2,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = AsyncStage2::new(config.clone()).await?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+            MemoryEntry::with_links(2, 101, 600, 1, 0),
+            MemoryEntry::with_links(3, 100, 700, 0, 0),
+        ]).await?;
+        assert_eq!(stage2.get_entry(2).await?.links(), (1, 0));
+        assert_eq!(stage2.find_epochs_by_token(100), vec![1, 3]);
+        drop(stage2);
+
+        // The sync store reads what the async one wrote, and vice versa
+        let mut sync_stage2 = Stage2::new(config.clone())?;
+        assert_eq!(sync_stage2.get_entry(3)?.weight(), 700);
+        sync_stage2.accept_entries(vec![MemoryEntry::with_links(4, 102, 800, 0, 0)])?;
+        drop(sync_stage2);
+
+        let reopened = AsyncStage2::new(config).await?;
+        for epoch in 1..=4 {
+            assert_eq!(reopened.get_entry(epoch).await?.epoch(), epoch);
+        }
+        assert!(matches!(reopened.get_entry(5).await, Err(Stage2Error::NotFound(5))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_token_range_sharding_matches_sync() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            shard_by: ShardBy::TokenRange(2),
+            ..Stage2Config::default()
+        };
+
+        let mut sync_stage2 = Stage2::new(config.clone())?;
+        sync_stage2.accept_entries(vec![MemoryEntry::with_links(10, 100, 500, 0, 0)])?;
+        drop(sync_stage2);
+
+        // Storing epoch 10 again keeps it in shard 0, whatever its token
+        let mut stage2 = AsyncStage2::new(config.clone()).await?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(10, 101, 999, 0, 0),
+            MemoryEntry::with_links(11, 101, 600, 0, 0),
+        ]).await?;
+        drop(stage2);
+
+        let mut files: Vec<String> = std::fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".bin"))
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["shard_0.bin", "shard_1.bin"]);
+
+        let reopened = AsyncStage2::new(config.clone()).await?;
+        assert_eq!(reopened.get_entry(10).await?.weight(), 999);
+        assert_eq!(reopened.get_entry(11).await?.weight(), 600);
+        drop(reopened);
+        assert_eq!(Stage2::new(config)?.get_entry(10)?.weight(), 999);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_replaces_token() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = AsyncStage2::new(config.clone()).await?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(10, 100, 500, 0, 0),
+            MemoryEntry::with_links(11, 100, 600, 0, 0),
+        ]).await?;
+        stage2.store_entry(MemoryEntry::with_links(10, 200, 700, 0, 0)).await?;
+        assert_eq!(stage2.find_epochs_by_token(100), vec![11]);
+        assert_eq!(stage2.find_epochs_by_token(200), vec![10]);
+        drop(stage2);
+
+        let reopened = AsyncStage2::new(config).await?;
+        assert_eq!(reopened.find_epochs_by_token(100), vec![11]);
+        assert_eq!(reopened.get_entry(10).await?.weight(), 700);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_torn_tail_with_huge_length_prefix() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = AsyncStage2::new(config.clone()).await?;
+        stage2.accept_entries(vec![MemoryEntry::with_links(1, 100, 500, 0, 0)]).await?;
+        let path = stage2.current_file_path.clone().unwrap();
+        drop(stage2);
+
+        // A torn append whose prefix claims far more than the file holds
+        let mut file = OpenOptions::new().append(true).open(&path).await?;
+        file.write_all(&u64::MAX.to_le_bytes()).await?;
+        file.write_all(&[0xAB; 5]).await?;
+        drop(file);
+
+        let reopened = AsyncStage2::new(config).await?;
+        assert_eq!(reopened.get_entry(1).await?.weight(), 500);
+        assert_eq!(reopened.find_epochs_by_token(100), vec![1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejects_deduplicated_stores() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            dedup_by_content: true,
+            ..Stage2Config::default()
+        };
+
+        let err = AsyncStage2::new(config.clone()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        // A directory a deduplicating Stage2 wrote to is refused as well
+        let mut sync_stage2 = Stage2::new(config.clone())?;
+        sync_stage2.accept_entries(vec![
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+            MemoryEntry::with_links(2, 100, 500, 0, 0),
+        ])?;
+        drop(sync_stage2);
+
+        let config = Stage2Config { dedup_by_content: false, ..config };
+        let err = AsyncStage2::new(config).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! Checksums used to detect corrupted blocks.
+
+use serde::{Deserialize, Serialize};
+
+/// Hash used to verify a stored block
+///
+/// Blocks record the algorithm they were written with, so stores can mix
+/// algorithms after a configuration change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Crc32,
+    XxHash64,
+    /// BLAKE3, truncated to its first 8 bytes
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    pub fn compute(self, data: &[u8]) -> u64 {
+        match self {
+            ChecksumAlgorithm::Crc32 => crc32fast::hash(data) as u64,
+            ChecksumAlgorithm::XxHash64 => xxhash_rust::xxh64::xxh64(data, 0),
+            ChecksumAlgorithm::Blake3 => {
+                let hash = blake3::hash(data);
+                u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_algorithms_detect_flip() {
+        let data = b"memory block contents".to_vec();
+        let mut flipped = data.clone();
+        flipped[3] ^= 0x01;
+
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::XxHash64,
+            ChecksumAlgorithm::Blake3,
+        ] {
+            assert_eq!(algorithm.compute(&data), algorithm.compute(&data));
+            assert_ne!(algorithm.compute(&data), algorithm.compute(&flipped));
+        }
+        assert_eq!(ChecksumAlgorithm::Crc32.compute(&data), crc32fast::hash(&data) as u64);
+    }
+}
@@ -0,0 +1,103 @@
+//! Conversion between wall-clock time and memory epochs.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current epoch for stamping and aging memories
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now_epoch(&self) -> u32;
+}
+
+/// Maps wall-clock time onto epochs counted from a seed time
+///
+/// Epochs are seconds since `seed_epoch`, itself given in seconds since the
+/// UNIX epoch. The default seed of zero makes epochs plain UNIX seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryClock {
+    seed_epoch: u32,
+}
+
+impl MemoryClock {
+    pub fn new(seed_epoch: u32) -> Self {
+        Self { seed_epoch }
+    }
+
+    pub fn seed_epoch(&self) -> u32 {
+        self.seed_epoch
+    }
+
+    /// The current epoch
+    pub fn now(&self) -> u32 {
+        self.epoch_at(SystemTime::now())
+    }
+
+    /// The epoch for a wall-clock time; times before the seed map to zero
+    pub fn epoch_at(&self, time: SystemTime) -> u32 {
+        let unix = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        unix.saturating_sub(self.seed_epoch as u64) as u32
+    }
+
+    /// The wall-clock time an epoch refers to
+    pub fn timestamp(&self, epoch: u32) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.seed_epoch as u64 + epoch as u64)
+    }
+}
+
+impl Clock for MemoryClock {
+    fn now_epoch(&self) -> u32 {
+        self.now()
+    }
+}
+
+/// Clock that only moves when told to, for deterministic tests
+#[derive(Debug, Default)]
+pub struct MockClock {
+    epoch: AtomicU32,
+}
+
+impl MockClock {
+    pub fn new(epoch: u32) -> Self {
+        Self { epoch: AtomicU32::new(epoch) }
+    }
+
+    pub fn set(&self, epoch: u32) {
+        self.epoch.store(epoch, Ordering::Relaxed);
+    }
+
+    /// Moves the clock forward by `seconds`
+    pub fn advance(&self, seconds: u32) {
+        self.epoch.fetch_add(seconds, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_epoch(&self) -> u32 {
+        self.epoch.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_round_trip() {
+        let clock = MemoryClock::new(1_000_000);
+        let time = UNIX_EPOCH + Duration::from_secs(1_000_250);
+
+        assert_eq!(clock.epoch_at(time), 250);
+        assert_eq!(clock.timestamp(250), time);
+        assert_eq!(clock.epoch_at(UNIX_EPOCH), 0);
+        assert_eq!(MemoryClock::default().epoch_at(time), 1_000_250);
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(100);
+        clock.advance(50);
+        assert_eq!(clock.now_epoch(), 150);
+        clock.set(7);
+        assert_eq!(clock.now_epoch(), 7);
+    }
+}
@@ -1,12 +1,69 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use lz4_flex::block::DecompressError;
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
 use lz4_flex::{compress_prepend_size, decompress_size_prepended};
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
 use std::time::Duration;
+use thiserror::Error;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Zstandard level used when none is specified
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Gzip level used when none is specified
+pub const DEFAULT_GZIP_LEVEL: u32 = 6;
+
+/// Compressed-to-original size ratio above which `compress` keeps the data
+/// uncompressed
+pub const DEFAULT_MAX_COMPRESSION_RATIO: f32 = 0.95;
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("LZ4 decompression error: {0}")]
+    Lz4(#[from] DecompressError),
+    #[error("Zstd decompression error: {0}")]
+    Zstd(io::Error),
+    #[error("Gzip decompression error: {0}")]
+    Gzip(io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompressionAlgorithm {
     None,
     LZ4,
-    // Future: Add Zstandard, etc.
+    Zstd { level: i32 },
+    /// Standard gzip, readable by `gzip -d` and other external tools
+    Gzip { level: u32 },
+}
+
+impl CompressionAlgorithm {
+    /// Zstandard at the default level
+    pub fn zstd() -> Self {
+        CompressionAlgorithm::Zstd { level: DEFAULT_ZSTD_LEVEL }
+    }
+
+    /// Gzip at the default level
+    pub fn gzip() -> Self {
+        CompressionAlgorithm::Gzip { level: DEFAULT_GZIP_LEVEL }
+    }
+
+    /// Clamps algorithm parameters into their supported ranges
+    pub fn normalized(self) -> Self {
+        match self {
+            CompressionAlgorithm::Zstd { level } => {
+                let range = zstd::compression_level_range();
+                CompressionAlgorithm::Zstd {
+                    level: level.clamp(*range.start(), *range.end()),
+                }
+            }
+            CompressionAlgorithm::Gzip { level } => CompressionAlgorithm::Gzip {
+                level: level.min(Compression::best().level()),
+            },
+            other => other,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,13 +85,34 @@ impl CompressionMetrics {
 
 pub struct Compressor {
     algorithm: CompressionAlgorithm,
+    max_ratio: f32,
 }
 
 impl Compressor {
     pub fn new(algorithm: CompressionAlgorithm) -> Self {
-        Self { algorithm }
+        Self {
+            algorithm: algorithm.normalized(),
+            max_ratio: DEFAULT_MAX_COMPRESSION_RATIO,
+        }
+    }
+
+    /// Sets the compression ratio above which `compress` gives up and keeps
+    /// the original bytes
+    pub fn with_max_ratio(mut self, max_ratio: f32) -> Self {
+        self.max_ratio = max_ratio;
+        self
+    }
+
+    /// The algorithm this compressor applies
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
     }
 
+    /// Compresses `data` in one go
+    ///
+    /// If the result is not smaller than `max_ratio` of the input, the input
+    /// is returned as is and the metrics record `CompressionAlgorithm::None`;
+    /// decompress with a compressor for the recorded algorithm.
     pub fn compress(&self, data: &[u8]) -> (Vec<u8>, CompressionMetrics) {
         let start = std::time::Instant::now();
         let original_size = data.len();
@@ -45,23 +123,275 @@ impl Compressor {
                 let compressed = compress_prepend_size(data);
                 (compressed.clone(), compressed.len())
             }
+            CompressionAlgorithm::Zstd { level } => {
+                // The level is clamped in `new`, so encoding an in-memory
+                // buffer can only fail on allocation failure
+                let compressed = zstd::stream::encode_all(data, level)
+                    .expect("zstd compression of an in-memory buffer failed");
+                let len = compressed.len();
+                (compressed, len)
+            }
+            CompressionAlgorithm::Gzip { level } => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+                encoder.write_all(data)
+                    .and_then(|()| encoder.finish())
+                    .map(|compressed| {
+                        let len = compressed.len();
+                        (compressed, len)
+                    })
+                    .expect("gzip compression into an in-memory buffer failed")
+            }
         };
 
-        let metrics = CompressionMetrics {
+        let mut metrics = CompressionMetrics {
             original_size,
             compressed_size,
             compression_time: start.elapsed(),
             algorithm: self.algorithm,
         };
 
+        // Dense data can come out barely smaller or even larger
+        if self.algorithm != CompressionAlgorithm::None && metrics.compression_ratio() > self.max_ratio {
+            metrics.compressed_size = original_size;
+            metrics.algorithm = CompressionAlgorithm::None;
+            return (data.to_vec(), metrics);
+        }
+
         (compressed_data, metrics)
     }
 
-    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
         match self.algorithm {
             CompressionAlgorithm::None => Ok(data.to_vec()),
-            CompressionAlgorithm::LZ4 => decompress_size_prepended(data)
-                .map_err(|e| format!("LZ4 decompression error: {}", e)),
+            CompressionAlgorithm::LZ4 => Ok(decompress_size_prepended(data)?),
+            CompressionAlgorithm::Zstd { .. } => zstd::stream::decode_all(data)
+                .map_err(CompressionError::Zstd),
+            CompressionAlgorithm::Gzip { .. } => {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(data)
+                    .read_to_end(&mut decompressed)
+                    .map(|_| decompressed)
+                    .map_err(CompressionError::Gzip)
+            }
+        }
+    }
+
+    /// Compresses everything from `reader` into `writer` without buffering
+    /// the whole input
+    ///
+    /// LZ4 output uses the frame format, so it is not interchangeable with
+    /// the size-prefixed blocks produced by `compress`.
+    pub fn compress_stream<R: Read, W: Write>(&self, reader: R, writer: W) -> io::Result<CompressionMetrics> {
+        let start = std::time::Instant::now();
+        let mut reader = CountingReader { inner: reader, count: 0 };
+        let mut writer = CountingWriter { inner: writer, count: 0 };
+
+        match self.algorithm {
+            CompressionAlgorithm::None => {
+                io::copy(&mut reader, &mut writer)?;
+            }
+            CompressionAlgorithm::LZ4 => {
+                let mut encoder = FrameEncoder::new(&mut writer);
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish().map_err(io::Error::other)?;
+            }
+            CompressionAlgorithm::Zstd { level } => {
+                zstd::stream::copy_encode(&mut reader, &mut writer, level)?;
+            }
+            CompressionAlgorithm::Gzip { level } => {
+                let mut encoder = GzEncoder::new(&mut writer, Compression::new(level));
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        writer.flush()?;
+
+        Ok(CompressionMetrics {
+            original_size: reader.count as usize,
+            compressed_size: writer.count as usize,
+            compression_time: start.elapsed(),
+            algorithm: self.algorithm,
+        })
+    }
+
+    /// Decompresses a stream written by `compress_stream`, returning the
+    /// number of bytes written
+    pub fn decompress_stream<R: Read, W: Write>(&self, reader: R, writer: W) -> io::Result<u64> {
+        let mut writer = CountingWriter { inner: writer, count: 0 };
+
+        match self.algorithm {
+            CompressionAlgorithm::None => {
+                let mut reader = reader;
+                io::copy(&mut reader, &mut writer)?;
+            }
+            CompressionAlgorithm::LZ4 => {
+                io::copy(&mut FrameDecoder::new(reader), &mut writer)?;
+            }
+            CompressionAlgorithm::Zstd { .. } => {
+                zstd::stream::copy_decode(reader, &mut writer)?;
+            }
+            CompressionAlgorithm::Gzip { .. } => {
+                io::copy(&mut GzDecoder::new(reader), &mut writer)?;
+            }
         }
+        writer.flush()?;
+
+        Ok(writer.count)
+    }
+}
+
+/// Counts bytes passing through a reader
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Counts bytes passing through a writer
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
     }
-} 
\ No newline at end of file
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zstd_round_trip() {
+        let compressor = Compressor::new(CompressionAlgorithm::zstd());
+        let data = b"memory memory memory memory memory memory".repeat(32);
+
+        let (compressed, _) = compressor.compress(&data);
+        assert_eq!(compressor.decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_zstd_metrics_record_level_and_ratio() {
+        let compressor = Compressor::new(CompressionAlgorithm::Zstd { level: 1000 });
+        let data = vec![7u8; 4096];
+
+        let (compressed, metrics) = compressor.compress(&data);
+        let max_level = *zstd::compression_level_range().end();
+        assert_eq!(metrics.algorithm, CompressionAlgorithm::Zstd { level: max_level });
+        assert_eq!(metrics.original_size, data.len());
+        assert_eq!(metrics.compressed_size, compressed.len());
+        assert!(metrics.compression_ratio() < 0.1);
+    }
+
+    #[test]
+    fn test_algorithm_serde_round_trip() {
+        for algorithm in [
+            CompressionAlgorithm::None,
+            CompressionAlgorithm::LZ4,
+            CompressionAlgorithm::Zstd { level: 7 },
+        ] {
+            let encoded = bincode::serialize(&algorithm).unwrap();
+            let decoded: CompressionAlgorithm = bincode::deserialize(&encoded).unwrap();
+            assert_eq!(decoded, algorithm);
+        }
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        use std::io::Cursor;
+
+        // 8 MiB of loosely repetitive data
+        let data: Vec<u8> = (0..8 * 1024 * 1024u32)
+            .map(|i| (i % 251) as u8 ^ (i >> 16) as u8)
+            .collect();
+
+        for algorithm in [
+            CompressionAlgorithm::None,
+            CompressionAlgorithm::LZ4,
+            CompressionAlgorithm::zstd(),
+        ] {
+            let compressor = Compressor::new(algorithm);
+
+            let mut compressed = Vec::new();
+            let metrics = compressor
+                .compress_stream(Cursor::new(&data), &mut compressed)
+                .unwrap();
+            assert_eq!(metrics.original_size, data.len());
+            assert_eq!(metrics.compressed_size, compressed.len());
+            if algorithm != CompressionAlgorithm::None {
+                assert!(metrics.compression_ratio() < 0.5);
+            }
+
+            let mut restored = Vec::new();
+            let written = compressor
+                .decompress_stream(Cursor::new(&compressed), &mut restored)
+                .unwrap();
+            assert_eq!(written, data.len() as u64);
+            assert!(restored == data);
+        }
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let data = b"gzip gzip gzip gzip gzip gzip gzip gzip".repeat(32);
+        let compressor = Compressor::new(CompressionAlgorithm::Gzip { level: 42 });
+        assert_eq!(compressor.algorithm(), CompressionAlgorithm::Gzip { level: 9 });
+
+        let (compressed, metrics) = compressor.compress(&data);
+        assert_eq!(metrics.algorithm, CompressionAlgorithm::Gzip { level: 9 });
+        assert!(metrics.compressed_size < data.len());
+        assert_eq!(compressor.decompress(&compressed).unwrap(), data);
+
+        let mut streamed = Vec::new();
+        Compressor::new(CompressionAlgorithm::gzip()).compress_stream(&data[..], &mut streamed).unwrap();
+        let mut restored = Vec::new();
+        compressor.decompress_stream(&streamed[..], &mut restored).unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[test]
+    fn test_gzip_output_is_standard() {
+        let data = b"readable by gzip -d ".repeat(8);
+        let (compressed, _) = Compressor::new(CompressionAlgorithm::gzip()).compress(&data);
+
+        // Gzip magic bytes, then decode with a plain gzip reader
+        assert_eq!(&compressed[..2], &[0x1f, 0x8b]);
+        let mut decoded = Vec::new();
+        GzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_incompressible_data_stored_raw() {
+        use rand::{RngCore, SeedableRng};
+
+        let mut data = vec![0u8; 4096];
+        rand::rngs::StdRng::seed_from_u64(7).fill_bytes(&mut data);
+
+        for algorithm in [CompressionAlgorithm::LZ4, CompressionAlgorithm::zstd(), CompressionAlgorithm::gzip()] {
+            let (stored, metrics) = Compressor::new(algorithm).compress(&data);
+            assert_eq!(metrics.algorithm, CompressionAlgorithm::None);
+            assert_eq!(metrics.compressed_size, data.len());
+            assert_eq!(Compressor::new(metrics.algorithm).decompress(&stored).unwrap(), data);
+
+            // With the threshold lifted the algorithm is applied regardless
+            let (_, metrics) = Compressor::new(algorithm).with_max_ratio(f32::INFINITY).compress(&data);
+            assert_eq!(metrics.algorithm, algorithm.normalized());
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! One configuration for the whole memory system, checked before any stage
+//! is built.
+
+use super::personality_cache::{EvictionPolicy, PersonalityCache};
+use super::stage1::Stage1Config;
+use super::stage2::{ShardBy, Stage2Config};
+use super::stage3::{RedundancyMode, Stage3Config};
+use thiserror::Error;
+
+/// Most shards one Reed-Solomon codeword can hold over GF(2^8)
+const MAX_SHARDS: usize = 256;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConfigError {
+    #[error("Stage 1 max_entries must be at least 1")]
+    ZeroStage1Capacity,
+    #[error("Stage 2 entries_per_file must be at least 1")]
+    ZeroEntriesPerFile,
+    #[error("Stage 2 token-range sharding needs at least one shard")]
+    ZeroTokenShards,
+    #[error("{data} data and {parity} parity shards: need at least one of each and at most {MAX_SHARDS} in total")]
+    InvalidShards { data: usize, parity: usize },
+    #[error("Stage 3 min_age_days {0} is longer than a 32-bit epoch can measure")]
+    UnreachableMinAge(u32),
+    #[error("Cache max_entries must be at least 1")]
+    ZeroCacheCapacity,
+    #[error("{0} must not be NaN")]
+    NanThreshold(&'static str),
+}
+
+/// Settings for the personality cache a pipeline is built with
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub max_entries: usize,
+    pub personality_threshold: f32,
+    pub eviction_policy: EvictionPolicy,
+    /// Entries evicted at once when an insert finds the cache full
+    pub eviction_batch: usize,
+    /// Added to a memory's weight on every cache hit; 0 leaves weights alone
+    pub access_boost: i16,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1000,
+            personality_threshold: 0.5,
+            eviction_policy: EvictionPolicy::default(),
+            eviction_batch: 1,
+            access_boost: 0,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Creates an empty cache with these settings
+    pub fn build(&self) -> PersonalityCache {
+        PersonalityCache::with_policy(self.max_entries, self.personality_threshold, self.eviction_policy)
+            .with_eviction_batch(self.eviction_batch)
+            .with_access_boost(self.access_boost)
+    }
+}
+
+/// Configuration for every stage and the optional cache
+///
+/// Build one with `MemConfig::builder()` to have it validated; a config
+/// assembled by hand is checked again by `MemoryPipeline::from_config`.
+#[derive(Debug, Clone, Default)]
+pub struct MemConfig {
+    pub stage1: Stage1Config,
+    pub stage2: Stage2Config,
+    pub stage3: Stage3Config,
+    /// `None` builds the pipeline without a cache
+    pub cache: Option<CacheConfig>,
+}
+
+impl MemConfig {
+    pub fn builder() -> MemConfigBuilder {
+        MemConfigBuilder::default()
+    }
+
+    /// Rejects settings no stage can run with
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.stage1.max_entries == Some(0) {
+            return Err(ConfigError::ZeroStage1Capacity);
+        }
+        if self.stage1.similarity_threshold.is_nan() {
+            return Err(ConfigError::NanThreshold("Stage 1 similarity_threshold"));
+        }
+
+        match self.stage2.shard_by {
+            ShardBy::Time if self.stage2.entries_per_file == 0 => return Err(ConfigError::ZeroEntriesPerFile),
+            ShardBy::TokenRange(0) => return Err(ConfigError::ZeroTokenShards),
+            _ => {}
+        }
+
+        check_shards(self.stage3.data_shards, self.stage3.parity_shards)?;
+        if let RedundancyMode::Erasure { data, parity } = self.stage3.redundancy {
+            check_shards(data, parity)?;
+        }
+        // Ages are measured in whole days of a u32 epoch
+        if self.stage3.min_age_days > u32::MAX / (3600 * 24) {
+            return Err(ConfigError::UnreachableMinAge(self.stage3.min_age_days));
+        }
+
+        if let Some(cache) = &self.cache {
+            if cache.max_entries == 0 {
+                return Err(ConfigError::ZeroCacheCapacity);
+            }
+            if cache.personality_threshold.is_nan() {
+                return Err(ConfigError::NanThreshold("Cache personality_threshold"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_shards(data: usize, parity: usize) -> Result<(), ConfigError> {
+    if data == 0 || parity == 0 || data + parity > MAX_SHARDS {
+        return Err(ConfigError::InvalidShards { data, parity });
+    }
+    Ok(())
+}
+
+/// Builds a `MemConfig` stage by stage, validating it on `build`
+///
+/// Stages left unset keep their defaults.
+#[derive(Debug, Clone, Default)]
+pub struct MemConfigBuilder {
+    config: MemConfig,
+}
+
+impl MemConfigBuilder {
+    pub fn stage1(mut self, stage1: Stage1Config) -> Self {
+        self.config.stage1 = stage1;
+        self
+    }
+
+    pub fn stage2(mut self, stage2: Stage2Config) -> Self {
+        self.config.stage2 = stage2;
+        self
+    }
+
+    pub fn stage3(mut self, stage3: Stage3Config) -> Self {
+        self.config.stage3 = stage3;
+        self
+    }
+
+    pub fn cache(mut self, cache: CacheConfig) -> Self {
+        self.config.cache = Some(cache);
+        self
+    }
+
+    pub fn build(self) -> Result<MemConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_rejects_invalid_combinations() {
+        assert!(MemConfig::builder().build().is_ok());
+
+        let err = MemConfig::builder()
+            .stage2(Stage2Config { entries_per_file: 0, ..Stage2Config::default() })
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::ZeroEntriesPerFile);
+
+        // Token-range sharding ignores entries_per_file
+        let sharded = Stage2Config {
+            entries_per_file: 0,
+            shard_by: ShardBy::TokenRange(4),
+            ..Stage2Config::default()
+        };
+        assert!(MemConfig::builder().stage2(sharded).build().is_ok());
+
+        let err = MemConfig::builder()
+            .stage3(Stage3Config { parity_shards: 0, ..Stage3Config::default() })
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::InvalidShards { data: 4, parity: 0 });
+
+        let err = MemConfig::builder()
+            .stage3(Stage3Config {
+                redundancy: RedundancyMode::Erasure { data: 200, parity: 100 },
+                ..Stage3Config::default()
+            })
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::InvalidShards { data: 200, parity: 100 });
+
+        let err = MemConfig::builder()
+            .stage3(Stage3Config { min_age_days: 50_000, ..Stage3Config::default() })
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ConfigError::UnreachableMinAge(50_000));
+
+        let err = MemConfig::builder()
+            .cache(CacheConfig { personality_threshold: f32::NAN, ..CacheConfig::default() })
+            .build()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Cache personality_threshold must not be NaN");
+    }
+}
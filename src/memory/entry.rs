@@ -1,30 +1,93 @@
+use super::clock::{Clock, MemoryClock};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// Version byte written by `MemoryEntry::to_wire`
+pub const WIRE_VERSION: u8 = 1;
+
+/// Length of an entry in the wire format
+///
+/// Byte 0 is the version and byte 1 holds flags (bit 0: has expiry, bit 1:
+/// pinned),
+/// followed by the epoch, token, weight, both links and the expiry in
+/// little-endian order; the expiry is zero when absent.
+pub const WIRE_SIZE: usize = 22;
+
+/// Flag set when the wire entry carries an expiry
+const WIRE_HAS_EXPIRY: u8 = 0b1;
+
+/// Flag set when the wire entry is pinned
+const WIRE_PINNED: u8 = 0b10;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum WireError {
+    #[error("Wire entry is {0} bytes, expected {WIRE_SIZE}")]
+    Length(usize),
+    #[error("Unsupported wire format version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown wire flags: {0:#04x}")]
+    UnknownFlags(u8),
+}
 
 /// Represents a single memory entry in the MeM|8 system
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryEntry {
     epoch_pointer: u32,  // 32-bit epoch pointer (136-year span)
     token: u16,         // 16-bit concept encoding
-    weight: u16,        // 16-bit importance score
+    weight: i16,        // Signed importance (-30,000 to 30,000)
     link1: u32,         // Primary link to related memory
     link2: u32,         // Secondary link to related memory
+    #[serde(default)]
+    expires_at: Option<u32>,  // Hard expiry epoch, regardless of weight
+    #[serde(default)]
+    pinned: bool,             // Never decayed, evicted or aged out
+}
+
+/// Layout written before entries could be pinned
+#[derive(Deserialize)]
+pub(super) struct ExpiringMemoryEntry {
+    epoch_pointer: u32,
+    token: u16,
+    weight: i16,
+    link1: u32,
+    link2: u32,
+    expires_at: Option<u32>,
+}
+
+impl From<ExpiringMemoryEntry> for MemoryEntry {
+    fn from(old: ExpiringMemoryEntry) -> Self {
+        let entry = Self::with_links(old.epoch_pointer, old.token, old.weight, old.link1, old.link2);
+        Self { expires_at: old.expires_at, ..entry }
+    }
+}
+
+/// Layout written before entries could expire
+#[derive(Deserialize)]
+struct LegacyMemoryEntry {
+    epoch_pointer: u32,
+    token: u16,
+    weight: i16,
+    link1: u32,
+    link2: u32,
 }
 
 impl MemoryEntry {
-    /// Creates a new memory entry with the current epoch
-    pub fn new(token: u16, weight: u16) -> Self {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
+    /// Creates a new memory entry with the current UNIX-seeded epoch
+    pub fn new(token: u16, weight: i16) -> Self {
+        Self::new_at(token, weight, &MemoryClock::default())
+    }
 
+    /// Creates a new memory entry stamped with `clock`'s current epoch
+    pub fn new_at(token: u16, weight: i16, clock: &dyn Clock) -> Self {
         Self {
-            epoch_pointer: now,
+            epoch_pointer: clock.now_epoch(),
             token,
             weight,
             link1: 0,  // No initial links
             link2: 0,
+            expires_at: None,
+            pinned: false,
         }
     }
 
@@ -32,7 +95,7 @@ impl MemoryEntry {
     pub fn with_links(
         epoch_pointer: u32,
         token: u16,
-        weight: u16,
+        weight: i16,
         link1: u32,
         link2: u32,
     ) -> Self {
@@ -42,14 +105,117 @@ impl MemoryEntry {
             weight,
             link1,
             link2,
+            expires_at: None,
+            pinned: false,
+        }
+    }
+
+    /// Sets the epoch at which this memory hard-expires
+    pub fn with_expiry(mut self, expires_at: u32) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// Moves the entry to another epoch, keeping everything else
+    pub fn with_epoch(mut self, epoch: u32) -> Self {
+        self.epoch_pointer = epoch;
+        self
+    }
+
+    /// Decodes a bincode-serialized entry, accepting blocks written before
+    /// entries carried an expiry or a pin
+    pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(bytes).or_else(|err| {
+            if let Ok(old) = bincode::deserialize::<ExpiringMemoryEntry>(bytes) {
+                return Ok(old.into());
+            }
+            let legacy: LegacyMemoryEntry = bincode::deserialize(bytes).map_err(|_| err)?;
+            Ok(Self::with_links(
+                legacy.epoch_pointer,
+                legacy.token,
+                legacy.weight,
+                legacy.link1,
+                legacy.link2,
+            ))
+        })
+    }
+
+    /// Decodes only the epoch, token and weight that lead a bincode-serialized
+    /// entry, in either layout `from_bytes` accepts
+    pub(super) fn header_from_bytes(bytes: &[u8]) -> bincode::Result<(u32, u16, i16)> {
+        bincode::deserialize(bytes)
+    }
+
+    /// Encodes the entry in the versioned wire format, which unlike bincode
+    /// is fixed across crate versions
+    pub fn to_wire(&self) -> [u8; WIRE_SIZE] {
+        let mut wire = [0u8; WIRE_SIZE];
+        wire[0] = WIRE_VERSION;
+        if self.expires_at.is_some() {
+            wire[1] |= WIRE_HAS_EXPIRY;
+        }
+        if self.pinned {
+            wire[1] |= WIRE_PINNED;
+        }
+        wire[2..6].copy_from_slice(&self.epoch_pointer.to_le_bytes());
+        wire[6..8].copy_from_slice(&self.token.to_le_bytes());
+        wire[8..10].copy_from_slice(&self.weight.to_le_bytes());
+        wire[10..14].copy_from_slice(&self.link1.to_le_bytes());
+        wire[14..18].copy_from_slice(&self.link2.to_le_bytes());
+        wire[18..22].copy_from_slice(&self.expires_at.unwrap_or(0).to_le_bytes());
+        wire
+    }
+
+    /// Decodes an entry written by `to_wire`
+    pub fn from_wire(bytes: &[u8]) -> Result<Self, WireError> {
+        let wire: &[u8; WIRE_SIZE] = bytes.try_into().map_err(|_| WireError::Length(bytes.len()))?;
+        if wire[0] != WIRE_VERSION {
+            return Err(WireError::UnsupportedVersion(wire[0]));
         }
+        if wire[1] & !(WIRE_HAS_EXPIRY | WIRE_PINNED) != 0 {
+            return Err(WireError::UnknownFlags(wire[1]));
+        }
+
+        let u32_at = |at: usize| u32::from_le_bytes(wire[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| [wire[at], wire[at + 1]];
+        let mut entry = Self::with_links(
+            u32_at(2),
+            u16::from_le_bytes(u16_at(6)),
+            i16::from_le_bytes(u16_at(8)),
+            u32_at(10),
+            u32_at(14),
+        );
+        entry.pinned = wire[1] & WIRE_PINNED != 0;
+        Ok(if wire[1] & WIRE_HAS_EXPIRY != 0 {
+            entry.with_expiry(u32_at(18))
+        } else {
+            entry
+        })
+    }
+
+    /// Starts a builder for entries that set only some of their fields
+    pub fn builder() -> MemoryEntryBuilder {
+        MemoryEntryBuilder::default()
     }
 
     // Getters
     pub fn epoch(&self) -> u32 { self.epoch_pointer }
     pub fn token(&self) -> u16 { self.token }
-    pub fn weight(&self) -> u16 { self.weight }
+    pub fn weight(&self) -> i16 { self.weight }
     pub fn links(&self) -> (u32, u32) { (self.link1, self.link2) }
+    pub fn expires_at(&self) -> Option<u32> { self.expires_at }
+    pub fn is_pinned(&self) -> bool { self.pinned }
+
+    /// Returns whether the memory's hard expiry has passed at `now`
+    pub fn is_expired(&self, now: u32) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// Pins or unpins the memory; pinned memories are never decayed,
+    /// evicted or aged out
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned;
+    }
 
     /// Updates the memory links
     pub fn update_links(&mut self, link1: u32, link2: u32) {
@@ -57,9 +223,19 @@ impl MemoryEntry {
         self.link2 = link2;
     }
 
-    /// Adjusts the memory weight
+    /// Replaces the memory weight
+    pub fn set_weight(&mut self, weight: i16) {
+        self.weight = weight;
+    }
+
+    /// Adjusts the memory weight, saturating at the `i16` bounds
     pub fn adjust_weight(&mut self, delta: i16) {
-        self.weight = self.weight.saturating_add_signed(delta);
+        self.weight = self.weight.saturating_add(delta);
+    }
+
+    /// The wall-clock time this memory was created, under `clock`
+    pub fn timestamp(&self, clock: &MemoryClock) -> SystemTime {
+        clock.timestamp(self.epoch_pointer)
     }
 
     /// Calculates age in seconds relative to a given epoch
@@ -68,6 +244,113 @@ impl MemoryEntry {
     }
 }
 
+/// Builds a `MemoryEntry` field by field
+///
+/// The epoch defaults to the current time and links default to 0 (unlinked).
+#[derive(Debug, Clone, Default)]
+pub struct MemoryEntryBuilder {
+    epoch: Option<u32>,
+    token: u16,
+    weight: i16,
+    link1: u32,
+    link2: u32,
+    expires_at: Option<u32>,
+    pinned: bool,
+}
+
+impl MemoryEntryBuilder {
+    pub fn token(mut self, token: u16) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn weight(mut self, weight: i16) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn epoch(mut self, epoch: u32) -> Self {
+        self.epoch = Some(epoch);
+        self
+    }
+
+    pub fn link1(mut self, link1: u32) -> Self {
+        self.link1 = link1;
+        self
+    }
+
+    pub fn link2(mut self, link2: u32) -> Self {
+        self.link2 = link2;
+        self
+    }
+
+    pub fn expires_at(mut self, expires_at: u32) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn pinned(mut self, pinned: bool) -> Self {
+        self.pinned = pinned;
+        self
+    }
+
+    pub fn build(self) -> MemoryEntry {
+        let mut entry = MemoryEntry::with_links(
+            self.epoch.unwrap_or_else(now_epoch),
+            self.token,
+            self.weight,
+            self.link1,
+            self.link2,
+        );
+        entry.expires_at = self.expires_at;
+        entry.pinned = self.pinned;
+        entry
+    }
+}
+
+/// The epoch, token and weight of a memory in 8 bytes, for stores that
+/// keep links and expiry elsewhere or not at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PackedEntry {
+    epoch: u32,
+    token: u16,
+    weight: i16,
+}
+
+impl PackedEntry {
+    pub fn new(epoch: u32, token: u16, weight: i16) -> Self {
+        Self { epoch, token, weight }
+    }
+
+    pub fn epoch(&self) -> u32 { self.epoch }
+    pub fn token(&self) -> u16 { self.token }
+    pub fn weight(&self) -> i16 { self.weight }
+}
+
+impl From<&MemoryEntry> for PackedEntry {
+    fn from(entry: &MemoryEntry) -> Self {
+        Self::new(entry.epoch_pointer, entry.token, entry.weight)
+    }
+}
+
+impl From<MemoryEntry> for PackedEntry {
+    fn from(entry: MemoryEntry) -> Self {
+        Self::from(&entry)
+    }
+}
+
+/// Unpacks to an unlinked entry with no expiry
+impl From<PackedEntry> for MemoryEntry {
+    fn from(packed: PackedEntry) -> Self {
+        Self::with_links(packed.epoch, packed.token, packed.weight, 0, 0)
+    }
+}
+
+/// Current time as a 32-bit epoch pointer
+fn now_epoch() -> u32 {
+    MemoryClock::default().now()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -86,7 +369,12 @@ mod tests {
         entry.adjust_weight(500);
         assert_eq!(entry.weight(), 1500);
         entry.adjust_weight(-2000);
-        assert_eq!(entry.weight(), 0); // Should saturate at 0
+        assert_eq!(entry.weight(), -500); // Negative importance is allowed
+        entry.adjust_weight(i16::MIN);
+        assert_eq!(entry.weight(), i16::MIN); // Should saturate at i16::MIN
+        let mut entry = MemoryEntry::new(123, 30000);
+        entry.adjust_weight(5000);
+        assert_eq!(entry.weight(), i16::MAX); // Should saturate at i16::MAX
     }
 
     #[test]
@@ -95,4 +383,119 @@ mod tests {
         entry.update_links(42, 84);
         assert_eq!(entry.links(), (42, 84));
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_builder_partial() {
+        let entry = MemoryEntry::builder()
+            .token(7)
+            .weight(-250)
+            .epoch(1234)
+            .link1(42)
+            .build();
+
+        assert_eq!(entry.epoch(), 1234);
+        assert_eq!(entry.token(), 7);
+        assert_eq!(entry.weight(), -250);
+        assert_eq!(entry.links(), (42, 0));
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let before = now_epoch();
+        let entry = MemoryEntry::builder().token(9).build();
+        let after = now_epoch();
+
+        assert!((before..=after).contains(&entry.epoch()));
+        assert_eq!(entry.weight(), 0);
+        assert_eq!(entry.links(), (0, 0));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let entry = MemoryEntry::builder().epoch(100).expires_at(200).build();
+        assert_eq!(entry.expires_at(), Some(200));
+        assert!(!entry.is_expired(199));
+        assert!(entry.is_expired(200));
+        assert!(!MemoryEntry::with_links(100, 1, 1, 0, 0).is_expired(u32::MAX));
+    }
+
+    #[test]
+    fn test_from_bytes_accepts_legacy_layout() {
+        #[derive(Serialize)]
+        struct Legacy(u32, u16, i16, u32, u32);
+
+        let bytes = bincode::serialize(&Legacy(100, 7, -5, 1, 2)).unwrap();
+        let entry = MemoryEntry::from_bytes(&bytes).unwrap();
+        assert_eq!(entry.epoch(), 100);
+        assert_eq!(entry.token(), 7);
+        assert_eq!(entry.weight(), -5);
+        assert_eq!(entry.links(), (1, 2));
+        assert_eq!(entry.expires_at(), None);
+
+        #[derive(Serialize)]
+        struct Expiring(u32, u16, i16, u32, u32, Option<u32>);
+
+        let bytes = bincode::serialize(&Expiring(100, 7, -5, 1, 2, Some(500))).unwrap();
+        let entry = MemoryEntry::from_bytes(&bytes).unwrap();
+        assert_eq!(entry.links(), (1, 2));
+        assert_eq!(entry.expires_at(), Some(500));
+        assert!(!entry.is_pinned());
+
+        let mut current = MemoryEntry::with_links(100, 7, -5, 1, 2).with_expiry(500);
+        current.set_pinned(true);
+        let decoded = MemoryEntry::from_bytes(&bincode::serialize(&current).unwrap()).unwrap();
+        assert_eq!(decoded.expires_at(), Some(500));
+        assert!(decoded.is_pinned());
+    }
+
+    #[test]
+    fn test_wire_round_trip() {
+        let entry = MemoryEntry::with_links(0x0102_0304, 0xBEEF, -1234, 7, u32::MAX).with_expiry(500);
+        let wire = entry.to_wire();
+        assert_eq!(wire[..10], [WIRE_VERSION, WIRE_HAS_EXPIRY, 0x04, 0x03, 0x02, 0x01, 0xEF, 0xBE, 0x2E, 0xFB]);
+
+        let decoded = MemoryEntry::from_wire(&wire).unwrap();
+        assert_eq!(decoded.epoch(), 0x0102_0304);
+        assert_eq!(decoded.token(), 0xBEEF);
+        assert_eq!(decoded.weight(), -1234);
+        assert_eq!(decoded.links(), (7, u32::MAX));
+        assert_eq!(decoded.expires_at(), Some(500));
+
+        assert!(!decoded.is_pinned());
+
+        let mut plain = MemoryEntry::with_links(1, 2, 3, 4, 5);
+        assert_eq!(MemoryEntry::from_wire(&plain.to_wire()).unwrap().expires_at(), None);
+        plain.set_pinned(true);
+        assert_eq!(plain.to_wire()[1], WIRE_PINNED);
+        assert!(MemoryEntry::from_wire(&plain.to_wire()).unwrap().is_pinned());
+    }
+
+    #[test]
+    fn test_wire_rejects_unknown_version() {
+        let mut wire = MemoryEntry::with_links(1, 2, 3, 4, 5).to_wire();
+        wire[0] = WIRE_VERSION + 1;
+        assert_eq!(MemoryEntry::from_wire(&wire).unwrap_err(), WireError::UnsupportedVersion(WIRE_VERSION + 1));
+
+        wire[0] = WIRE_VERSION;
+        wire[1] = 0x80;
+        assert_eq!(MemoryEntry::from_wire(&wire).unwrap_err(), WireError::UnknownFlags(0x80));
+        assert_eq!(MemoryEntry::from_wire(&wire[..16]).unwrap_err(), WireError::Length(16));
+    }
+
+    #[test]
+    fn test_packed_entry_round_trip() {
+        assert_eq!(std::mem::size_of::<PackedEntry>(), 8);
+        assert!(std::mem::size_of::<PackedEntry>() < std::mem::size_of::<MemoryEntry>());
+
+        let entry = MemoryEntry::with_links(42, 7, -300, 0, 0);
+        let packed = PackedEntry::from(&entry);
+        assert_eq!((packed.epoch(), packed.token(), packed.weight()), (42, 7, -300));
+        let unpacked: MemoryEntry = packed.into();
+        assert_eq!(unpacked.to_wire(), entry.to_wire());
+
+        // Links and expiry are what packing leaves behind
+        let linked: MemoryEntry = PackedEntry::from(MemoryEntry::with_links(42, 7, -300, 3, 4).with_expiry(90)).into();
+        assert_eq!(linked.links(), (0, 0));
+        assert_eq!(linked.expires_at(), None);
+    }
+}
@@ -0,0 +1,77 @@
+//! A single error type covering every stage, for callers that work across
+//! several of them.
+
+use super::archive::ArchiveError;
+use super::compression::CompressionError;
+use super::config::ConfigError;
+use super::error_correction::ErrorCorrectionError;
+use super::pipeline::{CheckpointError, PipelineError};
+use super::stage1::Stage1Error;
+use super::stage2::Stage2Error;
+use super::stage3::Stage3Error;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MemError {
+    #[error("Stage 1 error: {0}")]
+    Stage1(#[from] Stage1Error),
+    #[error("Stage 2 error: {0}")]
+    Stage2(#[from] Stage2Error),
+    #[error("Stage 3 error: {0}")]
+    Stage3(#[from] Stage3Error),
+    #[error("Compression error: {0}")]
+    Compression(#[from] CompressionError),
+    #[error("Error correction error: {0}")]
+    ErrorCorrection(#[from] ErrorCorrectionError),
+    #[error("Archive error: {0}")]
+    Archive(#[from] ArchiveError),
+    #[error("Checkpoint error: {0}")]
+    Checkpoint(#[from] CheckpointError),
+    #[error("Configuration error: {0}")]
+    Config(#[from] ConfigError),
+}
+
+/// Pipeline errors are unwrapped to the stage that raised them
+impl From<PipelineError> for MemError {
+    fn from(error: PipelineError) -> Self {
+        match error {
+            PipelineError::Stage2(e) => MemError::Stage2(e),
+            PipelineError::Stage3(e) => MemError::Stage3(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::compression::{CompressionAlgorithm, Compressor};
+    use crate::memory::error_correction::ReedSolomonEC;
+
+    #[test]
+    fn test_stage_errors_convert() {
+        assert!(matches!(
+            MemError::from(Stage1Error::EntryNotFound(1)),
+            MemError::Stage1(Stage1Error::EntryNotFound(1))
+        ));
+        assert!(matches!(
+            MemError::from(Stage2Error::NotFound(2)),
+            MemError::Stage2(Stage2Error::NotFound(2))
+        ));
+        assert!(matches!(
+            MemError::from(Stage3Error::NotFound(3)),
+            MemError::Stage3(Stage3Error::NotFound(3))
+        ));
+        assert!(matches!(
+            MemError::from(PipelineError::Stage3(Stage3Error::NotFound(4))),
+            MemError::Stage3(Stage3Error::NotFound(4))
+        ));
+
+        let compression = Compressor::new(CompressionAlgorithm::zstd()).decompress(b"junk").unwrap_err();
+        assert!(matches!(MemError::from(compression), MemError::Compression(CompressionError::Zstd(_))));
+
+        let error_correction = ReedSolomonEC::new(0, 2).err().unwrap();
+        let error = MemError::from(error_correction);
+        assert!(matches!(error, MemError::ErrorCorrection(ErrorCorrectionError::NoDataShards)));
+        assert_eq!(error.to_string(), "Error correction error: Reed-Solomon needs at least one data shard");
+    }
+}
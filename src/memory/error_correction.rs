@@ -1,6 +1,24 @@
 use reed_solomon_erasure::galois_8::ReedSolomon;
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ErrorCorrectionError {
+    #[error("Reed-Solomon needs at least one data shard")]
+    NoDataShards,
+    #[error("Reed-Solomon needs at least one parity shard")]
+    NoParityShards,
+    #[error("Expected {expected} shards, got {found}")]
+    ShardCount { expected: usize, found: usize },
+    #[error("Too few shards to reconstruct: {present} present, {needed} needed")]
+    TooFewShards { present: usize, needed: usize },
+    #[error("Shards have inconsistent lengths")]
+    InconsistentShardLengths,
+    #[error("Original size {original_size} exceeds the {capacity} bytes the data shards hold")]
+    OriginalSizeTooLarge { original_size: usize, capacity: usize },
+    #[error("Reed-Solomon error: {0}")]
+    ReedSolomon(#[from] reed_solomon_erasure::Error),
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorCorrectionMetrics {
@@ -17,9 +35,17 @@ pub struct ReedSolomonEC {
 }
 
 impl ReedSolomonEC {
-    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self, String> {
-        let rs = ReedSolomon::new(data_shards, parity_shards)
-            .map_err(|e| format!("Failed to create Reed-Solomon: {}", e))?;
+    /// Creates a codec, rejecting layouts without at least one data and one
+    /// parity shard
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self, ErrorCorrectionError> {
+        if data_shards == 0 {
+            return Err(ErrorCorrectionError::NoDataShards);
+        }
+        if parity_shards == 0 {
+            return Err(ErrorCorrectionError::NoParityShards);
+        }
+
+        let rs = ReedSolomon::new(data_shards, parity_shards)?;
         
         Ok(Self {
             rs,
@@ -28,11 +54,17 @@ impl ReedSolomonEC {
         })
     }
 
-    pub fn encode(&self, data: &[u8]) -> Result<(Vec<Vec<u8>>, ErrorCorrectionMetrics), String> {
-        let start = Instant::now();
-        
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    pub fn parity_shards(&self) -> usize {
+        self.parity_shards
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Result<(Vec<Vec<u8>>, ErrorCorrectionMetrics), ErrorCorrectionError> {
         // Split data into shards
-        let shard_size = (data.len() + self.data_shards - 1) / self.data_shards;
+        let shard_size = data.len().div_ceil(self.data_shards);
         let mut shards = vec![vec![0u8; shard_size]; self.data_shards + self.parity_shards];
         
         // Fill data shards
@@ -41,8 +73,7 @@ impl ReedSolomonEC {
         }
         
         // Generate parity shards
-        self.rs.encode(&mut shards)
-            .map_err(|e| format!("Encoding failed: {}", e))?;
+        self.rs.encode(&mut shards)?;
         
         let metrics = ErrorCorrectionMetrics {
             original_size: data.len(),
@@ -54,17 +85,119 @@ impl ReedSolomonEC {
         Ok((shards, metrics))
     }
 
-    pub fn reconstruct(&self, mut shards: Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
-        // Attempt reconstruction if needed
-        self.rs.reconstruct(&mut shards)
-            .map_err(|e| format!("Reconstruction failed: {}", e))?;
+    /// Rebuilds the original data from shards, where lost or corrupt shards
+    /// are passed as `None`
+    ///
+    /// `original_size` is the length `encode` was given, as recorded in its
+    /// metrics; the padding that filled out the last data shard is dropped.
+    /// The returned metrics count the shards that had to be rebuilt.
+    pub fn reconstruct(
+        &self,
+        mut shards: Vec<Option<Vec<u8>>>,
+        original_size: usize,
+    ) -> Result<(Vec<u8>, ErrorCorrectionMetrics), ErrorCorrectionError> {
+        let total = self.data_shards + self.parity_shards;
+        if shards.len() != total {
+            return Err(ErrorCorrectionError::ShardCount { expected: total, found: shards.len() });
+        }
+
+        let present: Vec<usize> = shards.iter().flatten().map(Vec::len).collect();
+        if present.len() < self.data_shards {
+            return Err(ErrorCorrectionError::TooFewShards {
+                present: present.len(),
+                needed: self.data_shards,
+            });
+        }
+        if present.windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(ErrorCorrectionError::InconsistentShardLengths);
+        }
+
+        let missing = total - present.len();
+        self.rs.reconstruct(&mut shards)?;
         
         // Combine data shards
         let mut result = Vec::new();
-        for shard in shards.iter().take(self.data_shards) {
+        for shard in shards.iter().take(self.data_shards).flatten() {
             result.extend_from_slice(shard);
         }
-        
-        Ok(result)
+        if original_size > result.len() {
+            return Err(ErrorCorrectionError::OriginalSizeTooLarge {
+                original_size,
+                capacity: result.len(),
+            });
+        }
+        result.truncate(original_size);
+
+        let metrics = ErrorCorrectionMetrics {
+            original_size: result.len(),
+            parity_size: present[0] * self.parity_shards,
+            corrections_performed: missing as u32,
+            last_correction_time: (missing > 0).then(std::time::SystemTime::now),
+        };
+
+        Ok((result, metrics))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_empty_shard_counts() {
+        assert!(matches!(ReedSolomonEC::new(0, 2), Err(ErrorCorrectionError::NoDataShards)));
+        assert!(matches!(ReedSolomonEC::new(4, 0), Err(ErrorCorrectionError::NoParityShards)));
+    }
+
+    #[test]
+    fn test_too_few_shards() {
+        let ec = ReedSolomonEC::new(4, 2).unwrap();
+        let (shards, _) = ec.encode(b"sixteen byte msg").unwrap();
+
+        let mut partial: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        for shard in partial.iter_mut().take(3) {
+            *shard = None;
+        }
+
+        let err = ec.reconstruct(partial, 16).unwrap_err();
+        assert!(matches!(err, ErrorCorrectionError::TooFewShards { present: 3, needed: 4 }), "{}", err);
+    }
+
+    #[test]
+    fn test_single_shard_loss_recovered() {
+        let ec = ReedSolomonEC::new(4, 2).unwrap();
+        let data = b"sixteen byte msg";
+        let (shards, _) = ec.encode(data).unwrap();
+
+        let (intact, metrics) = ec.reconstruct(shards.iter().cloned().map(Some).collect(), data.len()).unwrap();
+        assert_eq!(intact, data);
+        assert_eq!(metrics.corrections_performed, 0);
+        assert!(metrics.last_correction_time.is_none());
+
+        let mut partial: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        partial[1] = None;
+        let (recovered, metrics) = ec.reconstruct(partial, data.len()).unwrap();
+        assert_eq!(recovered, data);
+        assert_eq!(metrics.corrections_performed, 1);
+        assert!(metrics.last_correction_time.is_some());
+    }
+
+    #[test]
+    fn test_padding_dropped_on_reconstruct() {
+        let ec = ReedSolomonEC::new(4, 2).unwrap();
+        let data: Vec<u8> = (1..=23).collect();
+        let (shards, encoded) = ec.encode(&data).unwrap();
+        assert_eq!(encoded.original_size, 23);
+        assert_eq!(shards[0].len(), 6);
+
+        // Losing the padded last data shard still gives back exactly the input
+        let mut partial: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        partial[3] = None;
+        let (recovered, metrics) = ec.reconstruct(partial.clone(), encoded.original_size).unwrap();
+        assert_eq!(recovered, data);
+        assert_eq!(metrics.original_size, 23);
+
+        let err = ec.reconstruct(partial, 25).unwrap_err();
+        assert!(matches!(err, ErrorCorrectionError::OriginalSizeTooLarge { original_size: 25, capacity: 24 }), "{}", err);
     }
 } 
\ No newline at end of file
@@ -0,0 +1,87 @@
+//! Human-readable import and export of memory entries.
+
+use super::entry::MemoryEntry;
+use serde::{Deserialize, Serialize};
+
+/// JSON form of a memory entry
+///
+/// Kept separate from `MemoryEntry` so the field names stay stable even if
+/// the in-memory layout changes.
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    epoch: u32,
+    token: u16,
+    weight: i16,
+    link1: u32,
+    link2: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pinned: bool,
+}
+
+impl From<&MemoryEntry> for JsonEntry {
+    fn from(entry: &MemoryEntry) -> Self {
+        let (link1, link2) = entry.links();
+        Self {
+            epoch: entry.epoch(),
+            token: entry.token(),
+            weight: entry.weight(),
+            link1,
+            link2,
+            expires_at: entry.expires_at(),
+            pinned: entry.is_pinned(),
+        }
+    }
+}
+
+impl From<JsonEntry> for MemoryEntry {
+    fn from(json: JsonEntry) -> Self {
+        let mut entry = MemoryEntry::with_links(json.epoch, json.token, json.weight, json.link1, json.link2);
+        entry.set_pinned(json.pinned);
+        match json.expires_at {
+            Some(expires_at) => entry.with_expiry(expires_at),
+            None => entry,
+        }
+    }
+}
+
+/// Serializes entries as a pretty-printed JSON array
+pub fn export_json(entries: &[MemoryEntry]) -> String {
+    let json: Vec<JsonEntry> = entries.iter().map(JsonEntry::from).collect();
+    serde_json::to_string_pretty(&json).expect("memory entries always serialize to JSON")
+}
+
+/// Parses entries from a JSON array produced by `export_json`
+pub fn import_json(s: &str) -> Result<Vec<MemoryEntry>, serde_json::Error> {
+    let json: Vec<JsonEntry> = serde_json::from_str(s)?;
+    Ok(json.into_iter().map(MemoryEntry::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let entries = vec![
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+            MemoryEntry::with_links(2, 101, -250, 1, 0),
+        ];
+
+        let json = export_json(&entries);
+        assert!(json.contains("\"epoch\": 2"));
+        assert!(json.contains("\"link1\": 1"));
+
+        let imported = import_json(&json).unwrap();
+        assert_eq!(imported.len(), 2);
+        for (a, b) in entries.iter().zip(&imported) {
+            assert_eq!(a.epoch(), b.epoch());
+            assert_eq!(a.token(), b.token());
+            assert_eq!(a.weight(), b.weight());
+            assert_eq!(a.links(), b.links());
+        }
+
+        assert!(import_json("[{\"epoch\": 1}]").is_err());
+    }
+}
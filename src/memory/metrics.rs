@@ -0,0 +1,99 @@
+//! Point-in-time counters for the whole memory system.
+
+use std::fmt::Write;
+
+/// Snapshot of the memory system's gauges and counters
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemMetrics {
+    pub stage1_entries: usize,
+    pub stage1_avg_weight: f32,
+    pub stage2_files: usize,
+    pub stage2_bytes: u64,
+    pub stage3_core_memories: usize,
+    pub stage3_replicas_total: usize,
+    pub stage3_replicas_healthy: usize,
+    pub cache_hit_rate: f32,
+    pub corrections_performed: u64,
+}
+
+impl MemMetrics {
+    /// Formats the snapshot in the Prometheus text exposition format
+    ///
+    /// Metric names are stable and prefixed with `mem8_`.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+
+        metric("mem8_stage1_entries", "gauge", "Memories held in Stage 1.",
+            &[("", self.stage1_entries.to_string())]);
+        metric("mem8_stage1_avg_weight", "gauge", "Average weight of Stage 1 memories.",
+            &[("", self.stage1_avg_weight.to_string())]);
+        metric("mem8_stage2_files", "gauge", "Stage 2 storage files.",
+            &[("", self.stage2_files.to_string())]);
+        metric("mem8_stage2_bytes", "gauge", "Bytes used by Stage 2 storage files.",
+            &[("", self.stage2_bytes.to_string())]);
+        metric("mem8_stage3_core_memories", "gauge", "Core memories held in Stage 3.",
+            &[("", self.stage3_core_memories.to_string())]);
+        metric("mem8_stage3_replicas", "gauge", "Stage 3 replica copies by state.", &[
+            ("{state=\"healthy\"}", self.stage3_replicas_healthy.to_string()),
+            ("{state=\"damaged\"}", (self.stage3_replicas_total - self.stage3_replicas_healthy).to_string()),
+        ]);
+        metric("mem8_cache_hit_ratio", "gauge", "Fraction of cache lookups that hit.",
+            &[("", self.cache_hit_rate.to_string())]);
+        metric("mem8_corrections_total", "counter", "Replica repairs and shard reconstructions.",
+            &[("", self.corrections_performed.to_string())]);
+
+        out
+    }
+}
+
+/// Bucket edges used when none are configured: negative weights, weights
+/// below the default Stage 1 retention threshold, then decades up from it
+pub const DEFAULT_WEIGHT_EDGES: [i16; 4] = [0, 100, 1000, 10000];
+
+/// How many memories fall in each weight band
+///
+/// N ascending `edges` make N + 1 buckets: `counts[0]` holds weights below
+/// `edges[0]`, `counts[i]` those in `edges[i - 1]..edges[i]`, and the last
+/// bucket those at or above the final edge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeightHistogram {
+    pub edges: Vec<i16>,
+    pub counts: Vec<usize>,
+}
+
+impl WeightHistogram {
+    /// Creates an empty histogram; edges are sorted and deduplicated
+    pub fn new(edges: &[i16]) -> Self {
+        let mut edges = edges.to_vec();
+        edges.sort_unstable();
+        edges.dedup();
+        let counts = vec![0; edges.len() + 1];
+        Self { edges, counts }
+    }
+
+    /// Creates a histogram over `weights`
+    pub fn from_weights(edges: &[i16], weights: impl IntoIterator<Item = i16>) -> Self {
+        let mut histogram = Self::new(edges);
+        for weight in weights {
+            histogram.record(weight);
+        }
+        histogram
+    }
+
+    pub fn record(&mut self, weight: i16) {
+        let bucket = self.edges.partition_point(|&edge| edge <= weight);
+        self.counts[bucket] += 1;
+    }
+
+    /// Number of weights recorded
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+}
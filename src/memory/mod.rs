@@ -1,21 +1,29 @@
 //! Core logic for managing temporal memory entries.
 
-pub struct MemoryEntry {
-    pub epoch: u32,       // Epoch pointer (seconds since SeedFile epoch)
-    pub token: u16,       // Token ID
-    pub weight: i16,      // Importance (-30,000 to 30,000)
-    pub link1: Option<u32>, // First link
-    pub link2: Option<u32>, // Second link
-}
+#[cfg(feature = "tokio")]
+pub mod async_stage2;
+pub mod archive;
+pub mod checksum;
+pub mod clock;
+pub mod compression;
+pub mod config;
+pub mod entry;
+pub mod error;
+pub mod error_correction;
+pub mod interop;
+pub mod metrics;
+pub mod personality_cache;
+pub mod pipeline;
+pub mod stage1;
+pub mod stage2;
+pub mod stage3;
+pub mod token_codec;
+pub mod token_index;
 
-impl MemoryEntry {
-    pub fn new(epoch: u32, token: u16, weight: i16, link1: Option<u32>, link2: Option<u32>) -> Self {
-        Self {
-            epoch,
-            token,
-            weight,
-            link1,
-            link2,
-        }
-    }
-}
+pub use clock::{Clock, MemoryClock, MockClock};
+pub use config::{CacheConfig, ConfigError, MemConfig, MemConfigBuilder};
+pub use entry::{MemoryEntry, MemoryEntryBuilder, PackedEntry, WireError};
+pub use error::MemError;
+pub use personality_cache::{BackingLoader, EvictionHook, EvictionPolicy, LinkResolver, MemoryCache, MemoryView, PersonalityCache, ScoreWeights};
+pub use token_codec::{DictionaryCodec, HashingCodec, TokenCodec};
+pub use token_index::TokenIndex;
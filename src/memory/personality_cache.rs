@@ -1,93 +1,766 @@
-use super::entry::MemoryEntry;
-use std::collections::{HashMap, HashSet, BTreeMap};
-use std::time::{SystemTime, Duration};
-use parking_lot::RwLock;
+use super::entry::{MemoryEntry, PackedEntry};
+use super::metrics::{WeightHistogram, DEFAULT_WEIGHT_EDGES};
+use super::token_codec::{HashingCodec, TokenCodec};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 
 /// Represents the importance of a memory in the personality matrix
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct PersonalityScore {
-    weight: u16,
+    weight: i16,
     access_count: u32,
     link_strength: f32,
     last_access: SystemTime,
 }
 
+impl PersonalityScore {
+    /// Combined relevance used for ranking and eviction
+    ///
+    /// Weight scaled by link strength, plus recency, which halves every
+    /// `half_life` since the last access.
+    fn relevance(&self, weights: &ScoreWeights, half_life: Duration, now: SystemTime) -> f32 {
+        self.static_relevance(weights) + weights.recency * self.recency(half_life, now)
+    }
+
+    /// From 1.0 just after an access, halving every `half_life`
+    fn recency(&self, half_life: Duration, now: SystemTime) -> f32 {
+        let idle = now.duration_since(self.last_access).unwrap_or_default();
+        0.5f32.powf(idle.as_secs_f32() / half_life.as_secs_f32().max(f32::EPSILON))
+    }
+
+    /// The part of `relevance` that does not change as time passes
+    fn static_relevance(&self, weights: &ScoreWeights) -> f32 {
+        let weight = self.weight as f32 / i16::MAX as f32;
+        weight * (weights.weight + weights.link_strength * self.link_strength)
+    }
+}
+
+/// How much each factor contributes to a memory's relevance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreWeights {
+    /// Multiplier on the normalized memory weight on its own, without link
+    /// strength; 0 ranks by weight × link strength alone
+    pub weight: f32,
+    /// Multiplier on link strength, which scales with the memory's weight
+    pub link_strength: f32,
+    /// Multiplier on recency, from 1.0 (just accessed) toward 0.0
+    pub recency: f32,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            weight: 0.0,
+            link_strength: 1.0,
+            recency: 0.05,
+        }
+    }
+}
+
+/// Position of a cached memory in the eviction order
+///
+/// A cache only ever stores the variant matching its policy, so comparing
+/// across variants never matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum EvictionKey {
+    /// Relevance without its recency term, which is the only part that
+    /// changes with time, then weight for memories that tie on it
+    Static(TotalF32, i16),
+    LastAccess(SystemTime),
+    AccessCount(u32),
+}
+
+/// Cached epochs kept in eviction order, so a full cache need not score
+/// every entry to pick one to drop
+#[derive(Default)]
+struct EvictionIndex {
+    /// Ordered by the cache policy's key
+    by_key: BTreeSet<(EvictionKey, u32)>,
+    /// Ordered by last access, which bounds how much recency can still add
+    by_access: BTreeSet<(SystemTime, u32)>,
+}
+
+impl EvictionIndex {
+    fn insert(&mut self, key: EvictionKey, score: &PersonalityScore, epoch: u32) {
+        self.by_key.insert((key, epoch));
+        self.by_access.insert((score.last_access, epoch));
+    }
+
+    fn remove(&mut self, key: EvictionKey, score: &PersonalityScore, epoch: u32) {
+        self.by_key.remove(&(key, epoch));
+        self.by_access.remove(&(score.last_access, epoch));
+    }
+}
+
+/// An `f32` ordered by `total_cmp`, so it can key a `BTreeSet`
+#[derive(Debug, Clone, Copy)]
+struct TotalF32(f32);
+
+impl PartialEq for TotalF32 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for TotalF32 {}
+
+impl PartialOrd for TotalF32 {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TotalF32 {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Chooses which memory is dropped when the cache is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Lowest weight boosted by link strength and recency
+    #[default]
+    WeightedLink,
+    /// Oldest `last_access`
+    LeastRecentlyUsed,
+    /// Lowest `access_count`
+    LeastFrequentlyUsed,
+}
+
+/// One mutating call recorded in the operation log
+#[derive(Debug, Serialize, Deserialize)]
+enum CacheOp {
+    Update { entry: MemoryEntry, related_tokens: HashSet<u16> },
+    Add { entry: MemoryEntry, related_tokens: HashSet<u16> },
+    Remove { epoch: u32 },
+    Access { epoch: u32 },
+    Pin { epoch: u32, pinned: bool },
+}
+
+/// A cached memory together with its score and the related tokens it was
+/// indexed under
+///
+/// Links and expiry live in the cache's side table, so memories without
+/// them cost only the packed entry. Pinned memories are left out of the
+/// eviction index.
+struct CachedMemory {
+    entry: PackedEntry,
+    pinned: bool,
+    score: PersonalityScore,
+    related_tokens: HashSet<u16>,
+}
+
+/// The parts of a cached memory that `PackedEntry` leaves out
+#[derive(Clone, Copy)]
+struct EntryLinks {
+    link1: u32,
+    link2: u32,
+    expires_at: Option<u32>,
+}
+
+impl EntryLinks {
+    /// Splits an entry into its packed form and, if it has any, its links
+    /// and expiry
+    fn split(entry: MemoryEntry) -> (PackedEntry, Option<Self>) {
+        let (link1, link2) = entry.links();
+        let links = (link1 != 0 || link2 != 0 || entry.expires_at().is_some())
+            .then(|| Self { link1, link2, expires_at: entry.expires_at() });
+        (entry.into(), links)
+    }
+
+    /// Reassembles the entry a cached memory was split from
+    fn join(cached: &CachedMemory, links: Option<&Self>) -> MemoryEntry {
+        MemoryView { entry: cached.entry, links, pinned: cached.pinned }.to_entry()
+    }
+}
+
+/// A memory lent out by `PersonalityCache::with_memory`: the packed entry
+/// plus a reference to its links, if it has any
+#[derive(Clone, Copy)]
+pub struct MemoryView<'a> {
+    entry: PackedEntry,
+    links: Option<&'a EntryLinks>,
+    pinned: bool,
+}
+
+impl MemoryView<'_> {
+    pub fn packed(&self) -> PackedEntry { self.entry }
+    pub fn epoch(&self) -> u32 { self.entry.epoch() }
+    pub fn token(&self) -> u16 { self.entry.token() }
+    pub fn weight(&self) -> i16 { self.entry.weight() }
+    pub fn is_pinned(&self) -> bool { self.pinned }
+
+    pub fn links(&self) -> (u32, u32) {
+        self.links.map_or((0, 0), |links| (links.link1, links.link2))
+    }
+
+    pub fn expires_at(&self) -> Option<u32> {
+        self.links.and_then(|links| links.expires_at)
+    }
+
+    /// Copies the memory out as a full entry
+    pub fn to_entry(&self) -> MemoryEntry {
+        let packed = self.entry;
+        let mut entry = match self.links {
+            Some(links) => {
+                let entry = MemoryEntry::with_links(packed.epoch(), packed.token(), packed.weight(), links.link1, links.link2);
+                match links.expires_at {
+                    Some(expires_at) => entry.with_expiry(expires_at),
+                    None => entry,
+                }
+            }
+            None => packed.into(),
+        };
+        entry.set_pinned(self.pinned);
+        entry
+    }
+}
+
+/// Loads a memory from colder storage on a cache miss
+pub type BackingLoader = Box<dyn Fn(u32) -> Option<MemoryEntry> + Send + Sync>;
+
+/// Looks up the weight of a linked memory that is not cached
+pub type LinkResolver = Box<dyn Fn(u32) -> Option<i16> + Send + Sync>;
+
+/// Called with each memory the cache evicts to make room
+pub type EvictionHook = Box<dyn Fn(&MemoryEntry) + Send + Sync>;
+
 pub struct PersonalityCache {
-    entries: RwLock<HashMap<u32, (MemoryEntry, PersonalityScore)>>,
-    token_index: RwLock<BTreeMap<u16, HashSet<u32>>>,  // Token -> Epochs mapping
+    entries: RwLock<HashMap<u32, CachedMemory>>,
+    /// Links and expiry of the cached memories that have any; always locked
+    /// after `entries`
+    links: RwLock<HashMap<u32, EntryLinks>>,
+    /// Token -> Epochs mapping; always locked after `entries`
+    token_index: RwLock<BTreeMap<u16, HashSet<u32>>>,
+    /// Every cached epoch in eviction order; only touched while the entries
+    /// lock is held for writing
+    eviction_index: Mutex<EvictionIndex>,
     max_entries: usize,
+    /// Entries evicted at once when an insert finds the cache full
+    eviction_batch: usize,
+    /// Minimum link strength for `update_memory` to admit an entry; scoring
+    /// only affects what is evicted or ranked once admitted
     personality_threshold: f32,
+    eviction_policy: EvictionPolicy,
+    score_weights: ScoreWeights,
+    recency_half_life: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Append-only record of every mutating call, if enabled
+    op_log: Option<Mutex<File>>,
+    /// Consulted by `get_memory` for epochs that are not cached
+    backing_store: Option<BackingLoader>,
+    /// Consulted when scoring a memory whose links point outside the cache
+    link_resolver: Option<LinkResolver>,
+    /// Told about every eviction; cloned out so it runs with no lock held
+    eviction_hook: RwLock<Option<Arc<EvictionHook>>>,
+    /// Maps string concepts to tokens for the `*_concept` helpers
+    codec: Arc<dyn TokenCodec>,
+    /// Bucket edges for the weight histogram in `stats`
+    weight_histogram_edges: Vec<i16>,
+    /// Most related tokens a memory is indexed under
+    max_related_tokens: usize,
+    /// Added to a memory's weight each time `get_memory` finds it
+    access_boost: i16,
+    /// Entries scored by the most recent eviction
+    #[cfg(test)]
+    last_eviction_scan: std::sync::atomic::AtomicUsize,
 }
 
+/// Shorthand used by callers that treat the personality cache as a plain
+/// memory cache
+pub type MemoryCache = PersonalityCache;
+
 impl PersonalityCache {
     pub fn new(max_entries: usize, personality_threshold: f32) -> Self {
+        Self::with_policy(max_entries, personality_threshold, EvictionPolicy::default())
+    }
+
+    /// Creates a cache that evicts according to `eviction_policy`
+    pub fn with_policy(
+        max_entries: usize,
+        personality_threshold: f32,
+        eviction_policy: EvictionPolicy,
+    ) -> Self {
         Self {
             entries: RwLock::new(HashMap::new()),
+            links: RwLock::new(HashMap::new()),
             token_index: RwLock::new(BTreeMap::new()),
+            eviction_index: Mutex::new(EvictionIndex::default()),
             max_entries,
+            eviction_batch: 1,
             personality_threshold,
+            eviction_policy,
+            score_weights: ScoreWeights::default(),
+            recency_half_life: Duration::from_secs(3600 * 24),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            op_log: None,
+            backing_store: None,
+            link_resolver: None,
+            eviction_hook: RwLock::new(None),
+            codec: Arc::new(HashingCodec::new()),
+            weight_histogram_edges: DEFAULT_WEIGHT_EDGES.to_vec(),
+            max_related_tokens: usize::MAX,
+            access_boost: 0,
+            #[cfg(test)]
+            last_eviction_scan: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends every update, removal and lookup to the log at `path`, so the
+    /// cache can be rebuilt with `replay_from` after a restart
+    ///
+    /// Logging is best effort: a failed write does not fail the operation.
+    pub fn with_op_log(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.op_log = Some(Mutex::new(file));
+        Ok(self)
+    }
+
+    /// Rebuilds a cache by applying every operation in the log at `path`
+    ///
+    /// Lookups are replayed too, so access counts and hit rates survive. A
+    /// torn record at the end of the log is ignored. The returned cache does
+    /// not log; chain `with_op_log` to keep appending.
+    pub fn replay_from(path: impl AsRef<Path>, max_entries: usize, personality_threshold: f32) -> io::Result<Self> {
+        let cache = Self::new(max_entries, personality_threshold);
+        let mut reader = BufReader::new(File::open(path)?);
+
+        loop {
+            let op: CacheOp = match bincode::deserialize_from(&mut reader) {
+                Ok(op) => op,
+                Err(e) => match *e {
+                    bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    other => return Err(io::Error::new(io::ErrorKind::InvalidData, other)),
+                },
+            };
+
+            match op {
+                CacheOp::Update { entry, related_tokens } => {
+                    cache.update_memory(entry, related_tokens);
+                }
+                CacheOp::Add { entry, related_tokens } => cache.add_memory(entry, related_tokens),
+                CacheOp::Remove { epoch } => {
+                    cache.remove_memory(epoch);
+                }
+                CacheOp::Access { epoch } => {
+                    cache.get_memory(epoch);
+                }
+                CacheOp::Pin { epoch, pinned } => {
+                    cache.set_pinned(epoch, pinned);
+                }
+            }
+        }
+        Ok(cache)
+    }
+
+    /// Appends one operation to the log; callers hold the entries lock so
+    /// the log order matches the order operations were applied
+    fn log_op(&self, op: &CacheOp) {
+        if let Some(log) = &self.op_log {
+            if let Ok(record) = bincode::serialize(op) {
+                let _ = log.lock().write_all(&record);
+            }
+        }
+    }
+
+    /// Makes `get_memory` fall back to `loader` on a miss, caching whatever
+    /// it returns
+    ///
+    /// Loaded memories are scored like `add_memory` and skip the personality
+    /// threshold. The loader runs without any cache lock held.
+    pub fn with_backing_store(
+        mut self,
+        loader: impl Fn(u32) -> Option<MemoryEntry> + Send + Sync + 'static,
+    ) -> Self {
+        self.backing_store = Some(Box::new(loader));
+        self
+    }
+
+    /// Lets scoring weigh links to uncached memories using `resolver`
+    ///
+    /// The resolver runs with the cache locked, so it must not call back
+    /// into this cache.
+    pub fn with_link_resolver(
+        mut self,
+        resolver: impl Fn(u32) -> Option<i16> + Send + Sync + 'static,
+    ) -> Self {
+        self.link_resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Evicts `batch` entries whenever an insert finds the cache full,
+    /// leaving room for the next `batch - 1` inserts to skip eviction
+    ///
+    /// Zero is treated as one.
+    pub fn with_eviction_batch(mut self, batch: usize) -> Self {
+        self.eviction_batch = batch.max(1);
+        self
+    }
+
+    /// Calls `hook` with every memory evicted to make room, replacing any
+    /// earlier hook
+    ///
+    /// The hook runs after the cache's locks are released, so it may call
+    /// back into the cache; the memory is already gone by then.
+    pub fn set_eviction_hook(&self, hook: EvictionHook) {
+        *self.eviction_hook.write() = Some(Arc::new(hook));
+    }
+
+    fn notify_evicted(&self, evicted: Vec<MemoryEntry>) {
+        if evicted.is_empty() {
+            return;
+        }
+        let hook = self.eviction_hook.read().clone();
+        if let Some(hook) = hook {
+            for entry in &evicted {
+                hook(entry);
+            }
+        }
+    }
+
+    /// Encodes string concepts with `codec`; pass the codec Stage 1 uses so
+    /// both agree on tokens
+    pub fn with_codec(mut self, codec: Arc<dyn TokenCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets the bucket edges of the weight histogram in `stats`
+    pub fn with_weight_histogram_edges(mut self, edges: Vec<i16>) -> Self {
+        self.weight_histogram_edges = edges;
+        self
+    }
+
+    /// Indexes each memory under at most `max` related tokens
+    ///
+    /// `add_memory_ranked` and `add_concept` keep the first `max` in the
+    /// order given; the set-based calls, having no order, keep the `max`
+    /// lowest tokens.
+    pub fn with_max_related_tokens(mut self, max: usize) -> Self {
+        self.max_related_tokens = max;
+        self
+    }
+
+    /// Strengthens recalled memories: every `get_memory` hit adds `boost` to
+    /// the memory's weight, saturating at the `i16` bounds
+    ///
+    /// A boost of 0, the default, leaves weights alone.
+    pub fn with_access_boost(mut self, boost: i16) -> Self {
+        self.access_boost = boost;
+        self
+    }
+
+    /// Trims an unordered set of related tokens to the cap
+    fn cap_related(&self, related_tokens: HashSet<u16>) -> HashSet<u16> {
+        if related_tokens.len() <= self.max_related_tokens {
+            return related_tokens;
+        }
+        let mut tokens: Vec<u16> = related_tokens.into_iter().collect();
+        tokens.sort_unstable();
+        self.cap_ranked(tokens)
+    }
+
+    /// Keeps the first distinct tokens of `ranked` up to the cap
+    fn cap_ranked(&self, ranked: impl IntoIterator<Item = u16>) -> HashSet<u16> {
+        let mut kept = HashSet::new();
+        for token in ranked {
+            if kept.len() >= self.max_related_tokens {
+                break;
+            }
+            kept.insert(token);
+        }
+        kept
+    }
+
+    /// Sets how weight, links and recency combine, and how quickly recency
+    /// fades
+    pub fn with_score_weights(mut self, score_weights: ScoreWeights, recency_half_life: Duration) -> Self {
+        self.score_weights = score_weights;
+        self.recency_half_life = recency_half_life;
+
+        // Static keys depend on the weights, so re-key anything already cached
+        let mut index = EvictionIndex::default();
+        for (&epoch, cached) in self.entries.read().iter() {
+            index.insert(self.eviction_key(&cached.score), &cached.score, epoch);
+        }
+        *self.eviction_index.get_mut() = index;
+        self
+    }
+
+    fn relevance(&self, score: &PersonalityScore, now: SystemTime) -> f32 {
+        score.relevance(&self.score_weights, self.recency_half_life, now)
+    }
+
+    fn eviction_key(&self, score: &PersonalityScore) -> EvictionKey {
+        match self.eviction_policy {
+            EvictionPolicy::WeightedLink => {
+                EvictionKey::Static(TotalF32(score.static_relevance(&self.score_weights)), score.weight)
+            }
+            EvictionPolicy::LeastRecentlyUsed => EvictionKey::LastAccess(score.last_access),
+            EvictionPolicy::LeastFrequentlyUsed => EvictionKey::AccessCount(score.access_count),
         }
     }
 
     /// Adds or updates a memory in the personality cache
     pub fn update_memory(&self, entry: MemoryEntry, related_tokens: HashSet<u16>) -> bool {
+        let related_tokens = self.cap_related(related_tokens);
         let mut entries = self.entries.write();
         let mut token_index = self.token_index.write();
 
-        let epoch = entry.epoch();
-        let score = self.calculate_personality_score(&entry, &related_tokens);
+        // Score against the map we already hold; taking another lock here would
+        // deadlock since parking_lot's RwLock is not reentrant.
+        let score = self.calculate_personality_score(&entries, &entry, &related_tokens);
+        if self.op_log.is_some() {
+            self.log_op(&CacheOp::Update { entry: entry.clone(), related_tokens: related_tokens.clone() });
+        }
 
         // Only cache if the personality score meets our threshold
-        if score.link_strength >= self.personality_threshold {
-            if entries.len() >= self.max_entries {
-                self.evict_lowest_scoring(&mut entries, &mut token_index);
-            }
+        let admitted = score.link_strength >= self.personality_threshold;
+        let evicted = if admitted {
+            self.insert_scored(&mut entries, &mut token_index, entry, score, related_tokens)
+        } else {
+            Vec::new()
+        };
+
+        drop(token_index);
+        drop(entries);
+        self.notify_evicted(evicted);
+        admitted
+    }
+
+    /// Adds a memory regardless of the personality threshold, storing its
+    /// related tokens alongside it
+    pub fn add_memory(&self, entry: MemoryEntry, related_tokens: HashSet<u16>) {
+        self.add_capped(entry, self.cap_related(related_tokens));
+    }
+
+    /// Like `add_memory`, with related tokens given strongest first so the
+    /// cap keeps the strongest
+    pub fn add_memory_ranked(&self, entry: MemoryEntry, related_tokens: &[u16]) {
+        self.add_capped(entry, self.cap_ranked(related_tokens.iter().copied()));
+    }
+
+    fn add_capped(&self, entry: MemoryEntry, related_tokens: HashSet<u16>) {
+        let mut entries = self.entries.write();
+        let mut token_index = self.token_index.write();
 
-            // Update token index
+        let score = self.calculate_personality_score(&entries, &entry, &related_tokens);
+        if self.op_log.is_some() {
+            self.log_op(&CacheOp::Add { entry: entry.clone(), related_tokens: related_tokens.clone() });
+        }
+        let evicted = self.insert_scored(&mut entries, &mut token_index, entry, score, related_tokens);
+
+        drop(token_index);
+        drop(entries);
+        self.notify_evicted(evicted);
+    }
+
+    /// Adds a memory for a string concept, indexed under its related
+    /// concepts too
+    pub fn add_concept(&self, epoch: u32, concept: &str, weight: i16, related: &[&str]) {
+        let entry = MemoryEntry::with_links(epoch, self.codec.encode(concept), weight, 0, 0);
+        let related_tokens: Vec<u16> = related.iter().map(|concept| self.codec.encode(concept)).collect();
+        self.add_memory_ranked(entry, &related_tokens);
+    }
+
+    /// Finds memories for or related to a string concept, most relevant
+    /// first
+    pub fn find_by_concept(&self, concept: &str, limit: usize) -> Vec<MemoryEntry> {
+        self.find_related_memories(self.codec.encode(concept), limit)
+    }
+
+    /// The string concept a cached memory's token decodes to
+    pub fn concept(&self, epoch: u32) -> Option<String> {
+        let token = self.entries.read().get(&epoch)?.entry.token();
+        self.codec.decode(token)
+    }
+
+    /// Returns the related tokens a cached memory was stored with
+    pub fn get_related_tokens(&self, epoch: u32) -> Option<HashSet<u16>> {
+        self.entries
+            .read()
+            .get(&epoch)
+            .map(|cached| cached.related_tokens.clone())
+    }
+
+    /// Caches a scored memory, returning whatever was evicted to make room,
+    /// lowest scoring first
+    fn insert_scored(
+        &self,
+        entries: &mut HashMap<u32, CachedMemory>,
+        token_index: &mut BTreeMap<u16, HashSet<u32>>,
+        entry: MemoryEntry,
+        score: PersonalityScore,
+        related_tokens: HashSet<u16>,
+    ) -> Vec<MemoryEntry> {
+        let epoch = entry.epoch();
+        let mut evicted = Vec::new();
+
+        if let Some(previous) = entries.remove(&epoch) {
+            self.eviction_index.lock().remove(self.eviction_key(&previous.score), &previous.score, epoch);
+            Self::purge_from_index(token_index, &previous);
+        } else if entries.len() >= self.max_entries {
+            // Evicting down to below capacity spares the next inserts a scan
+            let batch = self.eviction_batch.min(entries.len());
+            evicted.extend((0..batch).map_while(|_| self.evict_lowest_scoring(entries, token_index)));
+        }
+
+        // Update token index
+        token_index
+            .entry(entry.token())
+            .or_default()
+            .insert(epoch);
+
+        for &token in &related_tokens {
             token_index
-                .entry(entry.token())
+                .entry(token)
                 .or_default()
                 .insert(epoch);
+        }
 
-            for token in related_tokens {
-                token_index
-                    .entry(token)
-                    .or_default()
-                    .insert(epoch);
-            }
-
-            entries.insert(epoch, (entry, score));
-            true
-        } else {
-            false
+        let pinned = entry.is_pinned();
+        let (entry, links) = EntryLinks::split(entry);
+        match links {
+            Some(links) => self.links.write().insert(epoch, links),
+            None => self.links.write().remove(&epoch),
+        };
+        if !pinned {
+            self.eviction_index.lock().insert(self.eviction_key(&score), &score, epoch);
         }
+        entries.insert(epoch, CachedMemory { entry, pinned, score, related_tokens });
+        evicted
     }
 
     /// Retrieves a memory and updates its access metrics
+    ///
+    /// On a miss the backing store, if one is set, is asked for the memory;
+    /// the lookup still counts as a miss.
     pub fn get_memory(&self, epoch: u32) -> Option<MemoryEntry> {
+        self.with_memory(epoch, |memory| memory.to_entry())
+    }
+
+    /// Runs `f` on a view of a memory and updates its access metrics,
+    /// without assembling a `MemoryEntry`
+    ///
+    /// Hits and misses, including the backing store fallback, are counted as
+    /// in `get_memory`. The view holds copies of the packed entry and links
+    /// taken once the access is recorded, so `f` runs with no lock held and
+    /// may call back into the cache.
+    pub fn with_memory<R>(&self, epoch: u32, f: impl FnOnce(&MemoryView<'_>) -> R) -> Option<R> {
         let mut entries = self.entries.write();
-        
-        if let Some((entry, score)) = entries.get_mut(&epoch) {
-            let mut updated_score = *score;
-            updated_score.access_count += 1;
-            updated_score.last_access = SystemTime::now();
-            
-            entries.insert(epoch, (entry.clone(), updated_score));
-            Some(entry.clone())
-        } else {
-            None
+        self.log_op(&CacheOp::Access { epoch });
+
+        let found = entries.get_mut(&epoch).map(|cached| {
+            self.record_access(epoch, cached);
+            (cached.entry, cached.pinned, self.links.read().get(&epoch).copied())
+        });
+        drop(entries);
+
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let (entry, pinned, links) = match found {
+            Some(found) => found,
+            None => {
+                let loaded = self.load_from_backing_store(epoch)?;
+                let pinned = loaded.is_pinned();
+                let (entry, links) = EntryLinks::split(loaded);
+                (entry, pinned, links)
+            }
+        };
+        Some(f(&MemoryView { entry, links: links.as_ref(), pinned }))
+    }
+
+    /// Counts a hit on `cached`, applying the access boost and moving it in
+    /// the eviction index
+    fn record_access(&self, epoch: u32, cached: &mut CachedMemory) {
+        let mut index = self.eviction_index.lock();
+        index.remove(self.eviction_key(&cached.score), &cached.score, epoch);
+        cached.score.access_count += 1;
+        cached.score.last_access = SystemTime::now();
+        if self.access_boost != 0 {
+            let weight = cached.entry.weight().saturating_add(self.access_boost);
+            cached.entry = PackedEntry::new(epoch, cached.entry.token(), weight);
+            cached.score.weight = weight;
+        }
+        if !cached.pinned {
+            index.insert(self.eviction_key(&cached.score), &cached.score, epoch);
+        }
+    }
+
+    /// Fetches a missed epoch from the backing store and caches it as
+    /// accessed once
+    fn load_from_backing_store(&self, epoch: u32) -> Option<MemoryEntry> {
+        let entry = (self.backing_store.as_ref()?)(epoch)?;
+
+        let mut entries = self.entries.write();
+        let mut token_index = self.token_index.write();
+        let related_tokens = HashSet::new();
+        let mut score = self.calculate_personality_score(&entries, &entry, &related_tokens);
+        score.access_count = 1;
+        if self.op_log.is_some() {
+            self.log_op(&CacheOp::Add { entry: entry.clone(), related_tokens: related_tokens.clone() });
         }
+        let evicted = self.insert_scored(&mut entries, &mut token_index, entry.clone(), score, related_tokens);
+
+        drop(token_index);
+        drop(entries);
+        self.notify_evicted(evicted);
+        Some(entry)
     }
 
-    /// Finds related memories based on token patterns
+    /// Returns how many times a cached memory has been retrieved
+    pub fn access_count(&self, epoch: u32) -> Option<u32> {
+        self.entries
+            .read()
+            .get(&epoch)
+            .map(|cached| cached.score.access_count)
+    }
+
+    /// Returns the `n` most accessed memories as (epoch, count), busiest first
+    pub fn hottest(&self, n: usize) -> Vec<(u32, u32)> {
+        let entries = self.entries.read();
+        let mut counts: Vec<(u32, u32)> = entries.iter()
+            .map(|(&epoch, cached)| (epoch, cached.score.access_count))
+            .collect();
+
+        // Ties fall back to epoch order so the ranking is stable
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+
+    /// Finds related memories based on token patterns, most relevant first
+    ///
+    /// Ties go to the most recently accessed memory.
     pub fn find_related_memories(&self, token: u16, limit: usize) -> Vec<MemoryEntry> {
-        let token_index = self.token_index.read();
         let entries = self.entries.read();
+        let token_index = self.token_index.read();
+        let links = self.links.read();
         
         if let Some(epochs) = token_index.get(&token) {
-            epochs.iter()
+            let mut candidates: Vec<&CachedMemory> = epochs.iter()
                 .filter_map(|&epoch| entries.get(&epoch))
-                .map(|(entry, _)| entry.clone())
+                .collect();
+            let now = SystemTime::now();
+            candidates.sort_by(|a, b| {
+                self.relevance(&b.score, now)
+                    .total_cmp(&self.relevance(&a.score, now))
+                    .then(b.score.last_access.cmp(&a.score.last_access))
+            });
+
+            candidates.into_iter()
+                .map(|cached| EntryLinks::join(cached, links.get(&cached.entry.epoch())))
                 .take(limit)
                 .collect()
         } else {
@@ -95,21 +768,93 @@ impl PersonalityCache {
         }
     }
 
-    /// Returns the personality relevance score for a memory
+    /// Finds memories for or related to `token` with epochs in
+    /// `start_epoch..=end_epoch`, newest first
+    pub fn find_related_in_window(
+        &self,
+        token: u16,
+        start_epoch: u32,
+        end_epoch: u32,
+        limit: usize,
+    ) -> Vec<MemoryEntry> {
+        let entries = self.entries.read();
+        let token_index = self.token_index.read();
+        let links = self.links.read();
+
+        let Some(epochs) = token_index.get(&token) else {
+            return Vec::new();
+        };
+        let mut in_window: Vec<u32> = epochs.iter()
+            .copied()
+            .filter(|epoch| (start_epoch..=end_epoch).contains(epoch))
+            .collect();
+        in_window.sort_unstable_by(|a, b| b.cmp(a));
+
+        in_window.into_iter()
+            .filter_map(|epoch| entries.get(&epoch))
+            .map(|cached| EntryLinks::join(cached, links.get(&cached.entry.epoch())))
+            .take(limit)
+            .collect()
+    }
+
+    /// Walks the link graph breadth-first from `start`, following up to
+    /// `max_depth` links away
+    ///
+    /// The start memory comes first. Each cached memory is returned once, so
+    /// cycles terminate; links to memories outside the cache are skipped.
+    pub fn traverse(&self, start: u32, max_depth: usize) -> Vec<MemoryEntry> {
+        let entries = self.entries.read();
+        let links = self.links.read();
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        let mut chain = Vec::new();
+
+        if entries.contains_key(&start) {
+            visited.insert(start);
+            queue.push_back((start, 0));
+        }
+
+        while let Some((epoch, depth)) = queue.pop_front() {
+            let entry = EntryLinks::join(&entries[&epoch], links.get(&epoch));
+            let (link1, link2) = entry.links();
+            chain.push(entry);
+            if depth == max_depth {
+                continue;
+            }
+
+            for link in [link1, link2] {
+                if link != 0 && entries.contains_key(&link) && visited.insert(link) {
+                    queue.push_back((link, depth + 1));
+                }
+            }
+        }
+
+        chain
+    }
+
+    /// Returns the personality relevance score for a memory, reading linked
+    /// entries from an already-locked map
+    ///
+    /// Link strength is the mean normalized weight over the memory's links.
+    /// A link to an uncached memory is weighed through the link resolver,
+    /// and counts as zero if there is none or it cannot find the memory.
     fn calculate_personality_score(
-        &self, 
-        entry: &MemoryEntry, 
-        related_tokens: &HashSet<u16>
+        &self,
+        entries: &HashMap<u32, CachedMemory>,
+        entry: &MemoryEntry,
+        _related_tokens: &HashSet<u16>,
     ) -> PersonalityScore {
-        let entries = self.entries.read();
         let (link1, link2) = entry.links();
-        
-        // Calculate link strength based on connected memories
-        let link_strength = [link1, link2].iter()
-            .filter(|&&link| link != 0)
-            .filter_map(|&link| entries.get(&link))
-            .map(|(_, score)| score.weight as f32 / u16::MAX as f32)
-            .sum::<f32>() / 2.0;
+        let links: Vec<u32> = [link1, link2].into_iter().filter(|&link| link != 0).collect();
+
+        let link_weight = |link: u32| match entries.get(&link) {
+            Some(cached) => Some(cached.score.weight),
+            None => self.link_resolver.as_ref().and_then(|resolve| resolve(link)),
+        };
+        let link_strength = links.iter()
+            .filter_map(|&link| link_weight(link))
+            .map(|weight| weight as f32 / i16::MAX as f32)
+            .sum::<f32>() / links.len().max(1) as f32;
 
         PersonalityScore {
             weight: entry.weight(),
@@ -119,43 +864,173 @@ impl PersonalityCache {
         }
     }
 
-    /// Evicts the lowest scoring entry from the cache
+    /// Evicts the lowest scoring entry under the cache's eviction policy
     fn evict_lowest_scoring(
         &self,
-        entries: &mut HashMap<u32, (MemoryEntry, PersonalityScore)>,
+        entries: &mut HashMap<u32, CachedMemory>,
         token_index: &mut BTreeMap<u16, HashSet<u32>>
-    ) {
-        if let Some((&epoch, _)) = entries.iter()
-            .min_by(|&(_, (_, a)), &(_, (_, b))| {
-                let a_score = a.weight as f32 * a.link_strength;
-                let b_score = b.weight as f32 * b.link_strength;
-                a_score.partial_cmp(&b_score).unwrap()
-            }) 
-        {
-            if let Some((entry, _)) = entries.remove(&epoch) {
-                // Clean up token index
-                if let Some(epochs) = token_index.get_mut(&entry.token()) {
-                    epochs.remove(&epoch);
+    ) -> Option<MemoryEntry> {
+        let mut index = self.eviction_index.lock();
+        let lowest = match self.eviction_policy {
+            EvictionPolicy::WeightedLink => self.lowest_relevance(entries, &index),
+            EvictionPolicy::LeastRecentlyUsed | EvictionPolicy::LeastFrequentlyUsed => {
+                #[cfg(test)]
+                self.last_eviction_scan.store(1, Ordering::Relaxed);
+                index.by_key.first().map(|&(_, epoch)| epoch)
+            }
+        };
+
+        let evicted = lowest.and_then(|epoch| entries.remove(&epoch))?;
+        let epoch = evicted.entry.epoch();
+        index.remove(self.eviction_key(&evicted.score), &evicted.score, epoch);
+        Self::purge_from_index(token_index, &evicted);
+        Some(EntryLinks::join(&evicted, self.links.write().remove(&epoch).as_ref()))
+    }
+
+    /// Finds the least relevant entry by walking the static order upward
+    ///
+    /// No entry's recency is below that of the least recently accessed one,
+    /// so the walk stops once no later entry could beat the best found so
+    /// far. Equally relevant entries go in key order, so among unlinked
+    /// memories, which all tie on static relevance under the default
+    /// weights, the lightest goes first unless recency sets them apart.
+    fn lowest_relevance(
+        &self,
+        entries: &HashMap<u32, CachedMemory>,
+        index: &EvictionIndex,
+    ) -> Option<u32> {
+        let now = SystemTime::now();
+        let stalest = index.by_access.first()
+            .map_or(0.0, |&(_, epoch)| entries[&epoch].score.recency(self.recency_half_life, now));
+        let recency_weight = self.score_weights.recency;
+        let floor = recency_weight.min(recency_weight * stalest);
+        let mut lowest: Option<(u32, f32)> = None;
+        #[cfg(test)]
+        let mut scanned = 0;
+
+        for &(key, epoch) in &index.by_key {
+            let EvictionKey::Static(TotalF32(static_relevance), _) = key else { continue };
+            if lowest.is_some_and(|(_, best)| static_relevance + floor >= best) {
+                break;
+            }
+            #[cfg(test)]
+            {
+                scanned += 1;
+            }
+
+            let relevance = self.relevance(&entries[&epoch].score, now);
+            if lowest.is_none_or(|(_, best)| relevance < best) {
+                lowest = Some((epoch, relevance));
+            }
+        }
+
+        #[cfg(test)]
+        self.last_eviction_scan.store(scanned, Ordering::Relaxed);
+        lowest.map(|(epoch, _)| epoch)
+    }
+
+    /// Removes a specific memory from the cache, returning it if present
+    pub fn remove_memory(&self, epoch: u32) -> Option<MemoryEntry> {
+        let mut entries = self.entries.write();
+        let mut token_index = self.token_index.write();
+        self.log_op(&CacheOp::Remove { epoch });
+
+        let removed = entries.remove(&epoch)?;
+        self.eviction_index.lock().remove(self.eviction_key(&removed.score), &removed.score, epoch);
+        Self::purge_from_index(&mut token_index, &removed);
+        Some(EntryLinks::join(&removed, self.links.write().remove(&epoch).as_ref()))
+    }
+
+    /// Pins a cached memory so it is never evicted, returning whether it was
+    /// cached
+    ///
+    /// A cache holding only pinned memories grows past its capacity rather
+    /// than evict one.
+    pub fn pin(&self, epoch: u32) -> bool {
+        self.set_pinned(epoch, true)
+    }
+
+    /// Makes a pinned memory evictable again, returning whether it was cached
+    pub fn unpin(&self, epoch: u32) -> bool {
+        self.set_pinned(epoch, false)
+    }
+
+    fn set_pinned(&self, epoch: u32, pinned: bool) -> bool {
+        let mut entries = self.entries.write();
+        let Some(cached) = entries.get_mut(&epoch) else {
+            return false;
+        };
+        self.log_op(&CacheOp::Pin { epoch, pinned });
+
+        if cached.pinned != pinned {
+            cached.pinned = pinned;
+            let mut index = self.eviction_index.lock();
+            if pinned {
+                index.remove(self.eviction_key(&cached.score), &cached.score, epoch);
+            } else {
+                index.insert(self.eviction_key(&cached.score), &cached.score, epoch);
+            }
+        }
+        true
+    }
+
+    /// Drops a cached memory's epoch from its own token set and every related
+    /// token set, so lookups never yield a dangling epoch
+    fn purge_from_index(token_index: &mut BTreeMap<u16, HashSet<u32>>, cached: &CachedMemory) {
+        let epoch = cached.entry.epoch();
+        let tokens = std::iter::once(cached.entry.token())
+            .chain(cached.related_tokens.iter().copied());
+
+        for token in tokens {
+            if let Some(epochs) = token_index.get_mut(&token) {
+                epochs.remove(&epoch);
+                if epochs.is_empty() {
+                    token_index.remove(&token);
                 }
             }
         }
     }
 
     /// Returns cache statistics
+    ///
+    /// Averages are reported as zero when the cache is empty.
     pub fn stats(&self) -> CacheStats {
         let entries = self.entries.read();
-        
+        // Sums over an empty map are zero, so this keeps the averages finite
+        let count = entries.len().max(1) as f32;
+
         CacheStats {
             total_entries: entries.len(),
             avg_weight: entries.values()
-                .map(|(_, score)| score.weight as f32)
-                .sum::<f32>() / entries.len() as f32,
+                .map(|cached| cached.score.weight as f32)
+                .sum::<f32>() / count,
             avg_link_strength: entries.values()
-                .map(|(_, score)| score.link_strength)
-                .sum::<f32>() / entries.len() as f32,
-            cache_hit_rate: 0.0, // TODO: Implement hit rate tracking
+                .map(|cached| cached.score.link_strength)
+                .sum::<f32>() / count,
+            cache_hit_rate: self.hit_rate(),
+            weight_histogram: WeightHistogram::from_weights(
+                &self.weight_histogram_edges,
+                entries.values().map(|cached| cached.score.weight),
+            ),
         }
     }
+
+    /// Fraction of `get_memory` lookups that found their entry
+    fn hit_rate(&self) -> f32 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let lookups = hits + self.misses.load(Ordering::Relaxed);
+        if lookups == 0 {
+            0.0
+        } else {
+            hits as f32 / lookups as f32
+        }
+    }
+
+    /// Zeroes the hit and miss counters
+    pub fn reset_stats(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -164,6 +1039,7 @@ pub struct CacheStats {
     pub avg_weight: f32,
     pub avg_link_strength: f32,
     pub cache_hit_rate: f32,
+    pub weight_histogram: WeightHistogram,
 }
 
 #[cfg(test)]
@@ -174,10 +1050,10 @@ mod tests {
 
     #[test]
     fn test_cache_add_and_retrieve() {
-        let cache = PersonalityCache::new(3, 0.5);
+        let cache = PersonalityCache::new(3, 0.0);
 
-        let entry1 = MemoryEntry::new(100, 500);
-        let entry2 = MemoryEntry::new(101, 600);
+        let entry1 = MemoryEntry::with_links(1, 100, 500, 0, 0);
+        let entry2 = MemoryEntry::with_links(2, 101, 600, 0, 0);
 
         let related1: HashSet<u16> = [200, 201].into_iter().collect();
         let related2: HashSet<u16> = [202].into_iter().collect();
@@ -194,8 +1070,8 @@ mod tests {
         assert!(related_memories.iter().any(|e| e.epoch() == entry1.epoch()));
 
         // Test eviction policy
-        let entry3 = MemoryEntry::new(102, 700);
-        let entry4 = MemoryEntry::new(103, 800);
+        let entry3 = MemoryEntry::with_links(3, 102, 700, 0, 0);
+        let entry4 = MemoryEntry::with_links(4, 103, 800, 0, 0);
 
         cache.update_memory(entry3.clone(), HashSet::new());
         cache.update_memory(entry4.clone(), HashSet::new());
@@ -206,12 +1082,15 @@ mod tests {
     // Add more comprehensive tests for personality aspects
     #[test]
     fn test_personality_weighted_eviction() {
-        let cache = PersonalityCache::new(3, 0.5);
+        let cache = PersonalityCache::new(3, 0.0).with_score_weights(
+            ScoreWeights { weight: 1.0, ..ScoreWeights::default() },
+            Duration::from_secs(3600 * 24),
+        );
 
         // Create entries with different weights
-        let mut entry1 = MemoryEntry::new(100, 900); // High weight
-        let mut entry2 = MemoryEntry::new(101, 300); // Low weight
-        let mut entry3 = MemoryEntry::new(102, 600); // Medium weight
+        let mut entry1 = MemoryEntry::with_links(1, 100, 900, 0, 0); // High weight
+        let mut entry2 = MemoryEntry::with_links(2, 101, 300, 0, 0); // Low weight
+        let mut entry3 = MemoryEntry::with_links(3, 102, 600, 0, 0); // Medium weight
 
         // Create links between entries
         entry1.update_links(entry2.epoch(), entry3.epoch());
@@ -226,7 +1105,7 @@ mod tests {
         cache.update_memory(entry3.clone(), related.clone());
 
         // Add a new entry to trigger eviction
-        let entry4 = MemoryEntry::new(104, 950);
+        let entry4 = MemoryEntry::with_links(5, 104, 950, 0, 0);
         cache.update_memory(entry4.clone(), HashSet::new());
 
         // The lowest weight entry should be evicted
@@ -236,7 +1115,7 @@ mod tests {
 
     #[test]
     fn test_access_patterns() {
-        let cache = PersonalityCache::new(3, 0.5);
+        let cache = PersonalityCache::new(3, 0.0);
         let entry = MemoryEntry::new(100, 500);
         let related: HashSet<u16> = [200, 201].into_iter().collect();
 
@@ -256,12 +1135,12 @@ mod tests {
     // Your existing personality scoring test remains...
     #[test]
     fn test_personality_scoring() {
-        let cache = PersonalityCache::new(10, 0.5);
+        let cache = PersonalityCache::new(10, 0.0);
         
         // Create a network of related memories
-        let mut entry1 = MemoryEntry::new(100, 900);
-        let mut entry2 = MemoryEntry::new(101, 800);
-        let entry3 = MemoryEntry::new(102, 700);
+        let mut entry1 = MemoryEntry::with_links(1, 100, 900, 0, 0);
+        let mut entry2 = MemoryEntry::with_links(2, 101, 800, 0, 0);
+        let entry3 = MemoryEntry::with_links(3, 102, 700, 0, 0);
         
         // Link memories
         entry1.update_links(entry2.epoch(), entry3.epoch());
@@ -285,12 +1164,15 @@ mod tests {
     // Your existing cache eviction test remains...
     #[test]
     fn test_cache_eviction() {
-        let cache = PersonalityCache::new(2, 0.5);
+        let cache = PersonalityCache::new(2, 0.0).with_score_weights(
+            ScoreWeights { weight: 1.0, ..ScoreWeights::default() },
+            Duration::from_secs(3600 * 24),
+        );
         
         // Add three entries to trigger eviction
-        let entry1 = MemoryEntry::new(100, 900);
-        let entry2 = MemoryEntry::new(101, 800);
-        let entry3 = MemoryEntry::new(102, 950);
+        let entry1 = MemoryEntry::with_links(1, 100, 900, 0, 0);
+        let entry2 = MemoryEntry::with_links(2, 101, 800, 0, 0);
+        let entry3 = MemoryEntry::with_links(3, 102, 950, 0, 0);
         
         let related: HashSet<u16> = vec![100, 101, 102].into_iter().collect();
         
@@ -303,4 +1185,625 @@ mod tests {
         assert!(cache.get_memory(entry1.epoch()).is_some());
         assert!(cache.get_memory(entry3.epoch()).is_some());
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_linked_updates_do_not_deadlock() {
+        let cache = std::sync::Arc::new(PersonalityCache::new(10, 0.0));
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let worker_cache = cache.clone();
+        std::thread::spawn(move || {
+            let entry1 = MemoryEntry::with_links(1, 100, 900, 0, 0);
+            let entry2 = MemoryEntry::with_links(2, 101, 800, 1, 0);
+
+            worker_cache.update_memory(entry1, HashSet::new());
+            worker_cache.update_memory(entry2, HashSet::new());
+            tx.send(()).unwrap();
+        });
+
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("update_memory deadlocked on linked entries");
+        assert!(cache.get_memory(2).is_some());
+    }
+
+    #[test]
+    fn test_token_lookups_do_not_deadlock_with_writers() {
+        let cache = std::sync::Arc::new(PersonalityCache::new(10, 0.0));
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let writer_cache = cache.clone();
+        let writer_tx = tx.clone();
+        std::thread::spawn(move || {
+            for i in 0..20_000u32 {
+                let related: HashSet<u16> = [200].into_iter().collect();
+                writer_cache.update_memory(MemoryEntry::with_links(i % 20, 100, 500, 0, 0), related);
+                writer_cache.remove_memory((i + 10) % 20);
+            }
+            writer_tx.send(()).unwrap();
+        });
+
+        let reader_cache = cache.clone();
+        std::thread::spawn(move || {
+            for _ in 0..20_000 {
+                reader_cache.find_related_memories(100, 5);
+                reader_cache.find_related_in_window(200, 0, 20, 5);
+            }
+            tx.send(()).unwrap();
+        });
+
+        for _ in 0..2 {
+            rx.recv_timeout(Duration::from_secs(10))
+                .expect("token lookups deadlocked against a writer");
+        }
+    }
+
+    #[test]
+    fn test_remove_memory_purges_related_tokens() {
+        let cache = PersonalityCache::new(10, 0.0);
+        let entry = MemoryEntry::with_links(1, 100, 500, 0, 0);
+        let related: HashSet<u16> = [200, 201, 202].into_iter().collect();
+
+        cache.update_memory(entry.clone(), related);
+
+        let removed = cache.remove_memory(entry.epoch()).unwrap();
+        assert_eq!(removed.token(), entry.token());
+        assert!(cache.get_memory(entry.epoch()).is_none());
+        assert!(cache.remove_memory(entry.epoch()).is_none());
+
+        for token in [200, 201, 202] {
+            assert!(cache.find_related_memories(token, 10).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_add_memory_keeps_related_tokens() {
+        // add_memory bypasses the personality threshold
+        let cache = MemoryCache::new(2, 0.5);
+
+        let entry1 = MemoryEntry::with_links(1, 100, 500, 0, 0);
+        let entry2 = MemoryEntry::with_links(2, 101, 600, 0, 0);
+        let entry3 = MemoryEntry::with_links(3, 102, 700, 0, 0);
+        let related1: HashSet<u16> = [200, 201].into_iter().collect();
+        let related2: HashSet<u16> = [202].into_iter().collect();
+
+        cache.add_memory(entry1.clone(), related1.clone());
+        cache.add_memory(entry2.clone(), related2.clone());
+        assert_eq!(cache.get_related_tokens(entry1.epoch()), Some(related1));
+        assert_eq!(cache.get_related_tokens(entry2.epoch()), Some(related2.clone()));
+
+        // Evicting entry1 drops its related tokens from the index
+        cache.add_memory(entry3.clone(), HashSet::new());
+        assert!(cache.get_related_tokens(entry1.epoch()).is_none());
+        assert!(cache.find_related_memories(200, 10).is_empty());
+        assert_eq!(cache.get_related_tokens(entry2.epoch()), Some(related2));
+        assert_eq!(cache.find_related_memories(202, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_stats_on_empty_cache() {
+        let stats = PersonalityCache::new(10, 0.5).stats();
+        assert_eq!(stats.total_entries, 0);
+        assert_eq!(stats.avg_weight, 0.0);
+        assert_eq!(stats.avg_link_strength, 0.0);
+        assert!(stats.avg_weight.is_finite() && stats.avg_link_strength.is_finite());
+    }
+
+    #[test]
+    fn test_negative_weight_evicted_first() {
+        let cache = PersonalityCache::new(2, 0.0);
+
+        let mut negative = MemoryEntry::with_links(1, 100, 200, 0, 0);
+        negative.adjust_weight(-700);
+        assert_eq!(negative.weight(), -500);
+
+        let low = MemoryEntry::with_links(2, 101, 10, 0, 0);
+        cache.update_memory(negative.clone(), HashSet::new());
+        cache.update_memory(low.clone(), HashSet::new());
+
+        // A third entry forces an eviction; the negative one goes first
+        cache.update_memory(MemoryEntry::with_links(3, 102, 300, 0, 0), HashSet::new());
+        assert!(cache.get_memory(negative.epoch()).is_none());
+        assert!(cache.get_memory(low.epoch()).is_some());
+
+        // Unlinked memories tie on relevance, so magnitude decides whichever
+        // was inserted first
+        for weights in [[-500, -30_000], [-30_000, -500]] {
+            let cache = PersonalityCache::new(2, 0.0);
+            for (epoch, weight) in (1..).zip(weights) {
+                cache.update_memory(MemoryEntry::with_links(epoch, 100, weight, 0, 0), HashSet::new());
+            }
+            cache.update_memory(MemoryEntry::with_links(3, 102, 300, 0, 0), HashSet::new());
+
+            let kept: Vec<i16> = [1, 2].into_iter()
+                .filter_map(|epoch| cache.get_memory(epoch))
+                .map(|entry| entry.weight())
+                .collect();
+            assert_eq!(kept, vec![-500], "inserted as {weights:?}");
+        }
+    }
+
+    #[test]
+    fn test_hottest_ranks_most_accessed_first() {
+        let cache = std::sync::Arc::new(PersonalityCache::new(10, 0.0));
+        for epoch in 1..=3 {
+            cache.add_memory(MemoryEntry::with_links(epoch, 100, 500, 0, 0), HashSet::new());
+        }
+
+        // Hammer epoch 2 from several threads; the write lock keeps counts exact
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        cache.get_memory(2);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        cache.get_memory(3);
+
+        assert_eq!(cache.access_count(2), Some(100));
+        assert_eq!(cache.access_count(1), Some(0));
+        assert_eq!(cache.access_count(99), None);
+        assert_eq!(cache.hottest(2), vec![(2, 100), (3, 1)]);
+        assert_eq!(cache.hottest(10).len(), 3);
+    }
+
+    #[test]
+    fn test_cache_hit_rate() {
+        let cache = PersonalityCache::new(10, 0.0);
+        assert_eq!(cache.stats().cache_hit_rate, 0.0);
+
+        cache.add_memory(MemoryEntry::with_links(1, 100, 500, 0, 0), HashSet::new());
+        for _ in 0..3 {
+            cache.get_memory(1);
+        }
+        cache.get_memory(2);
+
+        assert_eq!(cache.stats().cache_hit_rate, 0.75);
+
+        cache.reset_stats();
+        assert_eq!(cache.stats().cache_hit_rate, 0.0);
+        cache.get_memory(2);
+        assert_eq!(cache.stats().cache_hit_rate, 0.0);
+        cache.get_memory(1);
+        assert_eq!(cache.stats().cache_hit_rate, 0.5);
+    }
+
+    #[test]
+    fn test_find_related_memories_ranked() {
+        let cache = PersonalityCache::new(10, 0.0);
+        let shared: HashSet<u16> = [500].into_iter().collect();
+
+        cache.add_memory(MemoryEntry::with_links(9, 999, 1000, 0, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(1, 100, 300, 9, 0), shared.clone());
+        cache.add_memory(MemoryEntry::with_links(2, 101, 900, 9, 0), shared.clone());
+        cache.add_memory(MemoryEntry::with_links(3, 102, 600, 9, 0), shared.clone());
+        // Heavier than epoch 1 but unlinked, so it scores nothing
+        cache.add_memory(MemoryEntry::with_links(4, 103, 800, 0, 0), shared.clone());
+
+        let epochs: Vec<u32> = cache.find_related_memories(500, 10)
+            .iter()
+            .map(MemoryEntry::epoch)
+            .collect();
+        assert_eq!(epochs, vec![2, 3, 1, 4]);
+
+        let top: Vec<u32> = cache.find_related_memories(500, 2)
+            .iter()
+            .map(MemoryEntry::epoch)
+            .collect();
+        assert_eq!(top, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_traverse_cyclic_graph() {
+        let cache = PersonalityCache::new(10, 0.0);
+
+        // 1 -> 2 -> 3 -> 1 forms a cycle; 2 also points at 4, and 4 at a
+        // memory that was never cached
+        cache.add_memory(MemoryEntry::with_links(1, 100, 500, 2, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(2, 101, 500, 3, 4), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(3, 102, 500, 1, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(4, 103, 500, 99, 0), HashSet::new());
+
+        let epochs = |depth| -> Vec<u32> {
+            cache.traverse(1, depth).iter().map(MemoryEntry::epoch).collect()
+        };
+
+        assert_eq!(epochs(0), vec![1]);
+        assert_eq!(epochs(1), vec![1, 2]);
+        assert_eq!(epochs(10), vec![1, 2, 3, 4]);
+        assert!(cache.traverse(42, 10).is_empty());
+    }
+
+    #[test]
+    fn test_eviction_policies() {
+        let evicted_under = |policy| {
+            // Every memory links to one outside the cache at full strength,
+            // so weighted-link eviction ranks by weight alone
+            let cache = PersonalityCache::with_policy(3, 0.0, policy)
+                .with_link_resolver(|_| Some(i16::MAX));
+
+            // Heavy and popular, but touched longest ago
+            cache.add_memory(MemoryEntry::with_links(1, 100, 900, 99, 0), HashSet::new());
+            for _ in 0..3 {
+                cache.get_memory(1);
+            }
+            sleep(Duration::from_millis(5));
+
+            // Never read
+            cache.add_memory(MemoryEntry::with_links(2, 101, 500, 99, 0), HashSet::new());
+            sleep(Duration::from_millis(5));
+
+            // Lightest, but read most recently
+            cache.add_memory(MemoryEntry::with_links(3, 102, 100, 99, 0), HashSet::new());
+            cache.get_memory(3);
+            cache.get_memory(3);
+
+            cache.add_memory(MemoryEntry::with_links(4, 103, 1000, 99, 0), HashSet::new());
+            let evicted: Vec<u32> = (1..=3)
+                .filter(|&epoch| cache.access_count(epoch).is_none())
+                .collect();
+            evicted
+        };
+
+        assert_eq!(evicted_under(EvictionPolicy::WeightedLink), vec![3]);
+        assert_eq!(evicted_under(EvictionPolicy::LeastRecentlyUsed), vec![1]);
+        assert_eq!(evicted_under(EvictionPolicy::LeastFrequentlyUsed), vec![2]);
+    }
+
+    #[test]
+    fn test_stale_memory_loses_to_fresh_one() {
+        let weights = ScoreWeights { recency: 1.0, ..ScoreWeights::default() };
+        let cache = PersonalityCache::new(2, 0.0)
+            .with_score_weights(weights, Duration::from_millis(10));
+
+        cache.add_memory(MemoryEntry::with_links(1, 100, 900, 0, 0), HashSet::new());
+        sleep(Duration::from_millis(60));
+        cache.add_memory(MemoryEntry::with_links(2, 101, 600, 0, 0), HashSet::new());
+
+        cache.add_memory(MemoryEntry::with_links(3, 102, 700, 0, 0), HashSet::new());
+        assert!(cache.access_count(1).is_none(), "stale heavy memory should be evicted");
+        assert!(cache.access_count(2).is_some());
+    }
+
+    #[test]
+    fn test_replay_from_op_log() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log_path = dir.path().join("cache.log");
+
+        let cache = PersonalityCache::new(3, 0.0).with_op_log(&log_path)?;
+        cache.add_memory(MemoryEntry::with_links(1, 100, 500, 0, 0), HashSet::new());
+        cache.update_memory(MemoryEntry::with_links(2, 101, 700, 1, 0), [200].into_iter().collect());
+        cache.update_memory(MemoryEntry::with_links(3, 102, 900, 0, 0), HashSet::new());
+        cache.update_memory(MemoryEntry::with_links(4, 103, 300, 0, 0), HashSet::new());
+        cache.remove_memory(3);
+        for _ in 0..3 {
+            cache.get_memory(2);
+        }
+        cache.get_memory(1);
+        cache.get_memory(99);
+
+        let replayed = PersonalityCache::replay_from(&log_path, 3, 0.0)?;
+        let (before, after) = (cache.stats(), replayed.stats());
+        assert_eq!(after.total_entries, before.total_entries);
+        assert_eq!(after.avg_weight, before.avg_weight);
+        assert_eq!(after.avg_link_strength, before.avg_link_strength);
+        assert_eq!(after.cache_hit_rate, before.cache_hit_rate);
+        assert_eq!(replayed.hottest(2), cache.hottest(2));
+        assert_eq!(replayed.get_related_tokens(2), cache.get_related_tokens(2));
+
+        // A torn final record is ignored
+        let mut file = OpenOptions::new().append(true).open(&log_path)?;
+        file.write_all(&[2, 0])?;
+        assert_eq!(PersonalityCache::replay_from(&log_path, 3, 0.0)?.stats().total_entries, before.total_entries);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backing_store_fills_misses() {
+        let loads = std::sync::Arc::new(AtomicU64::new(0));
+        let counter = std::sync::Arc::clone(&loads);
+        let cache = PersonalityCache::new(3, 0.5).with_backing_store(move |epoch| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            (epoch < 100).then(|| MemoryEntry::with_links(epoch, 300, 700, 0, 0))
+        });
+
+        // A miss loads from the backing store and caches the result
+        assert_eq!(cache.get_memory(7).map(|e| e.token()), Some(300));
+        assert_eq!(cache.access_count(7), Some(1));
+        assert_eq!(cache.find_related_memories(300, 10).len(), 1);
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+
+        // Now it is a hit and the loader is not consulted again
+        assert!(cache.get_memory(7).is_some());
+        assert_eq!(cache.access_count(7), Some(2));
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+
+        assert!(cache.get_memory(500).is_none());
+        assert_eq!(cache.access_count(500), None);
+        assert_eq!(cache.stats().cache_hit_rate, 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_link_resolver_weighs_uncached_links() {
+        let cache = PersonalityCache::new(10, 0.0)
+            .with_link_resolver(|epoch| (epoch == 99).then_some(i16::MAX));
+        let link_strength = |epoch| cache.entries.read()[&epoch].score.link_strength;
+
+        cache.add_memory(MemoryEntry::with_links(1, 100, i16::MAX / 2, 0, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(2, 101, 500, 1, 99), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(3, 102, 500, 1, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(4, 103, 500, 1, 77), HashSet::new());
+
+        let half = (i16::MAX / 2) as f32 / i16::MAX as f32;
+        assert_eq!(link_strength(1), 0.0);
+        assert_eq!(link_strength(2), (half + 1.0) / 2.0);
+        // A lone link is not diluted by the empty slot
+        assert_eq!(link_strength(3), half);
+        // An unresolvable link still counts, at zero
+        assert_eq!(link_strength(4), half / 2.0);
+    }
+
+    /// The entry `cache` should evict next, found by scoring every entry
+    fn full_scan_victim(cache: &PersonalityCache) -> u32 {
+        let entries = cache.entries.read();
+        let now = SystemTime::now();
+        let (&epoch, _) = entries.iter()
+            .min_by(|&(ea, a), &(eb, b)| match cache.eviction_policy {
+                EvictionPolicy::WeightedLink => {
+                    cache.relevance(&a.score, now).total_cmp(&cache.relevance(&b.score, now))
+                }
+                EvictionPolicy::LeastRecentlyUsed => a.score.last_access.cmp(&b.score.last_access),
+                EvictionPolicy::LeastFrequentlyUsed => a.score.access_count.cmp(&b.score.access_count),
+            }.then(ea.cmp(eb)))
+            .unwrap();
+        epoch
+    }
+
+    #[test]
+    fn test_indexed_eviction_matches_full_scan() {
+        for policy in [
+            EvictionPolicy::WeightedLink,
+            EvictionPolicy::LeastRecentlyUsed,
+            EvictionPolicy::LeastFrequentlyUsed,
+        ] {
+            let weights = ScoreWeights { weight: 1.0, link_strength: 1.0, recency: 0.5 };
+            let cache = PersonalityCache::with_policy(50, 0.0, policy)
+                .with_score_weights(weights, Duration::from_secs(1));
+
+            for epoch in 1..=50u32 {
+                let weight = (epoch * 7919 % 2000) as i16 - 500;
+                cache.add_memory(MemoryEntry::with_links(epoch, 100, weight, epoch - 1, 0), HashSet::new());
+            }
+            for epoch in (1..=50u32).filter(|epoch| epoch % 3 == 0) {
+                for _ in 0..epoch % 7 {
+                    cache.get_memory(epoch);
+                }
+            }
+
+            for epoch in 51..=80u32 {
+                let victim = full_scan_victim(&cache);
+                cache.add_memory(MemoryEntry::with_links(epoch, 100, 300, 0, 0), HashSet::new());
+                assert!(cache.access_count(victim).is_none(), "{policy:?} kept {victim}");
+                assert_eq!(cache.entries.read().len(), 50);
+                if epoch % 4 == 0 {
+                    cache.get_memory(epoch - 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_eviction_scores_only_a_few_entries() {
+        let cache = PersonalityCache::new(1000, 0.0);
+        for epoch in 1..=1000u32 {
+            cache.add_memory(MemoryEntry::with_links(epoch, 100, (epoch * 30) as i16, 0, 0), HashSet::new());
+        }
+
+        cache.add_memory(MemoryEntry::with_links(1001, 100, 30_000, 0, 0), HashSet::new());
+        assert!(cache.access_count(1).is_none());
+        let scanned = cache.last_eviction_scan.load(Ordering::Relaxed);
+        assert!(scanned < 10, "scored {scanned} of 1000 entries");
+    }
+
+    #[test]
+    fn test_concepts_share_codec() {
+        let codec: Arc<dyn TokenCodec> = Arc::new(crate::memory::token_codec::DictionaryCodec::new());
+        let cache = PersonalityCache::new(10, 0.0).with_codec(codec.clone());
+
+        cache.add_concept(1, "coffee", 900, &["morning"]);
+        cache.add_concept(2, "rain", 500, &["morning"]);
+
+        // Neither is linked, so they tie and the more recent comes first
+        let found: Vec<u32> = cache.find_by_concept("morning", 10).iter().map(MemoryEntry::epoch).collect();
+        assert_eq!(found, vec![2, 1]);
+        assert_eq!(cache.concept(2).as_deref(), Some("rain"));
+        assert_eq!(cache.get_memory(1).unwrap().token(), codec.encode("coffee"));
+        assert_eq!(cache.concept(3), None);
+    }
+
+    #[test]
+    fn test_stats_weight_histogram() {
+        let cache = PersonalityCache::new(10, 0.0).with_weight_histogram_edges(vec![-100, 100]);
+        for (epoch, weight) in [(1, -500), (2, -100), (3, 0), (4, 99), (5, 100)] {
+            cache.add_memory(MemoryEntry::with_links(epoch, 100, weight, 0, 0), HashSet::new());
+        }
+
+        assert_eq!(cache.stats().weight_histogram.counts, vec![1, 3, 1]);
+    }
+
+    #[test]
+    fn test_max_related_tokens_bounds_index() {
+        let cache = PersonalityCache::new(100, 0.0).with_max_related_tokens(3);
+
+        let related: HashSet<u16> = (1000..1500).collect();
+        cache.add_memory(MemoryEntry::with_links(1, 1, 500, 0, 0), related.clone());
+        cache.update_memory(MemoryEntry::with_links(2, 2, 500, 0, 0), related);
+        cache.add_memory_ranked(MemoryEntry::with_links(3, 3, 500, 0, 0), &[900, 900, 800, 700, 600]);
+
+        // Own tokens plus 1000..1003 shared by the first two, and 900/800/700
+        assert_eq!(cache.token_index.read().len(), 9);
+        let kept: HashSet<u16> = (1000..1003).collect();
+        assert_eq!(cache.get_related_tokens(1), Some(kept.clone()));
+        assert_eq!(cache.get_related_tokens(2), Some(kept));
+        assert_eq!(cache.get_related_tokens(3), Some([900, 800, 700].into_iter().collect()));
+
+        assert_eq!(cache.find_related_memories(1002, 10).len(), 2);
+        assert!(cache.find_related_memories(1003, 10).is_empty());
+        assert_eq!(cache.find_related_memories(700, 10)[0].epoch(), 3);
+        assert!(cache.find_related_memories(600, 10).is_empty());
+    }
+
+    #[test]
+    fn test_eviction_hook_sees_evicted_entries() {
+        let cache = Arc::new(PersonalityCache::new(2, 0.0));
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = Arc::clone(&evicted);
+        let weak = Arc::downgrade(&cache);
+        cache.set_eviction_hook(Box::new(move |entry| {
+            // The cache is unlocked and the entry already gone
+            let cache = weak.upgrade().unwrap();
+            assert_eq!(cache.access_count(entry.epoch()), None);
+            seen.lock().push(entry.epoch());
+        }));
+
+        cache.add_memory(MemoryEntry::with_links(1, 10, 100, 0, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(2, 20, 900, 0, 0), HashSet::new());
+        // Replacing a cached epoch evicts nothing
+        cache.add_memory(MemoryEntry::with_links(2, 20, 950, 0, 0), HashSet::new());
+        assert!(evicted.lock().is_empty());
+
+        cache.update_memory(MemoryEntry::with_links(3, 30, 800, 0, 0), HashSet::new());
+        assert_eq!(*evicted.lock(), vec![1]);
+    }
+
+    #[test]
+    fn test_find_related_in_window() {
+        let cache = PersonalityCache::new(100, 0.0);
+        for epoch in [100, 200, 300, 400, 500] {
+            cache.add_memory(MemoryEntry::with_links(epoch, 7, 500, 0, 0), HashSet::new());
+        }
+        cache.add_memory(MemoryEntry::with_links(250, 8, 900, 0, 0), [7].into_iter().collect());
+        cache.add_memory(MemoryEntry::with_links(350, 9, 900, 0, 0), HashSet::new());
+
+        let epochs = |found: Vec<MemoryEntry>| found.iter().map(|e| e.epoch()).collect::<Vec<_>>();
+        assert_eq!(epochs(cache.find_related_in_window(7, 200, 400, 10)), vec![400, 300, 250, 200]);
+        assert_eq!(epochs(cache.find_related_in_window(7, 200, 400, 2)), vec![400, 300]);
+        assert_eq!(epochs(cache.find_related_in_window(7, 501, 600, 10)), Vec::<u32>::new());
+        assert_eq!(epochs(cache.find_related_in_window(7, 400, 200, 10)), Vec::<u32>::new());
+        assert!(cache.find_related_in_window(10, 0, u32::MAX, 10).is_empty());
+    }
+
+    #[test]
+    fn test_eviction_batch_makes_headroom() {
+        let cache = PersonalityCache::new(10, 0.0).with_eviction_batch(4);
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&evicted);
+        cache.set_eviction_hook(Box::new(move |entry| seen.lock().push(entry.epoch())));
+
+        for epoch in 1..=10 {
+            cache.add_memory(MemoryEntry::with_links(epoch, 1, 100 * epoch as i16, 0, 0), HashSet::new());
+        }
+        assert!(evicted.lock().is_empty());
+
+        // The first insert past capacity evicts a whole batch, lightest first
+        cache.add_memory(MemoryEntry::with_links(11, 1, 5000, 0, 0), HashSet::new());
+        assert_eq!(*evicted.lock(), vec![1, 2, 3, 4]);
+        assert_eq!(cache.stats().total_entries, 7);
+
+        // The next three fit in the headroom
+        for epoch in 12..=14 {
+            cache.add_memory(MemoryEntry::with_links(epoch, 1, 5000, 0, 0), HashSet::new());
+        }
+        assert_eq!(evicted.lock().len(), 4);
+        cache.add_memory(MemoryEntry::with_links(15, 1, 5000, 0, 0), HashSet::new());
+        assert_eq!(*evicted.lock(), (1..=8).collect::<Vec<u32>>());
+        assert_eq!(cache.stats().total_entries, 7);
+    }
+
+    #[test]
+    fn test_packed_entries_keep_links_in_side_table() {
+        let cache = PersonalityCache::new(10, 0.0);
+        cache.add_memory(MemoryEntry::with_links(1, 10, 500, 0, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(2, 20, 600, 1, 0).with_expiry(99), HashSet::new());
+
+        // Only the linked memory needs a side-table row
+        assert_eq!(cache.links.read().len(), 1);
+        assert!(std::mem::size_of::<PackedEntry>() < std::mem::size_of::<MemoryEntry>());
+
+        let linked = cache.get_memory(2).unwrap();
+        assert_eq!(linked.links(), (1, 0));
+        assert_eq!(linked.expires_at(), Some(99));
+        assert_eq!(cache.traverse(2, 1).len(), 2);
+
+        let removed = cache.remove_memory(2).unwrap();
+        assert_eq!((removed.weight(), removed.links()), (600, (1, 0)));
+        assert!(cache.links.read().is_empty());
+    }
+
+    #[test]
+    fn test_pinned_memory_never_evicted() {
+        let cache = PersonalityCache::new(2, 0.0);
+        cache.add_memory(MemoryEntry::with_links(1, 10, -500, 0, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(2, 20, 5000, 0, 0), HashSet::new());
+        assert!(cache.pin(1));
+        assert!(!cache.pin(99));
+
+        for epoch in 3..10 {
+            cache.add_memory(MemoryEntry::with_links(epoch, 30, 5000, 0, 0), HashSet::new());
+        }
+        let anchor = cache.get_memory(1).unwrap();
+        assert!(anchor.is_pinned());
+        assert_eq!(cache.stats().total_entries, 2);
+
+        // Unpinned, the lightest memory is the next to go
+        assert!(cache.unpin(1));
+        cache.add_memory(MemoryEntry::with_links(10, 30, 5000, 0, 0), HashSet::new());
+        assert!(cache.get_memory(1).is_none());
+    }
+
+    #[test]
+    fn test_access_boost_strengthens_recalled_memories() {
+        let cache = PersonalityCache::new(10, 0.0).with_access_boost(100);
+        cache.add_memory(MemoryEntry::with_links(1, 10, 1000, 0, 0), HashSet::new());
+        cache.add_memory(MemoryEntry::with_links(2, 20, 1000, 0, 0), HashSet::new());
+
+        let weights: Vec<i16> = (0..5).map(|_| cache.get_memory(1).unwrap().weight()).collect();
+        assert_eq!(weights, vec![1100, 1200, 1300, 1400, 1500]);
+        assert_eq!(cache.stats().avg_weight, 1250.0);
+        assert_eq!(cache.find_related_memories(20, 1)[0].weight(), 1000);
+
+        let unboosted = PersonalityCache::new(10, 0.0);
+        unboosted.add_memory(MemoryEntry::with_links(3, 30, i16::MAX - 1, 0, 0), HashSet::new());
+        unboosted.get_memory(3);
+        assert_eq!(unboosted.get_memory(3).unwrap().weight(), i16::MAX - 1);
+
+        let saturating = PersonalityCache::new(10, 0.0).with_access_boost(i16::MAX);
+        saturating.add_memory(MemoryEntry::with_links(3, 30, 1000, 0, 0), HashSet::new());
+        saturating.get_memory(3);
+        assert_eq!(saturating.get_memory(3).unwrap().weight(), i16::MAX);
+    }
+
+    #[test]
+    fn test_with_memory_lends_a_view() {
+        let cache = PersonalityCache::new(10, 0.0);
+        cache.add_memory(MemoryEntry::with_links(1, 100, 700, 5, 0).with_expiry(90), HashSet::new());
+
+        assert_eq!(cache.with_memory(1, |memory| memory.weight()), Some(700));
+        assert_eq!(cache.with_memory(1, |memory| (memory.links(), memory.expires_at())), Some(((5, 0), Some(90))));
+        assert_eq!(cache.access_count(1), Some(2));
+        assert_eq!(cache.with_memory(2, |memory| memory.weight()), None);
+
+        // No lock is held while the closure runs
+        let nested = cache.with_memory(1, |memory| cache.get_memory(memory.epoch()).map(|entry| entry.token()));
+        assert_eq!(nested, Some(Some(100)));
+
+        assert_eq!(cache.stats().cache_hit_rate, 0.8);
+    }
+}
@@ -0,0 +1,700 @@
+//! Moves memories through Stage 1, Stage 2 and Stage 3.
+
+use super::archive::{read_archive, write_archive};
+use super::checksum::ChecksumAlgorithm;
+use super::compression::CompressionAlgorithm;
+use super::config::MemConfig;
+use super::entry::MemoryEntry;
+use super::error::MemError;
+use super::metrics::MemMetrics;
+use super::personality_cache::PersonalityCache;
+use super::stage1::Stage1;
+use super::stage2::{Stage2, Stage2Error};
+use super::stage3::{Stage3, Stage3Error};
+use crate::storage::StorageManager;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+const SECONDS_PER_DAY: u32 = 3600 * 24;
+
+/// Format version written into checkpoint manifests
+pub const CHECKPOINT_VERSION: u16 = 2;
+
+/// Written last, so a checkpoint without one never finished
+const MANIFEST_FILE_NAME: &str = "manifest.bin";
+const STAGE1_FILE_NAME: &str = "stage1.bin";
+const STAGE2_FILE_NAME: &str = "stage2.mem8";
+const STAGE3_FILE_NAME: &str = "stage3.mem8";
+
+#[derive(Error, Debug)]
+pub enum PipelineError {
+    #[error("Stage 2 error: {0}")]
+    Stage2(#[from] Stage2Error),
+    #[error("Stage 3 error: {0}")]
+    Stage3(#[from] Stage3Error),
+}
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
+    #[error("Unsupported checkpoint version {found}, expected {CHECKPOINT_VERSION}")]
+    UnsupportedVersion { found: u16 },
+    #[error("Checkpoint manifest does not list {0}")]
+    MissingFile(&'static str),
+    #[error("Checkpoint file {0} does not match its manifest entry")]
+    Corrupt(String),
+}
+
+/// Lists each file of a checkpoint with its length and BLAKE3 checksum
+#[derive(Serialize, Deserialize)]
+struct CheckpointManifest {
+    version: u16,
+    files: Vec<(String, u64, u64)>,
+}
+
+/// Counts of memories moved across each stage boundary during a tick
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TickSummary {
+    pub stage1_to_stage2: usize,
+    pub stage2_to_stage3: usize,
+}
+
+/// Owns all three stages and promotes memories between them
+pub struct MemoryPipeline {
+    stage1: Stage1,
+    stage2: Stage2,
+    stage3: Stage3,
+    cache: Option<PersonalityCache>,
+}
+
+impl MemoryPipeline {
+    pub fn new(stage1: Stage1, stage2: Stage2, stage3: Stage3) -> Self {
+        Self {
+            stage1,
+            stage2,
+            stage3,
+            cache: None,
+        }
+    }
+
+    /// Builds every stage, and the cache if one is configured, from a
+    /// validated `config`
+    pub fn from_config(config: MemConfig) -> Result<Self, MemError> {
+        config.validate()?;
+        let stage2 = Stage2::new(config.stage2).map_err(Stage2Error::from)?;
+        let stage3 = Stage3::new(config.stage3).map_err(Stage3Error::from)?;
+        let pipeline = Self::new(Stage1::with_config(config.stage1), stage2, stage3);
+        Ok(match config.cache {
+            Some(cache) => pipeline.with_cache(cache.build()),
+            None => pipeline,
+        })
+    }
+
+    /// Attaches a personality cache whose statistics are reported alongside
+    /// the stages
+    pub fn with_cache(mut self, cache: PersonalityCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn stage1(&self) -> &Stage1 { &self.stage1 }
+    pub fn stage1_mut(&mut self) -> &mut Stage1 { &mut self.stage1 }
+    pub fn stage2(&self) -> &Stage2 { &self.stage2 }
+    pub fn stage2_mut(&mut self) -> &mut Stage2 { &mut self.stage2 }
+    pub fn stage3(&self) -> &Stage3 { &self.stage3 }
+    pub fn stage3_mut(&mut self) -> &mut Stage3 { &mut self.stage3 }
+    pub fn cache(&self) -> Option<&PersonalityCache> { self.cache.as_ref() }
+
+    /// Collects a snapshot of counters across every stage and the cache
+    pub fn metrics(&self) -> Result<MemMetrics, PipelineError> {
+        let stage1 = self.stage1.stats();
+        let disk = self.stage2.disk_usage().map_err(Stage2Error::from)?;
+        let replicas = self.stage3.replica_health();
+
+        Ok(MemMetrics {
+            stage1_entries: stage1.total_entries,
+            stage1_avg_weight: stage1.avg_weight,
+            stage2_files: disk.files,
+            stage2_bytes: disk.bytes,
+            stage3_core_memories: replicas.core_memories,
+            stage3_replicas_total: replicas.replicas_total,
+            stage3_replicas_healthy: replicas.replicas_healthy,
+            cache_hit_rate: self.cache.as_ref().map_or(0.0, |cache| cache.stats().cache_hit_rate),
+            corrections_performed: self.stage3.corrections_performed(),
+        })
+    }
+
+    /// Adds a memory to Stage 1, returning its epoch
+    ///
+    /// Anything Stage 1 evicts to stay under its capacity limit is written to
+    /// Stage 2 straight away rather than waiting for the next tick.
+    pub fn add_memory(&mut self, token: u16, weight: i16) -> Result<u32, PipelineError> {
+        let epoch = self.stage1.add_memory(token, weight);
+        let overflow = self.stage1.take_overflow();
+        if !overflow.is_empty() {
+            self.stage2.accept_entries(overflow)?;
+        }
+        Ok(epoch)
+    }
+
+    /// Returns every memory for `token` across all three stages, in epoch
+    /// order
+    ///
+    /// A memory held by more than one stage appears once; the copy with the
+    /// highest weight wins, and Stage 1 wins ties as the freshest.
+    pub fn query_token(&self, token: u16) -> Result<Vec<MemoryEntry>, PipelineError> {
+        let mut merged: BTreeMap<u32, MemoryEntry> = BTreeMap::new();
+        let candidates = self.stage1.find_by_token(token)
+            .into_iter()
+            .cloned()
+            .chain(self.stage2.find_by_token(token)?)
+            .chain(self.stage3.find_by_token(token)?);
+
+        for entry in candidates {
+            match merged.get(&entry.epoch()) {
+                Some(existing) if existing.weight() >= entry.weight() => {}
+                _ => {
+                    merged.insert(entry.epoch(), entry);
+                }
+            }
+        }
+
+        Ok(merged.into_values().collect())
+    }
+
+    /// Returns every `(source, target)` pair across all three stages where a
+    /// nonzero link points to a memory no stage holds
+    ///
+    /// Links from Stage 1 into later stages are fine; only targets missing
+    /// everywhere are reported. Pairs are sorted and appear once.
+    pub fn validate_links(&self) -> Result<Vec<(u32, u32)>, PipelineError> {
+        let exists = |epoch: u32| {
+            self.stage1.get_memory(epoch).is_ok()
+                || self.stage2.contains(epoch)
+                || self.stage3.contains(epoch)
+        };
+
+        let mut dangling: BTreeSet<(u32, u32)> = self.stage1.dangling_links(exists).into_iter().collect();
+
+        let stage2_epochs: Vec<u32> = self.stage2.iter_epochs().collect();
+        let later = self.stage2.get_entries(&stage2_epochs)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .chain(self.stage3.range(0, u32::MAX)?);
+        for entry in later {
+            let (link1, link2) = entry.links();
+            for target in [link1, link2] {
+                if target != 0 && !exists(target) {
+                    dangling.insert((entry.epoch(), target));
+                }
+            }
+        }
+
+        Ok(dangling.into_iter().collect())
+    }
+
+    /// Writes every stage's contents to `dir` as a checkpoint `restore` can
+    /// rebuild the pipeline from
+    ///
+    /// Stage 1 is snapshotted as by `Stage1::snapshot_to`; Stage 2 and
+    /// Stage 3 are written as archives of their entries, so the checkpoint
+    /// does not depend on how either store lays out its files. The manifest
+    /// goes in last, and any earlier one is removed first, so an interrupted
+    /// checkpoint is never mistaken for a complete one. The cache is not
+    /// included.
+    pub fn checkpoint(&self, dir: &Path) -> Result<(), MemError> {
+        let storage = StorageManager::new(dir).map_err(CheckpointError::from)?;
+        match fs::remove_file(dir.join(MANIFEST_FILE_NAME)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(CheckpointError::from(e).into()),
+            _ => {}
+        }
+
+        let stage1_path = dir.join(STAGE1_FILE_NAME);
+        self.stage1.snapshot_to(&stage1_path)?;
+        File::open(&stage1_path).and_then(|file| file.sync_all()).map_err(CheckpointError::from)?;
+
+        let stage2_epochs: Vec<u32> = self.stage2.iter_epochs().collect();
+        let stage2_entries: Vec<MemoryEntry> = self.stage2.get_entries(&stage2_epochs)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+        write_archive(&dir.join(STAGE2_FILE_NAME), &stage2_entries, CompressionAlgorithm::None)
+            .map_err(CheckpointError::from)?;
+
+        // Memories no replica can recover would otherwise be left out silently
+        let (stage3_entries, mut failed) = self.stage3.range_with_errors(0, u32::MAX);
+        if !failed.is_empty() {
+            return Err(failed.swap_remove(0).1.into());
+        }
+        write_archive(&dir.join(STAGE3_FILE_NAME), &stage3_entries, CompressionAlgorithm::None)
+            .map_err(CheckpointError::from)?;
+
+        let mut files = Vec::new();
+        for name in [STAGE1_FILE_NAME, STAGE2_FILE_NAME, STAGE3_FILE_NAME] {
+            let bytes = fs::read(dir.join(name)).map_err(CheckpointError::from)?;
+            files.push((name.to_string(), bytes.len() as u64, ChecksumAlgorithm::Blake3.compute(&bytes)));
+        }
+        let manifest = CheckpointManifest { version: CHECKPOINT_VERSION, files };
+        let encoded = bincode::serialize(&manifest).map_err(CheckpointError::from)?;
+        storage.save_memory(MANIFEST_FILE_NAME, &encoded).map_err(CheckpointError::from)?;
+        Ok(())
+    }
+
+    /// Rebuilds a pipeline from a checkpoint written by `checkpoint`
+    ///
+    /// Every file is checked against the manifest before anything is
+    /// loaded, so a partial or damaged checkpoint fails without touching
+    /// the stores `config` points at. Those should be empty; entries already
+    /// there are kept alongside the restored ones. A configured cache starts
+    /// out empty.
+    pub fn restore(dir: &Path, config: MemConfig) -> Result<Self, MemError> {
+        config.validate()?;
+        let raw = fs::read(dir.join(MANIFEST_FILE_NAME)).map_err(CheckpointError::from)?;
+        let manifest: CheckpointManifest = bincode::deserialize(&raw).map_err(CheckpointError::from)?;
+        if manifest.version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion { found: manifest.version }.into());
+        }
+        for name in [STAGE1_FILE_NAME, STAGE2_FILE_NAME, STAGE3_FILE_NAME] {
+            if !manifest.files.iter().any(|(listed, _, _)| listed == name) {
+                return Err(CheckpointError::MissingFile(name).into());
+            }
+        }
+        for (name, len, checksum) in &manifest.files {
+            let bytes = fs::read(dir.join(name)).map_err(CheckpointError::from)?;
+            if bytes.len() as u64 != *len || ChecksumAlgorithm::Blake3.compute(&bytes) != *checksum {
+                return Err(CheckpointError::Corrupt(name.clone()).into());
+            }
+        }
+
+        let stage1 = Stage1::restore_from(&dir.join(STAGE1_FILE_NAME), config.stage1)?;
+        let stage2_entries = read_archive(&dir.join(STAGE2_FILE_NAME))?;
+        let stage3_entries = read_archive(&dir.join(STAGE3_FILE_NAME))?;
+
+        let mut stage2 = Stage2::new(config.stage2).map_err(Stage2Error::from)?;
+        stage2.accept_entries(stage2_entries)?;
+        let stage3 = Stage3::new(config.stage3).map_err(Stage3Error::from)?;
+        for entry in stage3_entries {
+            stage3.store_core_memory(entry)?;
+        }
+
+        let pipeline = Self::new(stage1, stage2, stage3);
+        Ok(match config.cache {
+            Some(cache) => pipeline.with_cache(cache.build()),
+            None => pipeline,
+        })
+    }
+
+    /// Runs Stage 1 maintenance, hands aged entries to Stage 2, then promotes
+    /// qualifying Stage 2 entries into Stage 3
+    pub fn tick(&mut self) -> Result<TickSummary, PipelineError> {
+        let mut summary = TickSummary::default();
+
+        let aged = self.stage1.maintain();
+        summary.stage1_to_stage2 = aged.len();
+        self.stage2.accept_entries(aged)?;
+
+        // Stage 2 holds Stage 1's epochs, so age them on Stage 1's clock
+        let current_epoch = self.stage1.clock().now_epoch();
+
+        // Entries younger than Stage 3's minimum age cannot qualify, so they
+        // are never read
+        let min_age = self.stage3.min_age_days().saturating_mul(SECONDS_PER_DAY);
+        let Some(cutoff) = current_epoch.checked_sub(min_age) else {
+            return Ok(summary);
+        };
+        let candidates: Vec<u32> = self.stage2
+            .range(0, cutoff.saturating_add(1))
+            .filter(|&epoch| !self.stage3.contains(epoch))
+            .collect();
+        for (_, entry) in self.stage2.get_entries(&candidates)? {
+            let age_days = entry.age_from(current_epoch) / SECONDS_PER_DAY;
+            if self.stage3.evaluate_promotion(&entry, age_days) {
+                self.stage3.store_core_memory(entry)?;
+                summary.stage2_to_stage3 += 1;
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::clock::MockClock;
+    use crate::memory::config::{CacheConfig, ConfigError};
+    use crate::memory::stage1::Stage1Config;
+    use crate::memory::stage2::Stage2Config;
+    use crate::memory::stage3::Stage3Config;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_tick_promotes_through_all_stages() -> Result<(), PipelineError> {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        // Everything ages out of Stage 1 after a second and is immediately
+        // old enough for Stage 3
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let stage1 = Stage1::with_config(Stage1Config {
+            max_age: 0,
+            clock: clock.clone(),
+            ..Stage1Config::default()
+        });
+        let stage2 = Stage2::new(Stage2Config {
+            storage_path: stage2_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        }).map_err(Stage2Error::from)?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: stage3_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            min_age_days: 0,
+            ..Stage3Config::default()
+        }).map_err(Stage3Error::from)?;
+
+        let mut pipeline = MemoryPipeline::new(stage1, stage2, stage3);
+        let epoch = pipeline.stage1_mut().add_memory(100, 5000);
+
+        assert_eq!(pipeline.tick()?, TickSummary::default());
+
+        clock.advance(1);
+        let summary = pipeline.tick()?;
+        assert_eq!(summary.stage1_to_stage2, 1);
+        assert_eq!(summary.stage2_to_stage3, 1);
+
+        // Already promoted entries are not promoted again
+        assert_eq!(pipeline.tick()?, TickSummary::default());
+
+        assert!(pipeline.stage1().get_memory(epoch).is_err());
+        assert_eq!(pipeline.stage2_mut().get_entry(epoch)?.token(), 100);
+        assert_eq!(pipeline.stage3().get_core_memory(epoch)?.token(), 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tick_skips_entries_too_young_to_promote() -> Result<(), PipelineError> {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let stage1 = Stage1::with_config(Stage1Config {
+            clock: clock.clone(),
+            ..Stage1Config::default()
+        });
+        let mut stage2 = Stage2::new(Stage2Config {
+            storage_path: stage2_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        }).map_err(Stage2Error::from)?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: stage3_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            min_age_days: 1,
+            ..Stage3Config::default()
+        }).map_err(Stage3Error::from)?;
+
+        let old = 1_000_000 - 2 * SECONDS_PER_DAY;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(old, 100, 5000, 0, 0),
+            MemoryEntry::with_links(999_000, 101, 5000, 0, 0),
+        ])?;
+
+        // Damage the young entry's block, which ends the only data file; a
+        // tick that read it would fail
+        let data_file = fs::read_dir(stage2_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "bin"))
+            .unwrap();
+        let mut bytes = fs::read(&data_file).unwrap();
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&[0xFF; 4]);
+        fs::write(&data_file, bytes).unwrap();
+
+        let mut pipeline = MemoryPipeline::new(stage1, stage2, stage3);
+        assert_eq!(pipeline.tick()?.stage2_to_stage3, 1);
+        assert!(pipeline.stage3().contains(old));
+        assert!(!pipeline.stage3().contains(999_000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tick_promotes_same_second_burst() -> Result<(), PipelineError> {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let stage1 = Stage1::with_config(Stage1Config {
+            max_age: 0,
+            clock: clock.clone(),
+            ..Stage1Config::default()
+        });
+        let stage2 = Stage2::new(Stage2Config {
+            storage_path: stage2_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        }).map_err(Stage2Error::from)?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: stage3_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        }).map_err(Stage3Error::from)?;
+
+        let mut pipeline = MemoryPipeline::new(stage1, stage2, stage3);
+        for weight in [500, 600, 700] {
+            pipeline.add_memory(100, weight)?;
+        }
+        assert_eq!(pipeline.query_token(100)?.len(), 3);
+
+        // The whole burst ages out together and stays three memories
+        clock.advance(1);
+        assert_eq!(pipeline.tick()?.stage1_to_stage2, 3);
+        assert_eq!(pipeline.stage2().len(), 3);
+        // A second of decay takes a point off each
+        let weights: Vec<i16> = pipeline.query_token(100)?.iter().map(MemoryEntry::weight).collect();
+        assert_eq!(weights, vec![499, 599, 699]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_token_merges_stages() -> Result<(), PipelineError> {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let stage2 = Stage2::new(Stage2Config {
+            storage_path: stage2_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        }).map_err(Stage2Error::from)?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: stage3_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        }).map_err(Stage3Error::from)?;
+
+        let mut pipeline = MemoryPipeline::new(Stage1::new(), stage2, stage3);
+        let epoch = pipeline.stage1_mut().add_memory(100, 5000);
+
+        // An older, lighter copy of the same memory sits in Stage 2
+        pipeline.stage2_mut().accept_entries(vec![
+            MemoryEntry::with_links(epoch, 100, 4000, 0, 0),
+            MemoryEntry::with_links(7, 200, 4000, 0, 0),
+        ])?;
+        pipeline.stage3_mut().store_core_memory(MemoryEntry::with_links(5, 100, 900, 0, 0))?;
+
+        let found = pipeline.query_token(100)?;
+        let summary: Vec<(u32, i16)> = found.iter().map(|e| (e.epoch(), e.weight())).collect();
+        assert_eq!(summary, vec![(5, 900), (epoch, 5000)]);
+        assert_eq!(pipeline.query_token(300)?.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics_render_prometheus() -> Result<(), PipelineError> {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let stage2 = Stage2::new(Stage2Config {
+            storage_path: stage2_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        }).map_err(Stage2Error::from)?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: stage3_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        }).map_err(Stage3Error::from)?;
+
+        let cache = PersonalityCache::new(10, 0.0);
+        cache.add_memory(MemoryEntry::with_links(1, 100, 500, 0, 0), Default::default());
+        cache.get_memory(1);
+        cache.get_memory(2);
+
+        let mut pipeline = MemoryPipeline::new(Stage1::new(), stage2, stage3).with_cache(cache);
+        pipeline.stage1_mut().add_memory(100, 1000);
+        pipeline.stage1_mut().add_memory(101, 3000);
+        pipeline.stage2_mut().accept_entries(vec![MemoryEntry::with_links(7, 200, 500, 0, 0)])?;
+        pipeline.stage3_mut().store_core_memory(MemoryEntry::with_links(9, 300, 900, 0, 0))?;
+
+        // Damage the primary copy so the read repairs it
+        std::fs::write(stage3_dir.path().join("core_9.bin"), b"junk").unwrap();
+        pipeline.stage3().get_core_memory(9)?;
+
+        let metrics = pipeline.metrics()?;
+        assert_eq!(metrics.stage2_files, 1);
+        assert!(metrics.stage2_bytes > 0);
+        assert_eq!(metrics.corrections_performed, 1);
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE mem8_stage1_entries gauge"));
+        assert!(text.contains("mem8_stage1_entries 2\n"));
+        assert!(text.contains("mem8_stage1_avg_weight 2000\n"));
+        assert!(text.contains("mem8_stage2_files 1\n"));
+        assert!(text.contains(&format!("mem8_stage2_bytes {}\n", metrics.stage2_bytes)));
+        assert!(text.contains("mem8_stage3_core_memories 1\n"));
+        assert!(text.contains("mem8_stage3_replicas{state=\"healthy\"} 2\n"));
+        assert!(text.contains("mem8_stage3_replicas{state=\"damaged\"} 0\n"));
+        assert!(text.contains("mem8_cache_hit_ratio 0.5\n"));
+        assert!(text.contains("# TYPE mem8_corrections_total counter"));
+        assert!(text.contains("mem8_corrections_total 1\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_memory_absorbs_overflow() -> Result<(), PipelineError> {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let stage1 = Stage1::with_config(Stage1Config {
+            max_entries: Some(2),
+            ..Stage1Config::default()
+        });
+        let stage2 = Stage2::new(Stage2Config {
+            storage_path: stage2_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        }).map_err(Stage2Error::from)?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: stage3_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        }).map_err(Stage3Error::from)?;
+
+        let mut pipeline = MemoryPipeline::new(stage1, stage2, stage3);
+        let light = pipeline.add_memory(100, 200)?;
+        pipeline.add_memory(101, 3000)?;
+        pipeline.add_memory(102, 4000)?;
+
+        assert_eq!(pipeline.stage1().stats().total_entries, 2);
+        assert!(pipeline.stage1().get_memory(light).is_err());
+        assert_eq!(pipeline.stage2().get_entry(light)?.token(), 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_links_across_stages() -> Result<(), PipelineError> {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let stage2 = Stage2::new(Stage2Config {
+            storage_path: stage2_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        }).map_err(Stage2Error::from)?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: stage3_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        }).map_err(Stage3Error::from)?;
+
+        let mut pipeline = MemoryPipeline::new(Stage1::new(), stage2, stage3);
+        pipeline.stage3_mut().store_core_memory(MemoryEntry::with_links(5, 100, 900, 0, 9))?;
+        pipeline.stage2_mut().accept_entries(vec![MemoryEntry::with_links(7, 200, 400, 5, 8)])?;
+        let source = pipeline.stage1_mut().add_memory(300, 1000);
+        let target = pipeline.stage1_mut().add_memory(301, 1000);
+        pipeline.stage1_mut().link_memories(source, target, 0).unwrap();
+
+        // 5 and 7 link into other stages; only 8 and 9 are missing everywhere
+        assert_eq!(pipeline.validate_links()?, vec![(5, 9), (7, 8)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() -> Result<(), MemError> {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let checkpoint_dir = tempdir().unwrap();
+        let configs = |stage2: &Path, stage3: &Path, backup: &Path| MemConfig {
+            stage1: Stage1Config::default(),
+            stage2: Stage2Config {
+                storage_path: stage2.to_path_buf(),
+                ..Stage2Config::default()
+            },
+            stage3: Stage3Config {
+                storage_path: stage3.to_path_buf(),
+                redundancy_path: backup.to_path_buf(),
+                ..Stage3Config::default()
+            },
+            cache: None,
+        };
+
+        let mut pipeline = MemoryPipeline::from_config(configs(stage2_dir.path(), stage3_dir.path(), backup_dir.path()))?;
+        let fresh = pipeline.stage1_mut().add_memory(100, 3000);
+        pipeline.stage2_mut().accept_entries(vec![MemoryEntry::with_links(7, 100, 400, 5, 0)])?;
+        pipeline.stage3_mut().store_core_memory(MemoryEntry::with_links(5, 100, 900, 0, 0))?;
+        pipeline.checkpoint(checkpoint_dir.path())?;
+
+        let restored_dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let restore = || MemoryPipeline::restore(
+            checkpoint_dir.path(),
+            configs(restored_dirs[0].path(), restored_dirs[1].path(), restored_dirs[2].path()),
+        );
+        let restored = restore()?;
+        let summary = |entries: Vec<MemoryEntry>| -> Vec<(u32, i16, (u32, u32))> {
+            entries.iter().map(|e| (e.epoch(), e.weight(), e.links())).collect()
+        };
+        assert_eq!(summary(restored.query_token(100)?), summary(pipeline.query_token(100)?));
+        assert_eq!(restored.stage1().get_memory(fresh)?.weight(), 3000);
+        assert!(restored.stage2().contains(7));
+        assert!(restored.stage3().contains(5));
+
+        // A truncated component fails before any stage is rebuilt
+        let stage2_file = checkpoint_dir.path().join(STAGE2_FILE_NAME);
+        let bytes = fs::read(&stage2_file).unwrap();
+        fs::write(&stage2_file, &bytes[..bytes.len() - 1]).unwrap();
+        let err = restore().err().unwrap();
+        assert!(matches!(err, MemError::Checkpoint(CheckpointError::Corrupt(ref name)) if name == STAGE2_FILE_NAME), "{}", err);
+
+        // Without a manifest the checkpoint never finished
+        fs::remove_file(checkpoint_dir.path().join(MANIFEST_FILE_NAME)).unwrap();
+        assert!(matches!(restore(), Err(MemError::Checkpoint(CheckpointError::Io(_)))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_config_validates_before_building() {
+        let stage2_dir = tempdir().unwrap();
+        let stage3_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let config = MemConfig {
+            stage2: Stage2Config {
+                storage_path: stage2_dir.path().to_path_buf(),
+                ..Stage2Config::default()
+            },
+            stage3: Stage3Config {
+                storage_path: stage3_dir.path().to_path_buf(),
+                redundancy_path: backup_dir.path().to_path_buf(),
+                ..Stage3Config::default()
+            },
+            cache: Some(CacheConfig::default()),
+            ..MemConfig::default()
+        };
+
+        let pipeline = MemoryPipeline::from_config(config.clone()).unwrap();
+        assert!(pipeline.cache().is_some());
+
+        let invalid = MemConfig {
+            cache: Some(CacheConfig { max_entries: 0, ..CacheConfig::default() }),
+            ..config
+        };
+        let err = MemoryPipeline::from_config(invalid).err().unwrap();
+        assert!(matches!(err, MemError::Config(ConfigError::ZeroCacheCapacity)), "{}", err);
+    }
+}
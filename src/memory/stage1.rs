@@ -1,6 +1,17 @@
+use super::clock::{Clock, MemoryClock};
 use super::entry::MemoryEntry;
-use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use super::metrics::{WeightHistogram, DEFAULT_WEIGHT_EDGES};
+use super::token_codec::{HashingCodec, TokenCodec};
+use super::token_index::TokenIndex;
+use parking_lot::RwLock;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,6 +20,12 @@ pub enum Stage1Error {
     EntryNotFound(u32),
     #[error("Invalid link: target epoch {0} does not exist")]
     InvalidLink(u32),
+    #[error("Memory {0} has no free link slot")]
+    NoFreeLink(u32),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
 }
 
 /// Configuration for Stage1 memory management
@@ -17,11 +34,32 @@ pub struct Stage1Config {
     /// Maximum age (in seconds) before memory is eligible for cleanup
     pub max_age: u32,
     /// Minimum weight threshold for retention
-    pub min_weight: u16,
-    /// Weight decay rate (per hour)
-    pub decay_rate: f32,
+    pub min_weight: i16,
+    /// How much weight memories lose between maintenance passes; shared so
+    /// the config stays cloneable
+    pub decay_schedule: Arc<dyn DecaySchedule>,
     /// Token similarity threshold for automatic linking
     pub similarity_threshold: f32,
+    /// Most links `update_automatic_links` records per memory; links past
+    /// the two built-in slots go to the overflow table
+    pub max_auto_links: usize,
+    /// Most memories held at once; adding past the cap evicts the lightest
+    /// ones for Stage 2. `None` means unbounded.
+    pub max_entries: Option<usize>,
+    /// Source of the epochs memories are stamped and aged with; shared so
+    /// the config stays cloneable
+    pub clock: Arc<dyn Clock>,
+    /// Links start at strength 1.0 and weaken with their target's weight
+    /// decay; `maintain` drops links that fall below this
+    pub link_strength_floor: f32,
+    /// Maps string concepts to tokens for the `*_concept` helpers; shared
+    /// so other stages can use the same vocabulary
+    pub codec: Arc<dyn TokenCodec>,
+    /// Bucket edges for the weight histogram in `stats`
+    pub weight_histogram_edges: Vec<i16>,
+    /// Per-token cap on additions through `try_add_memory`. `None` means
+    /// unlimited.
+    pub rate_limit: Option<RateLimit>,
 }
 
 impl Default for Stage1Config {
@@ -29,40 +67,596 @@ impl Default for Stage1Config {
         Self {
             max_age: 3600 * 24,  // 24 hours
             min_weight: 100,
-            decay_rate: 0.95,    // 5% decay per hour
+            decay_schedule: Arc::new(ExponentialDecay::new(0.95)),  // 5% decay per hour
             similarity_threshold: 0.7,
+            max_auto_links: 2,
+            max_entries: None,
+            clock: Arc::new(MemoryClock::default()),
+            link_strength_floor: 0.05,
+            codec: Arc::new(HashingCodec::new()),
+            weight_histogram_edges: DEFAULT_WEIGHT_EDGES.to_vec(),
+            rate_limit: None,
         }
     }
 }
 
+/// What `try_add_memory` does with an addition over the rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitAction {
+    /// Drop the addition
+    #[default]
+    Reject,
+    /// Add its weight to the token's newest memory instead
+    Coalesce,
+}
+
+/// Caps how many memories one token may add within a sliding window
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Most additions per token within the window
+    pub max_per_window: usize,
+    /// Length of the sliding window in seconds
+    pub window_seconds: u32,
+    pub action: RateLimitAction,
+}
+
+/// Result of `try_add_memory`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddOutcome {
+    /// A new memory was added at this epoch
+    Inserted(u32),
+    /// The token is over its limit and the addition was dropped
+    RateLimited,
+    /// The token is over its limit and the weight went to the memory at
+    /// this epoch
+    Coalesced(u32),
+}
+
+/// Sliding-window admission counter per token
+///
+/// Each token keeps at most `max_per_window` admission times, and tokens
+/// with nothing inside the window are swept once per window, so the state
+/// only covers tokens seen in the last two windows.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    recent: HashMap<u16, VecDeque<u32>>,
+    last_sweep: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            recent: HashMap::new(),
+            last_sweep: 0,
+        }
+    }
+
+    pub fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    /// Records an addition of `token` at `now` if it is under the limit,
+    /// returning whether it was admitted
+    pub fn admit(&mut self, token: u16, now: u32) -> bool {
+        let window = self.limit.window_seconds;
+        if now.saturating_sub(self.last_sweep) >= window {
+            self.recent.retain(|_, times| times.back().is_some_and(|&t| now.saturating_sub(t) < window));
+            self.last_sweep = now;
+        }
+
+        let times = self.recent.entry(token).or_default();
+        while times.front().is_some_and(|&t| now.saturating_sub(t) >= window) {
+            times.pop_front();
+        }
+        if times.len() >= self.limit.max_per_window {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Returns how many tokens currently have admissions on record
+    pub fn tracked_tokens(&self) -> usize {
+        self.recent.len()
+    }
+}
+
+/// Decides how much of a memory's weight survives a maintenance pass
+pub trait DecaySchedule: Send + Sync + fmt::Debug {
+    /// Returns the multiplier applied to the weight of a memory that is
+    /// `age_seconds` old, `elapsed_seconds` after the previous pass
+    fn factor(&self, age_seconds: u32, elapsed_seconds: u32) -> f32;
+}
+
+/// Loses a fixed fraction of weight per hour, compounding
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialDecay {
+    rate_per_hour: f32,
+}
+
+impl ExponentialDecay {
+    /// `rate_per_hour` is the fraction retained after one hour
+    pub fn new(rate_per_hour: f32) -> Self {
+        Self { rate_per_hour }
+    }
+}
+
+impl DecaySchedule for ExponentialDecay {
+    fn factor(&self, _age_seconds: u32, elapsed_seconds: u32) -> f32 {
+        self.rate_per_hour.powf(elapsed_seconds as f32 / 3600.0)
+    }
+}
+
+/// Loses a fixed fraction of the current weight per hour, without compounding
+/// within a pass
+#[derive(Debug, Clone, Copy)]
+pub struct LinearDecay {
+    loss_per_hour: f32,
+}
+
+impl LinearDecay {
+    /// `loss_per_hour` is the fraction removed per hour, e.g. 0.05 for 5%
+    pub fn new(loss_per_hour: f32) -> Self {
+        Self { loss_per_hour }
+    }
+}
+
+impl DecaySchedule for LinearDecay {
+    fn factor(&self, _age_seconds: u32, elapsed_seconds: u32) -> f32 {
+        (1.0 - self.loss_per_hour * elapsed_seconds as f32 / 3600.0).max(0.0)
+    }
+}
+
+/// Applies a different hourly rate depending on how old a memory is
+///
+/// Each step is `(min_age_seconds, rate_per_hour)`; the step with the largest
+/// minimum age not exceeding the memory's age applies. Memories younger than
+/// every step do not decay.
+#[derive(Debug, Clone)]
+pub struct StepDecay {
+    steps: Vec<(u32, f32)>,
+}
+
+impl StepDecay {
+    pub fn new(mut steps: Vec<(u32, f32)>) -> Self {
+        steps.sort_by_key(|&(min_age, _)| min_age);
+        Self { steps }
+    }
+}
+
+impl DecaySchedule for StepDecay {
+    fn factor(&self, age_seconds: u32, elapsed_seconds: u32) -> f32 {
+        self.steps.iter()
+            .rev()
+            .find(|&&(min_age, _)| age_seconds >= min_age)
+            .map_or(1.0, |&(_, rate)| rate.powf(elapsed_seconds as f32 / 3600.0))
+    }
+}
+
+/// Scores how closely two tokens are related, from 0.0 (unrelated) to 1.0
+pub trait SimilarityMetric: Send + Sync {
+    fn similarity(&self, a: u16, b: u16) -> f32;
+}
+
+/// Treats numerically close token IDs as similar
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokenDistanceMetric;
+
+impl SimilarityMetric for TokenDistanceMetric {
+    fn similarity(&self, a: u16, b: u16) -> f32 {
+        let diff = (a as i32 - b as i32).abs();
+        let max_diff = u16::MAX as i32;
+        1.0 - (diff as f32 / max_diff as f32)
+    }
+}
+
+/// Cosine similarity over a caller-supplied embedding table
+///
+/// Tokens without an embedding, or with a zero-length vector, are treated as
+/// unrelated to everything.
+#[derive(Debug, Clone, Default)]
+pub struct CosineTokenMetric {
+    embeddings: HashMap<u16, Vec<f32>>,
+}
+
+impl CosineTokenMetric {
+    pub fn new(embeddings: HashMap<u16, Vec<f32>>) -> Self {
+        Self { embeddings }
+    }
+}
+
+impl SimilarityMetric for CosineTokenMetric {
+    fn similarity(&self, a: u16, b: u16) -> f32 {
+        let (Some(va), Some(vb)) = (self.embeddings.get(&a), self.embeddings.get(&b)) else {
+            return 0.0;
+        };
+
+        let dot: f32 = va.iter().zip(vb).map(|(x, y)| x * y).sum();
+        let norm_a = va.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let norm_b = vb.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm_a == 0.0 || norm_b == 0.0 {
+            return 0.0;
+        }
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// On-disk form of a Stage1 instance
+#[derive(Serialize, Deserialize)]
+struct Stage1Snapshot {
+    entries: Vec<MemoryEntry>,
+    current_epoch: u32,
+    last_cleanup: u32,
+    extra_links: HashMap<u32, Vec<u32>>,
+    added_at: HashMap<u32, u32>,
+}
+
 /// High-resolution, ephemeral memory storage
 pub struct Stage1 {
     entries: HashMap<u32, MemoryEntry>,
+    tokens: TokenIndex,
+    /// Links beyond an entry's `link1`/`link2` slots
+    extra_links: HashMap<u32, Vec<u32>>,
+    /// Strength of each `(source, target)` link that has decayed; links not
+    /// listed are at full strength
+    link_strengths: HashMap<(u32, u32), f32>,
+    /// Entries evicted by the capacity limit, waiting for Stage 2
+    overflow: Vec<MemoryEntry>,
+    /// Wall-clock second each memory was added, for memories a burst pushed
+    /// to a later epoch; the rest were added at their epoch
+    added_at: HashMap<u32, u32>,
     current_epoch: u32,
     config: Stage1Config,
     last_cleanup: u32,
+    metric: Box<dyn SimilarityMetric>,
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl Default for Stage1 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Stage1 {
     /// Creates a new Stage1 memory instance
     pub fn new() -> Self {
+        Self::with_config(Stage1Config::default())
+    }
+
+    /// Creates a new Stage1 memory instance with a custom configuration
+    pub fn with_config(config: Stage1Config) -> Self {
+        // Decay is measured from creation, not from the seed epoch
+        let now = config.clock.now_epoch();
+        let rate_limiter = config.rate_limit.map(RateLimiter::new);
+
         Self {
             entries: HashMap::new(),
+            tokens: TokenIndex::new(),
+            extra_links: HashMap::new(),
+            link_strengths: HashMap::new(),
+            overflow: Vec::new(),
+            added_at: HashMap::new(),
             current_epoch: 0,
-            config: Stage1Config::default(),
-            last_cleanup: 0,
+            config,
+            last_cleanup: now,
+            metric: Box::new(TokenDistanceMetric),
+            rate_limiter,
         }
     }
 
-    /// Adds a new memory entry
-    pub fn add_memory(&mut self, token: u16, weight: u16) -> u32 {
-        let entry = MemoryEntry::new(token, weight);
-        let epoch = entry.epoch();
+    /// Creates a new Stage1 memory instance that links memories using a
+    /// custom similarity metric
+    pub fn with_metric(metric: impl SimilarityMetric + 'static) -> Self {
+        let mut stage1 = Self::new();
+        stage1.metric = Box::new(metric);
+        stage1
+    }
+
+    /// Writes all entries and decay bookkeeping to `path`
+    pub fn snapshot_to(&self, path: &Path) -> Result<(), Stage1Error> {
+        let snapshot = Stage1Snapshot {
+            entries: self.entries.values().cloned().collect(),
+            current_epoch: self.current_epoch,
+            last_cleanup: self.last_cleanup,
+            extra_links: self.extra_links.clone(),
+            added_at: self.added_at.clone(),
+        };
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(&mut writer, &snapshot)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Rebuilds a Stage1 instance from a snapshot written by `snapshot_to`
+    ///
+    /// The last cleanup time is restored so decay resumes from where it left
+    /// off. Similarity metrics and link strengths are not persisted; the
+    /// default metric is used and every link starts at full strength.
+    pub fn restore_from(path: &Path, config: Stage1Config) -> Result<Self, Stage1Error> {
+        let reader = BufReader::new(File::open(path)?);
+        let snapshot: Stage1Snapshot = bincode::deserialize_from(reader)?;
+
+        let mut stage1 = Self::with_config(config);
+        stage1.entries = snapshot.entries
+            .into_iter()
+            .map(|entry| (entry.epoch(), entry))
+            .collect();
+        for entry in stage1.entries.values() {
+            stage1.tokens.insert(entry.token(), entry.epoch());
+        }
+        stage1.current_epoch = snapshot.current_epoch;
+        stage1.last_cleanup = snapshot.last_cleanup;
+        stage1.extra_links = snapshot.extra_links;
+        stage1.added_at = snapshot.added_at;
+        Ok(stage1)
+    }
+
+    /// Adds a new memory entry, returning its unique epoch
+    ///
+    /// Epochs track wall-clock seconds, but a memory added in the same second
+    /// as the previous one takes the next free second instead, so a burst of
+    /// additions never overwrites earlier entries. Such a memory still ages
+    /// and decays from the second it was actually added.
+    ///
+    /// If `max_entries` is already reached, the lowest-weight memories are
+    /// evicted first and queued for `take_overflow`. Pinned memories are
+    /// never evicted.
+    pub fn add_memory(&mut self, token: u16, weight: i16) -> u32 {
+        if let Some(max_entries) = self.config.max_entries {
+            self.evict_to(max_entries.saturating_sub(1));
+        }
+
+        let now = self.config.clock.now_epoch();
+        let epoch = now.max(self.current_epoch.saturating_add(1));
+        let entry = MemoryEntry::with_links(epoch, token, weight, 0, 0);
         self.entries.insert(epoch, entry);
+        self.tokens.insert(token, epoch);
+        if epoch != now {
+            self.added_at.insert(epoch, now);
+        }
         self.current_epoch = epoch;
         epoch
     }
 
+    /// Adds a memory unless its token is over the config's `rate_limit`
+    ///
+    /// Over the limit, the addition is dropped or, with
+    /// `RateLimitAction::Coalesce`, its weight is added to the token's
+    /// newest memory; with no such memory left it is dropped. Plain
+    /// `add_memory` is not limited.
+    pub fn try_add_memory(&mut self, token: u16, weight: i16) -> AddOutcome {
+        let now = self.config.clock.now_epoch();
+        let Some(limiter) = self.rate_limiter.as_mut() else {
+            return AddOutcome::Inserted(self.add_memory(token, weight));
+        };
+        if limiter.admit(token, now) {
+            return AddOutcome::Inserted(self.add_memory(token, weight));
+        }
+
+        if limiter.limit().action == RateLimitAction::Coalesce {
+            let newest = self.tokens.epochs(token).last();
+            if let Some(entry) = newest.and_then(|epoch| self.entries.get_mut(&epoch)) {
+                entry.adjust_weight(weight);
+                return AddOutcome::Coalesced(entry.epoch());
+            }
+        }
+        AddOutcome::RateLimited
+    }
+
+    /// Adds a batch of `(token, weight)` memories, returning their epochs in
+    /// order
+    ///
+    /// Epochs are assigned as `add_memory` would for the same sequence of
+    /// calls. Room is made by evicting existing memories first; only a batch
+    /// larger than `max_entries` loses its own lightest memories.
+    pub fn add_memories(&mut self, items: &[(u16, i16)]) -> Vec<u32> {
+        if let Some(max_entries) = self.config.max_entries {
+            self.evict_to(max_entries.saturating_sub(items.len()));
+        }
+
+        self.entries.reserve(items.len());
+        let now = self.config.clock.now_epoch();
+        let mut epoch = now.max(self.current_epoch.saturating_add(1));
+        let mut epochs = Vec::with_capacity(items.len());
+        for &(token, weight) in items {
+            self.entries.insert(epoch, MemoryEntry::with_links(epoch, token, weight, 0, 0));
+            self.tokens.insert(token, epoch);
+            if epoch != now {
+                self.added_at.insert(epoch, now);
+            }
+            self.current_epoch = epoch;
+            epochs.push(epoch);
+            epoch = epoch.saturating_add(1);
+        }
+
+        if let Some(max_entries) = self.config.max_entries {
+            self.evict_to(max_entries);
+        }
+        epochs
+    }
+
+    /// Adds a memory for a string concept, encoded by the config's codec
+    pub fn add_concept(&mut self, concept: &str, weight: i16) -> u32 {
+        let token = self.config.codec.encode(concept);
+        self.add_memory(token, weight)
+    }
+
+    /// Returns the epoch of the newest memory for `token` added less than
+    /// `dedup_window_seconds` ago, or adds a new one and returns its epoch
+    ///
+    /// A window of zero always adds.
+    pub fn get_or_insert(&mut self, token: u16, weight: i16, dedup_window_seconds: u32) -> u32 {
+        let now = self.config.clock.now_epoch();
+        let recent = self.tokens
+            .epochs(token)
+            .last()
+            .and_then(|epoch| self.entries.get(&epoch))
+            .filter(|entry| age_of(&self.added_at, entry, now) < dedup_window_seconds);
+
+        match recent {
+            Some(entry) => entry.epoch(),
+            None => self.add_memory(token, weight),
+        }
+    }
+
+    /// Merges memories of the same token stored less than `window_seconds`
+    /// after one another, returning how many were merged away
+    ///
+    /// Each run is folded into its oldest memory, which takes the saturating
+    /// sum of the weights and the union of the links. Links to a merged
+    /// memory are pointed at its survivor, keeping the stronger link where
+    /// that makes two coincide.
+    pub fn consolidate(&mut self, window_seconds: u32) -> usize {
+        let mut survivors: HashMap<u32, u32> = HashMap::new();
+        // (token, surviving epoch, second the run's latest memory was added)
+        let mut run: Option<(u16, u32, u32)> = None;
+        for (token, epoch) in self.tokens.iter() {
+            let added = self.added_at.get(&epoch).copied().unwrap_or(epoch);
+            match run {
+                Some((run_token, survivor, last)) if run_token == token && added.saturating_sub(last) < window_seconds => {
+                    survivors.insert(epoch, survivor);
+                    run = Some((token, survivor, added));
+                }
+                _ => run = Some((token, epoch, added)),
+            }
+        }
+        if survivors.is_empty() {
+            return 0;
+        }
+
+        // Every link with its strength, so merged links keep the stronger
+        let mut links: HashMap<u32, Vec<(u32, f32)>> = self.entries
+            .keys()
+            .map(|&source| {
+                let targets = self.all_links(source).unwrap_or_default()
+                    .into_iter()
+                    .map(|target| (target, self.link_strength(source, target).unwrap_or(1.0)))
+                    .collect();
+                (source, targets)
+            })
+            .collect();
+        let mut merged: Vec<u32> = survivors.keys().copied().collect();
+        merged.sort_unstable();
+        // Summed wide so the result does not depend on the order of merging
+        let mut weights: HashMap<u32, i32> = HashMap::new();
+        let mut pinned: HashSet<u32> = HashSet::new();
+        for &epoch in &merged {
+            let survivor = survivors[&epoch];
+            let weight = self.entries[&epoch].weight() as i32;
+            if self.entries[&epoch].is_pinned() {
+                pinned.insert(survivor);
+            }
+            *weights.entry(survivor).or_insert(self.entries[&survivor].weight() as i32) += weight;
+            let absorbed = links.remove(&epoch).unwrap_or_default();
+            links.entry(survivor).or_default().extend(absorbed);
+            self.remove_entry(epoch);
+        }
+        for (survivor, weight) in weights {
+            if let Some(entry) = self.entries.get_mut(&survivor) {
+                let weight = weight.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+                let mut rebuilt = MemoryEntry::with_links(survivor, entry.token(), weight, 0, 0);
+                rebuilt.set_pinned(entry.is_pinned() || pinned.contains(&survivor));
+                *entry = match entry.expires_at() {
+                    Some(expires_at) => rebuilt.with_expiry(expires_at),
+                    None => rebuilt,
+                };
+            }
+        }
+
+        self.extra_links.clear();
+        self.link_strengths.clear();
+        for (source, targets) in links {
+            let mut rewritten: Vec<(u32, f32)> = Vec::new();
+            for (target, strength) in targets {
+                let target = survivors.get(&target).copied().unwrap_or(target);
+                match rewritten.iter_mut().find(|(kept, _)| *kept == target) {
+                    Some((_, kept)) => *kept = kept.max(strength),
+                    None if target != source => rewritten.push((target, strength)),
+                    None => {}
+                }
+            }
+
+            for &(target, strength) in &rewritten {
+                if strength < 1.0 {
+                    self.link_strengths.insert((source, target), strength);
+                }
+            }
+            let slot = |i: usize| rewritten.get(i).map_or(0, |&(target, _)| target);
+            if let Some(entry) = self.entries.get_mut(&source) {
+                entry.update_links(slot(0), slot(1));
+            }
+            if rewritten.len() > 2 {
+                self.extra_links.insert(source, rewritten[2..].iter().map(|&(target, _)| target).collect());
+            }
+        }
+
+        merged.len()
+    }
+
+    /// Pins a memory so maintenance and the capacity limit never touch it
+    pub fn pin(&mut self, epoch: u32) -> Result<(), Stage1Error> {
+        self.set_pinned(epoch, true)
+    }
+
+    /// Lets a pinned memory decay and age out again
+    pub fn unpin(&mut self, epoch: u32) -> Result<(), Stage1Error> {
+        self.set_pinned(epoch, false)
+    }
+
+    fn set_pinned(&mut self, epoch: u32, pinned: bool) -> Result<(), Stage1Error> {
+        self.entries
+            .get_mut(&epoch)
+            .ok_or(Stage1Error::EntryNotFound(epoch))?
+            .set_pinned(pinned);
+        Ok(())
+    }
+
+    /// Returns the memories evicted by the capacity limit since the last
+    /// call, oldest first
+    pub fn take_overflow(&mut self) -> Vec<MemoryEntry> {
+        std::mem::take(&mut self.overflow)
+    }
+
+    /// Evicts the lightest unpinned memories, oldest first among equal
+    /// weights, until at most `len` remain
+    fn evict_to(&mut self, len: usize) {
+        if self.entries.len() <= len {
+            return;
+        }
+
+        let mut candidates: Vec<(i16, u32)> = self.entries
+            .values()
+            .filter(|entry| !entry.is_pinned())
+            .map(|entry| (entry.weight(), entry.epoch()))
+            .collect();
+        candidates.sort_unstable();
+
+        // Pinned memories stay even if that leaves the store over `len`
+        let excess = (self.entries.len() - len).min(candidates.len());
+        for &(_, epoch) in &candidates[..excess] {
+            if let Some(entry) = self.remove_entry(epoch) {
+                self.overflow.push(entry);
+            }
+        }
+    }
+
+    fn remove_entry(&mut self, epoch: u32) -> Option<MemoryEntry> {
+        let entry = self.entries.remove(&epoch)?;
+        self.tokens.remove(entry.token(), epoch);
+        self.extra_links.remove(&epoch);
+        self.added_at.remove(&epoch);
+        Some(entry)
+    }
+
+    /// The clock memories are stamped and aged with
+    pub fn clock(&self) -> &dyn Clock {
+        self.config.clock.as_ref()
+    }
+
     /// Retrieves a memory by its epoch
     pub fn get_memory(&self, epoch: u32) -> Result<&MemoryEntry, Stage1Error> {
         self.entries
@@ -70,6 +664,28 @@ impl Stage1 {
             .ok_or(Stage1Error::EntryNotFound(epoch))
     }
 
+    /// Returns every memory that encodes `token`, in epoch order
+    pub fn find_by_token(&self, token: u16) -> Vec<&MemoryEntry> {
+        self.tokens
+            .epochs(token)
+            .filter_map(|epoch| self.entries.get(&epoch))
+            .collect()
+    }
+
+    /// Returns every memory for a string concept, in epoch order
+    ///
+    /// Under a hashing codec this includes memories of any concept that
+    /// collides with it.
+    pub fn find_by_concept(&self, concept: &str) -> Vec<&MemoryEntry> {
+        self.find_by_token(self.config.codec.encode(concept))
+    }
+
+    /// The string concept a memory's token decodes to
+    pub fn concept(&self, epoch: u32) -> Result<Option<String>, Stage1Error> {
+        let token = self.get_memory(epoch)?.token();
+        Ok(self.config.codec.decode(token))
+    }
+
     /// Links two memories together
     pub fn link_memories(
         &mut self,
@@ -94,36 +710,228 @@ impl Stage1 {
         }
     }
 
+    /// Adds a link from `source` to `target`, using a free built-in slot
+    /// first and the overflow table once both are taken
+    ///
+    /// Adding a link that already exists does nothing.
+    pub fn add_link(&mut self, source: u32, target: u32) -> Result<(), Stage1Error> {
+        if target == 0 || target == source || !self.entries.contains_key(&target) {
+            return Err(Stage1Error::InvalidLink(target));
+        }
+        let entry = self.entries.get_mut(&source).ok_or(Stage1Error::EntryNotFound(source))?;
+
+        if let Some((link1, link2)) = Self::with_link(entry.links(), target) {
+            entry.update_links(link1, link2);
+        } else {
+            let extra = self.extra_links.entry(source).or_default();
+            if !extra.contains(&target) {
+                extra.push(target);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns every link of a memory: the two built-in slots, then overflow
+    /// links in the order they were added
+    pub fn all_links(&self, epoch: u32) -> Result<Vec<u32>, Stage1Error> {
+        let (link1, link2) = self.get_memory(epoch)?.links();
+        Ok([link1, link2]
+            .into_iter()
+            .filter(|&link| link != 0)
+            .chain(self.extra_links.get(&epoch).into_iter().flatten().copied())
+            .collect())
+    }
+
+    /// Current strength of the link from `source` to `target`, or `None` if
+    /// there is no such link
+    pub fn link_strength(&self, source: u32, target: u32) -> Option<f32> {
+        self.all_links(source).ok()?.contains(&target).then(|| {
+            self.link_strengths.get(&(source, target)).copied().unwrap_or(1.0)
+        })
+    }
+
+    /// Returns every `(source, target)` pair where a nonzero link points to
+    /// a memory that is no longer held, sorted by source then target
+    pub fn validate_links(&self) -> Vec<(u32, u32)> {
+        self.dangling_links(|epoch| self.entries.contains_key(&epoch))
+    }
+
+    /// Like `validate_links`, but a target counts as present whenever
+    /// `exists` says so, letting callers check against other stages too
+    pub fn dangling_links(&self, exists: impl Fn(u32) -> bool) -> Vec<(u32, u32)> {
+        let mut dangling: Vec<(u32, u32)> = self.entries
+            .keys()
+            .flat_map(|&epoch| {
+                self.all_links(epoch)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(move |target| (epoch, target))
+            })
+            .filter(|&(_, target)| !exists(target))
+            .collect();
+        dangling.sort_unstable();
+        dangling
+    }
+
+    /// Clears every link reported by `validate_links`, returning how many
+    /// were removed
+    pub fn prune_dangling_links(&mut self) -> usize {
+        let dangling = self.validate_links();
+        for &(source, target) in &dangling {
+            self.unlink(source, target);
+        }
+        dangling.len()
+    }
+
+    /// Removes the link from `source` to `target` from whichever slot or
+    /// overflow list holds it
+    fn unlink(&mut self, source: u32, target: u32) {
+        if let Some(entry) = self.entries.get_mut(&source) {
+            let (link1, link2) = entry.links();
+            let clear = |link: u32| if link == target { 0 } else { link };
+            entry.update_links(clear(link1), clear(link2));
+        }
+        if let Some(extra) = self.extra_links.get_mut(&source) {
+            extra.retain(|&link| link != target);
+            if extra.is_empty() {
+                self.extra_links.remove(&source);
+            }
+        }
+        self.link_strengths.remove(&(source, target));
+    }
+
+    /// Weakens every link by the decay factor its target received this pass
+    /// and drops those below `link_strength_floor`
+    ///
+    /// Strengths of links that no longer exist are forgotten, so a link that
+    /// is removed and later recreated starts at full strength again.
+    fn decay_links(&mut self, factors: &HashMap<u32, f32>) {
+        let mut strengths = HashMap::new();
+        let mut weak = Vec::new();
+        let sources: Vec<u32> = self.entries.keys().copied().collect();
+        for source in sources {
+            for target in self.all_links(source).unwrap_or_default() {
+                let strength = self.link_strengths.get(&(source, target)).copied().unwrap_or(1.0)
+                    * factors.get(&target).copied().unwrap_or(1.0);
+                if strength < self.config.link_strength_floor {
+                    weak.push((source, target));
+                } else {
+                    strengths.insert((source, target), strength);
+                }
+            }
+        }
+
+        self.link_strengths = strengths;
+        for (source, target) in weak {
+            self.unlink(source, target);
+        }
+    }
+
+    /// Links two memories to each other, each taking the other in its first
+    /// free link slot
+    ///
+    /// A memory that already links to the other keeps that slot. Nothing is
+    /// changed unless both sides can be linked.
+    pub fn link_bidirectional(&mut self, a: u32, b: u32) -> Result<(), Stage1Error> {
+        if a == b {
+            return Err(Stage1Error::InvalidLink(b));
+        }
+        let entry_a = self.entries.get(&a).ok_or(Stage1Error::EntryNotFound(a))?;
+        let entry_b = self.entries.get(&b).ok_or(Stage1Error::InvalidLink(b))?;
+
+        let links_a = Self::with_link(entry_a.links(), b).ok_or(Stage1Error::NoFreeLink(a))?;
+        let links_b = Self::with_link(entry_b.links(), a).ok_or(Stage1Error::NoFreeLink(b))?;
+
+        if let Some(entry) = self.entries.get_mut(&a) {
+            entry.update_links(links_a.0, links_a.1);
+        }
+        if let Some(entry) = self.entries.get_mut(&b) {
+            entry.update_links(links_b.0, links_b.1);
+        }
+        Ok(())
+    }
+
+    /// Returns `links` with `target` placed in the first free slot, or `None`
+    /// if both slots hold other memories
+    fn with_link(links: (u32, u32), target: u32) -> Option<(u32, u32)> {
+        match links {
+            (l1, l2) if l1 == target || l2 == target => Some((l1, l2)),
+            (0, l2) => Some((target, l2)),
+            (l1, 0) => Some((l1, target)),
+            _ => None,
+        }
+    }
+
     /// Returns all memories older than the specified age in seconds
     pub fn get_aged_memories(&self, min_age_seconds: u32) -> Vec<&MemoryEntry> {
+        let now = self.config.clock.now_epoch();
         self.entries
             .values()
-            .filter(|entry| entry.age_from(self.current_epoch) >= min_age_seconds)
+            .filter(|entry| age_of(&self.added_at, entry, now) >= min_age_seconds)
             .collect()
     }
 
+    /// Scores every memory against `token` with the configured similarity
+    /// metric, returning the `limit` best as (epoch, score), most similar
+    /// first
+    ///
+    /// Ties are broken by epoch so the ranking is stable.
+    pub fn recall_similar(&self, token: u16, limit: usize) -> Vec<(u32, f32)> {
+        let mut scored: Vec<(u32, f32)> = self.entries
+            .values()
+            .map(|entry| (entry.epoch(), self.metric.similarity(token, entry.token())))
+            .collect();
+
+        scored.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scored.truncate(limit);
+        scored
+    }
+
     /// Performs memory cleanup and weight decay
+    ///
+    /// Aged-out and light entries are removed and returned for Stage 2,
+    /// after any still waiting in the overflow queue; expired entries are
+    /// removed and discarded. Links weaken along with their targets and
+    /// are dropped once too weak. Pinned memories are left untouched.
     pub fn maintain(&mut self) -> Vec<MemoryEntry> {
-        let current_epoch = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
+        let current_epoch = self.config.clock.now_epoch();
 
-        let hours_since_cleanup = (current_epoch - self.last_cleanup) / 3600;
-        let decay_factor = self.config.decay_rate.powi(hours_since_cleanup as i32);
+        // A clock that moved backward gives no elapsed time to decay over
+        let elapsed = current_epoch.checked_sub(self.last_cleanup);
+        if elapsed.is_none() {
+            log::warn!(
+                "clock moved backward from epoch {} to {}; skipping decay",
+                self.last_cleanup,
+                current_epoch
+            );
+        }
 
         // Collect entries for removal or transition to Stage 2
         let mut to_remove = Vec::new();
-        let mut aged_entries = Vec::new();
+        let mut aged_entries = self.take_overflow();
+        let mut factors = HashMap::with_capacity(self.entries.len());
 
         for (epoch, entry) in self.entries.iter_mut() {
-            // Apply weight decay
-            let new_weight = (entry.weight() as f32 * decay_factor) as u16;
-            entry.adjust_weight((new_weight as i16) - (entry.weight() as i16));
+            if entry.is_pinned() {
+                continue;
+            }
+
+            // Apply weight decay; negative weights shrink toward zero but stay
+            // below min_weight, so they are still the first to be dropped
+            let age = age_of(&self.added_at, entry, current_epoch);
+            if let Some(elapsed) = elapsed {
+                let decay_factor = self.config.decay_schedule.factor(age, elapsed);
+                factors.insert(*epoch, decay_factor);
+                entry.set_weight(decayed_weight(entry.weight(), decay_factor));
+            }
+
+            if entry.is_expired(current_epoch) {
+                to_remove.push(*epoch);
+                continue;
+            }
 
             // Check for removal conditions
-            if entry.age_from(current_epoch) > self.config.max_age 
-               || entry.weight() < self.config.min_weight {
+            if age > self.config.max_age || entry.weight() < self.config.min_weight {
                 to_remove.push(*epoch);
                 aged_entries.push(entry.clone());
             }
@@ -131,10 +939,13 @@ impl Stage1 {
 
         // Remove processed entries
         for epoch in to_remove {
-            self.entries.remove(&epoch);
+            self.remove_entry(epoch);
         }
+        self.decay_links(&factors);
 
-        self.last_cleanup = current_epoch;
+        // Keep the later mark so time already decayed over is not decayed
+        // again once the clock catches up
+        self.last_cleanup = self.last_cleanup.max(current_epoch);
         aged_entries
     }
 
@@ -143,74 +954,247 @@ impl Stage1 {
         let epochs: Vec<u32> = self.entries.keys().cloned().collect();
         
         for &source_epoch in &epochs {
-            let mut best_matches = Vec::new();
-            let source_token = self.entries[&source_epoch].token();
-
-            // Find similar memories
-            for &target_epoch in &epochs {
-                if source_epoch != target_epoch {
-                    let target_token = self.entries[&target_epoch].token();
-                    let similarity = Self::calculate_similarity(source_token, target_token);
-                    
-                    if similarity >= self.config.similarity_threshold {
-                        best_matches.push((target_epoch, similarity));
-                    }
-                }
-            }
-
-            // Sort by similarity and update links
-            best_matches.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+            let best_matches = self.suggest_links(source_epoch, self.config.max_auto_links);
             if let Some(entry) = self.entries.get_mut(&source_epoch) {
-                let link1 = best_matches.get(0).map(|&(epoch, _)| epoch).unwrap_or(0);
+                let link1 = best_matches.first().map(|&(epoch, _)| epoch).unwrap_or(0);
                 let link2 = best_matches.get(1).map(|&(epoch, _)| epoch).unwrap_or(0);
                 entry.update_links(link1, link2);
             }
+
+            let overflow: Vec<u32> = best_matches.iter().skip(2).map(|&(epoch, _)| epoch).collect();
+            if overflow.is_empty() {
+                self.extra_links.remove(&source_epoch);
+            } else {
+                self.extra_links.insert(source_epoch, overflow);
+            }
         }
     }
 
-    /// Calculate similarity between two tokens (simple example)
-    fn calculate_similarity(token1: u16, token2: u16) -> f32 {
-        // This is a simple example - replace with your similarity metric
-        let diff = (token1 as i32 - token2 as i32).abs();
-        let max_diff = u16::MAX as i32;
-        1.0 - (diff as f32 / max_diff as f32)
+    /// Ranks the memories `update_automatic_links` would link `epoch` to,
+    /// most similar first, without changing anything
+    ///
+    /// Only memories at or above the similarity threshold are suggested.
+    /// Ties are broken by epoch; an unknown epoch gets no suggestions. A
+    /// metric returning NaN is read as negative infinity, the lowest score.
+    pub fn suggest_links(&self, epoch: u32, limit: usize) -> Vec<(u32, f32)> {
+        let Some(source) = self.entries.get(&epoch) else {
+            return Vec::new();
+        };
+
+        let mut candidates: Vec<(u32, f32)> = self.entries
+            .values()
+            .filter(|target| target.epoch() != epoch)
+            .map(|target| {
+                let similarity = self.metric.similarity(source.token(), target.token());
+                (target.epoch(), if similarity.is_nan() { f32::NEG_INFINITY } else { similarity })
+            })
+            .filter(|&(_, similarity)| similarity >= self.config.similarity_threshold)
+            .collect();
+
+        candidates.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        candidates.truncate(limit);
+        candidates
+    }
+
+    /// Picks up to `n` distinct epochs, each with probability proportional
+    /// to its weight, most likely first
+    ///
+    /// Entries with zero or negative weight are never picked. Draws are
+    /// made in epoch order, so a seeded `rng` gives repeatable samples.
+    pub fn sample_weighted(&self, n: usize, rng: &mut impl Rng) -> Vec<u32> {
+        let mut candidates: Vec<(u32, i16)> = self.entries
+            .values()
+            .filter(|entry| entry.weight() > 0)
+            .map(|entry| (entry.epoch(), entry.weight()))
+            .collect();
+        candidates.sort_unstable();
+
+        // Efraimidis-Spirakis: the `n` largest keys u^(1/w) form a weighted
+        // sample without replacement
+        let mut keyed: Vec<(f64, u32)> = candidates
+            .into_iter()
+            .map(|(epoch, weight)| (rng.gen::<f64>().powf(1.0 / weight as f64), epoch))
+            .collect();
+        keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+        keyed.truncate(n);
+        keyed.into_iter().map(|(_, epoch)| epoch).collect()
+    }
+
+    /// Returns the `p`-th percentile weight (nearest rank) over the current
+    /// entries, with `p` clamped to 0-100
+    ///
+    /// Returns zero when there are no entries.
+    pub fn weight_percentile(&self, p: f32) -> i16 {
+        let mut weights: Vec<i16> = self.entries.values().map(|e| e.weight()).collect();
+        if weights.is_empty() {
+            return 0;
+        }
+        weights.sort_unstable();
+
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * weights.len() as f32).ceil() as usize;
+        weights[rank.clamp(1, weights.len()) - 1]
+    }
+
+    /// Returns an entry's weight as a fraction of the heaviest entry's
+    ///
+    /// Negative weights count as zero, and so does everything when no
+    /// entry has a positive weight.
+    pub fn normalized_weight(&self, epoch: u32) -> Option<f32> {
+        let weight = self.entries.get(&epoch)?.weight().max(0);
+        let max = self.entries.values().map(|e| e.weight()).max().unwrap_or(0);
+        if max <= 0 {
+            return Some(0.0);
+        }
+        Some(weight as f32 / max as f32)
     }
 
     /// Returns statistics about the current memory state
+    ///
+    /// Averages are reported as zero when there are no entries.
     pub fn stats(&self) -> Stage1Stats {
-        let current_epoch = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
+        let current_epoch = self.config.clock.now_epoch();
+        // Sums over an empty map are zero, so this keeps the averages finite
+        let count = self.entries.len().max(1) as f32;
 
         Stage1Stats {
             total_entries: self.entries.len(),
             avg_weight: self.entries.values()
                 .map(|e| e.weight() as f32)
-                .sum::<f32>() / self.entries.len() as f32,
+                .sum::<f32>() / count,
             avg_age: self.entries.values()
-                .map(|e| e.age_from(current_epoch) as f32)
-                .sum::<f32>() / self.entries.len() as f32,
+                .map(|e| age_of(&self.added_at, e, current_epoch) as f32)
+                .sum::<f32>() / count,
             linked_entries: self.entries.values()
                 .filter(|e| e.links() != (0, 0))
                 .count(),
+            weight_histogram: WeightHistogram::from_weights(
+                &self.config.weight_histogram_edges,
+                self.entries.values().map(|e| e.weight()),
+            ),
         }
     }
 }
 
+/// A `Stage1` that can be shared between threads
+///
+/// Lookups take a read lock and run concurrently; additions, linking and
+/// maintenance take the write lock.
+#[derive(Default)]
+pub struct ConcurrentStage1 {
+    inner: RwLock<Stage1>,
+}
+
+impl ConcurrentStage1 {
+    pub fn new(stage1: Stage1) -> Self {
+        Self { inner: RwLock::new(stage1) }
+    }
+
+    /// Adds a new memory entry, returning its unique epoch
+    pub fn add_memory(&self, token: u16, weight: i16) -> u32 {
+        self.inner.write().add_memory(token, weight)
+    }
+
+    /// Adds a memory unless its token is over the rate limit
+    pub fn try_add_memory(&self, token: u16, weight: i16) -> AddOutcome {
+        self.inner.write().try_add_memory(token, weight)
+    }
+
+    /// Adds a batch of memories under one lock, returning their epochs
+    pub fn add_memories(&self, items: &[(u16, i16)]) -> Vec<u32> {
+        self.inner.write().add_memories(items)
+    }
+
+    /// Returns a recent memory's epoch for `token`, or adds one
+    pub fn get_or_insert(&self, token: u16, weight: i16, dedup_window_seconds: u32) -> u32 {
+        self.inner.write().get_or_insert(token, weight, dedup_window_seconds)
+    }
+
+    /// Pins a memory so maintenance and the capacity limit never touch it
+    pub fn pin(&self, epoch: u32) -> Result<(), Stage1Error> {
+        self.inner.write().pin(epoch)
+    }
+
+    /// Lets a pinned memory decay and age out again
+    pub fn unpin(&self, epoch: u32) -> Result<(), Stage1Error> {
+        self.inner.write().unpin(epoch)
+    }
+
+    /// Merges same-token memories stored close together
+    pub fn consolidate(&self, window_seconds: u32) -> usize {
+        self.inner.write().consolidate(window_seconds)
+    }
+
+    /// Returns the memories evicted by the capacity limit since the last
+    /// call
+    pub fn take_overflow(&self) -> Vec<MemoryEntry> {
+        self.inner.write().take_overflow()
+    }
+
+    /// Retrieves a copy of the memory stored at `epoch`
+    pub fn get_memory(&self, epoch: u32) -> Result<MemoryEntry, Stage1Error> {
+        self.inner.read().get_memory(epoch).cloned()
+    }
+
+    /// Links two memories together
+    pub fn link_memories(&self, source_epoch: u32, link1: u32, link2: u32) -> Result<(), Stage1Error> {
+        self.inner.write().link_memories(source_epoch, link1, link2)
+    }
+
+    /// Performs memory cleanup and weight decay
+    pub fn maintain(&self) -> Vec<MemoryEntry> {
+        self.inner.write().maintain()
+    }
+
+    /// Attempts to find and create links between similar memories
+    pub fn update_automatic_links(&self) {
+        self.inner.write().update_automatic_links()
+    }
+
+    /// Ranks candidate links for a memory without applying them
+    pub fn suggest_links(&self, epoch: u32, limit: usize) -> Vec<(u32, f32)> {
+        self.inner.read().suggest_links(epoch, limit)
+    }
+
+    /// Returns statistics about the current memory state
+    pub fn stats(&self) -> Stage1Stats {
+        self.inner.read().stats()
+    }
+
+    /// Unwraps the underlying `Stage1`
+    pub fn into_inner(self) -> Stage1 {
+        self.inner.into_inner()
+    }
+}
+
+/// Seconds since `entry` was added, which for a memory a burst pushed to a
+/// later epoch is measured from its entry in `added_at`
+fn age_of(added_at: &HashMap<u32, u32>, entry: &MemoryEntry, now: u32) -> u32 {
+    let added = added_at.get(&entry.epoch()).copied().unwrap_or(entry.epoch());
+    now.saturating_sub(added)
+}
+
+/// Scales `weight` by `factor` in a wider type and clamps the result back
+/// into `i16`, so extreme weights can neither wrap nor flip sign
+fn decayed_weight(weight: i16, factor: f32) -> i16 {
+    let scaled = (f32::from(weight) * factor) as i32;
+    scaled.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16
+}
+
 #[derive(Debug)]
 pub struct Stage1Stats {
     pub total_entries: usize,
     pub avg_weight: f32,
     pub avg_age: f32,
     pub linked_entries: usize,
+    pub weight_histogram: WeightHistogram,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread::sleep;
-    use std::time::Duration;
+    use crate::memory::clock::MockClock;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[test]
     fn test_memory_storage_and_retrieval() {
@@ -236,15 +1220,22 @@ mod tests {
 
     #[test]
     fn test_memory_decay() {
-        let mut stage1 = Stage1::new();
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            clock: clock.clone(),
+            ..Stage1Config::default()
+        });
         let epoch = stage1.add_memory(123, 1000);
-        
-        // Force decay
-        sleep(Duration::from_secs(1));
-        let aged = stage1.maintain();
-        
+        assert_eq!(epoch, 1_000_000);
+
+        // No time passing means no decay; an hour costs the hourly 5%
+        stage1.maintain();
+        assert_eq!(stage1.get_memory(epoch).unwrap().weight(), 1000);
+        clock.advance(3600);
+        stage1.maintain();
+
         let entry = stage1.get_memory(epoch).unwrap();
-        assert!(entry.weight() < 1000, "Weight should decay over time");
+        assert_eq!(entry.weight(), 950, "Weight should decay over time");
     }
 
     #[test]
@@ -252,7 +1243,7 @@ mod tests {
         let mut stage1 = Stage1::new();
         let epoch1 = stage1.add_memory(100, 1000);
         let epoch2 = stage1.add_memory(101, 1000);  // Similar token
-        let epoch3 = stage1.add_memory(500, 1000);  // Different token
+        let _epoch3 = stage1.add_memory(500, 1000);  // Different token
         
         stage1.update_automatic_links();
         
@@ -260,4 +1251,790 @@ mod tests {
         let (link1, _) = entry1.links();
         assert_eq!(link1, epoch2, "Should link to similar token");
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_stats_on_empty_stage1() {
+        let stats = Stage1::new().stats();
+        assert_eq!(stats.total_entries, 0);
+        assert_eq!(stats.avg_weight, 0.0);
+        assert_eq!(stats.avg_age, 0.0);
+        assert_eq!(stats.linked_entries, 0);
+    }
+
+    #[test]
+    fn test_custom_similarity_metric() {
+        // Links only tokens 100 and 60000, which the distance metric would
+        // consider unrelated
+        struct PairMetric;
+        impl SimilarityMetric for PairMetric {
+            fn similarity(&self, a: u16, b: u16) -> f32 {
+                if (a, b) == (100, 60000) || (a, b) == (60000, 100) { 1.0 } else { 0.0 }
+            }
+        }
+
+        let mut stage1 = Stage1::with_metric(PairMetric);
+        let epoch1 = stage1.add_memory(100, 1000);
+        let epoch2 = stage1.add_memory(60000, 1000);
+
+        stage1.update_automatic_links();
+
+        assert_eq!(stage1.get_memory(epoch1).unwrap().links(), (epoch2, 0));
+        assert_eq!(stage1.get_memory(epoch2).unwrap().links(), (epoch1, 0));
+    }
+
+    #[test]
+    fn test_cosine_token_metric() {
+        let embeddings: HashMap<u16, Vec<f32>> = [
+            (1, vec![1.0, 0.0]),
+            (2, vec![2.0, 0.0]),
+            (3, vec![0.0, 1.0]),
+        ].into_iter().collect();
+        let metric = CosineTokenMetric::new(embeddings);
+
+        assert!((metric.similarity(1, 2) - 1.0).abs() < 1e-6);
+        assert!(metric.similarity(1, 3).abs() < 1e-6);
+        assert_eq!(metric.similarity(1, 4), 0.0);
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() -> Result<(), Stage1Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("stage1.bin");
+
+        let mut stage1 = Stage1::new();
+        let epoch1 = stage1.add_memory(100, 1000);
+        let epoch2 = stage1.add_memory(200, 2000);
+        stage1.link_memories(epoch2, epoch1, 0)?;
+
+        stage1.snapshot_to(&path)?;
+        let restored = Stage1::restore_from(&path, Stage1Config::default())?;
+
+        assert_eq!(restored.current_epoch, stage1.current_epoch);
+        assert_eq!(restored.last_cleanup, stage1.last_cleanup);
+        assert_eq!(restored.entries.len(), 2);
+        for epoch in [epoch1, epoch2] {
+            let original = stage1.get_memory(epoch)?;
+            let copy = restored.get_memory(epoch)?;
+            assert_eq!(copy.token(), original.token());
+            assert_eq!(copy.weight(), original.weight());
+            assert_eq!(copy.links(), original.links());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_negative_weight_dropped_by_maintain() {
+        let mut stage1 = Stage1::new();
+        let epoch = stage1.add_memory(123, -1000);
+        assert_eq!(stage1.get_memory(epoch).unwrap().weight(), -1000);
+
+        let aged = stage1.maintain();
+        assert!(stage1.get_memory(epoch).is_err());
+        assert_eq!(aged.len(), 1);
+        assert!(aged[0].weight() < 0);
+    }
+
+    #[test]
+    fn test_burst_of_memories_gets_unique_epochs() {
+        let mut stage1 = Stage1::new();
+        let epochs: Vec<u32> = (0..1000)
+            .map(|i| stage1.add_memory(i as u16, 1000))
+            .collect();
+
+        assert_eq!(stage1.stats().total_entries, 1000);
+        for (i, &epoch) in epochs.iter().enumerate() {
+            assert_eq!(stage1.get_memory(epoch).unwrap().token(), i as u16);
+        }
+        assert!(epochs.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_burst_ages_from_wall_clock() -> Result<(), Stage1Error> {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let config = Stage1Config {
+            clock: clock.clone(),
+            ..Stage1Config::default()
+        };
+        let mut stage1 = Stage1::with_config(config.clone());
+        stage1.add_memories(&vec![(7, 1000); 10_000]);
+        let epochs: Vec<u32> = (0..1000).map(|i| stage1.add_memory(i as u16, 1000)).collect();
+
+        // The burst runs ahead of the clock but ages as if added at once
+        assert_eq!(*epochs.last().unwrap(), 1_010_999);
+        clock.advance(3600);
+        assert_eq!(stage1.get_aged_memories(3600).len(), 11_000);
+        assert_eq!(stage1.stats().avg_age, 3600.0);
+        stage1.maintain();
+        assert!(stage1.entries.values().all(|entry| entry.weight() == 950));
+
+        // The add times survive a snapshot
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("stage1.bin");
+        stage1.snapshot_to(&path)?;
+        let mut restored = Stage1::restore_from(&path, config)?;
+
+        // Past max_age the whole burst leaves together
+        clock.advance(3600 * 24);
+        assert_eq!(restored.maintain().len(), 11_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_add_memories() {
+        let mut stage1 = Stage1::new();
+        let first = stage1.add_memory(1, 100);
+        let items: Vec<(u16, i16)> = (0..10_000).map(|i| (i as u16, (i % 500) as i16)).collect();
+        let epochs = stage1.add_memories(&items);
+
+        assert_eq!(epochs.len(), items.len());
+        assert!(epochs[0] > first);
+        assert!(epochs.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(stage1.stats().total_entries, 10_001);
+        for (&epoch, &(token, weight)) in epochs.iter().zip(&items) {
+            let entry = stage1.get_memory(epoch).unwrap();
+            assert_eq!((entry.token(), entry.weight()), (token, weight));
+        }
+        assert!(stage1.add_memory(2, 100) > epochs[epochs.len() - 1]);
+
+        // Existing memories make room before the batch does
+        let mut capped = Stage1::with_config(Stage1Config {
+            max_entries: Some(3),
+            ..Stage1Config::default()
+        });
+        let old = capped.add_memory(100, 5000);
+        let batch = capped.add_memories(&[(101, 10), (102, 20), (103, 30)]);
+        assert_eq!(capped.take_overflow().iter().map(|e| e.epoch()).collect::<Vec<_>>(), vec![old]);
+        assert!(batch.iter().all(|&epoch| capped.get_memory(epoch).is_ok()));
+        let epochs = capped.add_memories(&[(104, 1), (105, 2), (106, 3), (107, 4)]);
+        assert_eq!(capped.take_overflow().len(), 4);
+        assert!(capped.get_memory(epochs[0]).is_err());
+        assert!(epochs[1..].iter().all(|&epoch| capped.get_memory(epoch).is_ok()));
+    }
+
+    #[test]
+    fn test_consolidate_merges_runs() -> Result<(), Stage1Error> {
+        let clock = Arc::new(MockClock::new(1000));
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            clock: clock.clone(),
+            ..Stage1Config::default()
+        });
+
+        let other = stage1.add_memory(9, 100);
+        let first = stage1.add_memory(7, 20_000);
+        let second = stage1.add_memory(7, 15_000);
+        clock.advance(5);
+        let third = stage1.add_memory(7, -500);
+        clock.advance(100);
+        let later = stage1.add_memory(7, 300);
+
+        stage1.add_link(second, other)?;
+        stage1.add_link(third, later)?;
+        stage1.add_link(other, third)?;
+        stage1.add_link(later, first)?;
+
+        assert_eq!(stage1.consolidate(10), 2);
+        assert_eq!(stage1.consolidate(10), 0);
+
+        let epochs: Vec<u32> = stage1.find_by_token(7).iter().map(|e| e.epoch()).collect();
+        assert_eq!(epochs, vec![first, later]);
+        assert_eq!(stage1.get_memory(first)?.weight(), i16::MAX);
+        assert_eq!(stage1.all_links(first)?, vec![other, later]);
+        // Links to merged memories now reach the survivor, without duplicates
+        assert_eq!(stage1.all_links(other)?, vec![first]);
+        assert_eq!(stage1.all_links(later)?, vec![first]);
+        assert!(stage1.get_memory(second).is_err());
+        assert!(stage1.get_memory(third).is_err());
+        assert!(stage1.validate_links().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_stage1() {
+        let stage1 = std::sync::Arc::new(ConcurrentStage1::default());
+        let seeded: Vec<u32> = (0..10).map(|i| stage1.add_memory(i, 1000)).collect();
+
+        let mut handles = Vec::new();
+        for writer in 0..2u16 {
+            let stage1 = stage1.clone();
+            handles.push(std::thread::spawn(move || {
+                for i in 0..100 {
+                    stage1.add_memory(writer * 1000 + i, 1000);
+                }
+            }));
+        }
+        for _ in 0..4 {
+            let stage1 = stage1.clone();
+            let seeded = seeded.clone();
+            handles.push(std::thread::spawn(move || {
+                for _ in 0..50 {
+                    for &epoch in &seeded {
+                        assert!(stage1.get_memory(epoch).is_ok());
+                    }
+                    assert!(stage1.stats().total_entries >= seeded.len());
+                }
+            }));
+        }
+        {
+            let stage1 = stage1.clone();
+            handles.push(std::thread::spawn(move || {
+                for _ in 0..20 {
+                    // Nothing is old or light enough to be dropped yet
+                    assert!(stage1.maintain().is_empty());
+                }
+            }));
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(stage1.stats().total_entries, 210);
+    }
+
+    #[test]
+    fn test_decay_schedules() {
+        let exponential = ExponentialDecay::new(0.5);
+        assert!((exponential.factor(0, 7200) - 0.25).abs() < 1e-6);
+
+        let linear = LinearDecay::new(0.1);
+        assert!((linear.factor(0, 7200) - 0.8).abs() < 1e-6);
+        assert_eq!(linear.factor(0, 3600 * 20), 0.0);
+
+        let step = StepDecay::new(vec![(3600, 0.5), (0, 0.9)]);
+        assert!((step.factor(10, 3600) - 0.9).abs() < 1e-6);
+        assert!((step.factor(7200, 3600) - 0.5).abs() < 1e-6);
+        assert_eq!(StepDecay::new(vec![(3600, 0.5)]).factor(10, 3600), 1.0);
+    }
+
+    #[test]
+    fn test_step_decay_retains_young_memories() {
+        let exponential = Stage1Config {
+            decay_schedule: Arc::new(ExponentialDecay::new(0.5)),
+            ..Stage1Config::default()
+        };
+        // Memories under a day old keep their weight
+        let step = Stage1Config {
+            decay_schedule: Arc::new(StepDecay::new(vec![(0, 1.0), (86400, 0.5)])),
+            ..Stage1Config::default()
+        };
+
+        let mut weights = Vec::new();
+        for config in [exponential, step] {
+            let mut stage1 = Stage1::with_config(config);
+            let epoch = stage1.add_memory(123, 1000);
+
+            // Pretend the last pass ran two hours ago
+            stage1.last_cleanup -= 7200;
+            stage1.maintain();
+            weights.push(stage1.get_memory(epoch).unwrap().weight());
+        }
+
+        assert_eq!(weights, vec![250, 1000]);
+    }
+
+    #[test]
+    fn test_expired_memory_dropped_despite_weight() {
+        let mut stage1 = Stage1::new();
+        let expiring = stage1.add_memory(123, 30000);
+        let lasting = stage1.add_memory(124, 30000);
+        let now = stage1.get_memory(expiring).unwrap().epoch();
+        let entry = stage1.entries[&expiring].clone().with_expiry(now);
+        stage1.entries.insert(expiring, entry);
+
+        let aged = stage1.maintain();
+        assert!(aged.is_empty(), "expired memories are not handed to Stage 2");
+        assert!(stage1.get_memory(expiring).is_err());
+        assert!(stage1.find_by_token(123).is_empty());
+        assert_eq!(stage1.get_memory(lasting).unwrap().weight(), 30000);
+    }
+
+    #[test]
+    fn test_link_bidirectional() -> Result<(), Stage1Error> {
+        let mut stage1 = Stage1::new();
+        let a = stage1.add_memory(100, 1000);
+        let b = stage1.add_memory(200, 1000);
+        let c = stage1.add_memory(300, 1000);
+        let d = stage1.add_memory(400, 1000);
+
+        stage1.link_bidirectional(a, b)?;
+        assert_eq!(stage1.get_memory(a)?.links(), (b, 0));
+        assert_eq!(stage1.get_memory(b)?.links(), (a, 0));
+
+        // Relinking is a no-op; a second partner fills the next slot
+        stage1.link_bidirectional(b, a)?;
+        stage1.link_bidirectional(a, c)?;
+        assert_eq!(stage1.get_memory(a)?.links(), (b, c));
+        assert_eq!(stage1.get_memory(c)?.links(), (a, 0));
+
+        // `a` is full, so `d` must stay untouched
+        assert!(matches!(stage1.link_bidirectional(d, a), Err(Stage1Error::NoFreeLink(x)) if x == a));
+        assert_eq!(stage1.get_memory(d)?.links(), (0, 0));
+        assert!(matches!(stage1.link_bidirectional(a, 1), Err(Stage1Error::InvalidLink(1))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_links_beyond_two_slots() -> Result<(), Stage1Error> {
+        let mut stage1 = Stage1::new();
+        let source = stage1.add_memory(100, 1000);
+        let targets: Vec<u32> = (0..5).map(|i| stage1.add_memory(200 + i, 1000)).collect();
+
+        for &target in &targets {
+            stage1.add_link(source, target)?;
+        }
+        stage1.add_link(source, targets[3])?;
+
+        assert_eq!(stage1.get_memory(source)?.links(), (targets[0], targets[1]));
+        assert_eq!(stage1.all_links(source)?, targets);
+        assert!(matches!(stage1.add_link(source, 42), Err(Stage1Error::InvalidLink(42))));
+
+        // Overflow links survive a snapshot round trip
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("stage1.bin");
+        stage1.snapshot_to(&path)?;
+        let restored = Stage1::restore_from(&path, Stage1Config::default())?;
+        assert_eq!(restored.all_links(source)?, targets);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_automatic_links_fill_overflow() -> Result<(), Stage1Error> {
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            max_auto_links: 4,
+            ..Stage1Config::default()
+        });
+        let source = stage1.add_memory(100, 1000);
+        for token in 101..=105 {
+            stage1.add_memory(token, 1000);
+        }
+
+        stage1.update_automatic_links();
+        let links = stage1.all_links(source)?;
+        let tokens: Vec<u16> = links.iter()
+            .map(|&epoch| stage1.get_memory(epoch).map(MemoryEntry::token))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(tokens, vec![101, 102, 103, 104]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_capacity_limit_evicts_lightest() {
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            max_entries: Some(3),
+            ..Stage1Config::default()
+        });
+
+        let light = stage1.add_memory(100, 200);
+        let heavy = stage1.add_memory(101, 5000);
+        let lighter = stage1.add_memory(102, 150);
+        assert!(stage1.take_overflow().is_empty());
+
+        let newest = stage1.add_memory(103, 1000);
+        let next = stage1.add_memory(104, 100);
+        assert_eq!(stage1.stats().total_entries, 3);
+
+        let overflow: Vec<u32> = stage1.take_overflow().iter().map(|e| e.epoch()).collect();
+        assert_eq!(overflow, vec![lighter, light]);
+        assert!(stage1.take_overflow().is_empty());
+        assert!(stage1.find_by_token(102).is_empty());
+        for epoch in [heavy, newest, next] {
+            assert!(stage1.get_memory(epoch).is_ok());
+        }
+
+        // Anything not taken yet is handed over by the next maintenance pass
+        stage1.add_memory(105, 3000);
+        let aged = stage1.maintain();
+        assert!(aged.iter().any(|e| e.epoch() == next));
+        assert!(stage1.stats().total_entries <= 3);
+    }
+
+    #[test]
+    fn test_weight_percentiles() {
+        let mut stage1 = Stage1::new();
+        assert_eq!(stage1.weight_percentile(50.0), 0);
+
+        let only = stage1.add_memory(100, 400);
+        assert_eq!(stage1.weight_percentile(50.0), 400);
+        assert_eq!(stage1.normalized_weight(only), Some(1.0));
+
+        let epochs: Vec<u32> = [500, 100, 300, 200]
+            .into_iter()
+            .map(|weight| stage1.add_memory(100, weight))
+            .collect();
+        assert_eq!(stage1.weight_percentile(50.0), 300);
+        assert_eq!(stage1.weight_percentile(0.0), 100);
+        assert_eq!(stage1.weight_percentile(100.0), 500);
+        assert_eq!(stage1.weight_percentile(250.0), 500);
+
+        assert_eq!(stage1.normalized_weight(epochs[0]), Some(1.0));
+        assert_eq!(stage1.normalized_weight(epochs[1]), Some(0.2));
+        assert_eq!(stage1.normalized_weight(1), None);
+    }
+
+    #[test]
+    fn test_dangling_links_reported_and_pruned() {
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            max_auto_links: 0,
+            ..Stage1Config::default()
+        });
+        let source = stage1.add_memory(100, 5000);
+        let target = stage1.add_memory(101, 50);
+        let kept = stage1.add_memory(102, 5000);
+        stage1.link_memories(source, target, kept).unwrap();
+        assert!(stage1.validate_links().is_empty());
+
+        // The light target is handed to Stage 2 by maintenance
+        let aged = stage1.maintain();
+        assert_eq!(aged.len(), 1);
+        assert_eq!(stage1.validate_links(), vec![(source, target)]);
+        assert!(stage1.dangling_links(|epoch| epoch == target || epoch == kept).is_empty());
+
+        assert_eq!(stage1.prune_dangling_links(), 1);
+        assert!(stage1.validate_links().is_empty());
+        assert_eq!(stage1.get_memory(source).unwrap().links(), (0, kept));
+    }
+
+    #[test]
+    fn test_recall_similar_ranking() {
+        let mut stage1 = Stage1::new();
+        let far = stage1.add_memory(1000, 500);
+        let below = stage1.add_memory(90, 500);
+        let exact = stage1.add_memory(100, 500);
+        let above = stage1.add_memory(110, 500);
+        let near = stage1.add_memory(101, 500);
+
+        let ranked: Vec<u32> = stage1.recall_similar(100, 10).iter().map(|&(epoch, _)| epoch).collect();
+        assert_eq!(ranked, vec![exact, near, below, above, far]);
+
+        let top = stage1.recall_similar(100, 2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0], (exact, 1.0));
+        assert!(top[1].1 < 1.0);
+        assert!(Stage1::new().recall_similar(100, 5).is_empty());
+    }
+
+    #[test]
+    fn test_epochs_relative_to_seed() {
+        let unix_now = MemoryClock::default().now();
+        let clock = MemoryClock::new(unix_now - 1000);
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            clock: Arc::new(clock),
+            ..Stage1Config::default()
+        });
+
+        let epoch = stage1.add_memory(100, 1000);
+        assert!((1000..1005).contains(&epoch));
+
+        // Timestamps land back on the wall clock, and ages stay near zero
+        let entry = stage1.get_memory(epoch).unwrap();
+        let drift = std::time::SystemTime::now()
+            .duration_since(entry.timestamp(&clock))
+            .unwrap_or_default();
+        assert!(drift.as_secs() < 5);
+        assert!(stage1.stats().avg_age < 5.0);
+        assert!(stage1.get_aged_memories(500).is_empty());
+        assert!(stage1.maintain().is_empty());
+    }
+
+    #[test]
+    fn test_links_fade_with_their_targets() -> Result<(), Stage1Error> {
+        #[derive(Debug)]
+        struct Halve;
+        impl DecaySchedule for Halve {
+            fn factor(&self, _age_seconds: u32, _elapsed_seconds: u32) -> f32 {
+                0.5
+            }
+        }
+
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            min_weight: 0,
+            decay_schedule: Arc::new(Halve),
+            link_strength_floor: 0.1,
+            ..Stage1Config::default()
+        });
+        let source = stage1.add_memory(100, 10000);
+        let target = stage1.add_memory(200, 10000);
+        stage1.link_memories(source, target, 0)?;
+        assert_eq!(stage1.link_strength(source, target), Some(1.0));
+
+        for expected in [0.5, 0.25, 0.125] {
+            assert!(stage1.maintain().is_empty());
+            assert_eq!(stage1.link_strength(source, target), Some(expected));
+            assert_eq!(stage1.get_memory(source)?.links(), (target, 0));
+        }
+
+        // Below the floor the link is gone, though both memories remain
+        stage1.maintain();
+        assert_eq!(stage1.link_strength(source, target), None);
+        assert_eq!(stage1.get_memory(source)?.links(), (0, 0));
+        assert!(stage1.get_memory(target).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_or_insert_dedups_within_window() {
+        let mut stage1 = Stage1::new();
+
+        let first = stage1.get_or_insert(100, 1000, 60);
+        assert_eq!(stage1.get_or_insert(100, 2000, 60), first);
+        assert_eq!(stage1.find_by_token(100).len(), 1);
+        assert_eq!(stage1.get_memory(first).unwrap().weight(), 1000);
+
+        // Another token, or an empty window, always adds
+        assert_ne!(stage1.get_or_insert(101, 1000, 60), first);
+        let second = stage1.get_or_insert(100, 1000, 0);
+        assert_ne!(second, first);
+        assert_eq!(stage1.find_by_token(100).len(), 2);
+        assert_eq!(stage1.get_or_insert(100, 1000, 60), second);
+    }
+
+    #[test]
+    fn test_sample_weighted_follows_weights() {
+        let mut stage1 = Stage1::new();
+        let weights = [1000, 2000, 3000, 4000];
+        let epochs: Vec<u32> = weights.iter().map(|&w| stage1.add_memory(100, w)).collect();
+        let excluded = [stage1.add_memory(101, 0), stage1.add_memory(102, -500)];
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut counts = HashMap::new();
+        let rounds = 20_000;
+        for _ in 0..rounds {
+            for epoch in stage1.sample_weighted(1, &mut rng) {
+                *counts.entry(epoch).or_insert(0) += 1;
+            }
+        }
+
+        let total: i16 = weights.iter().sum();
+        for (epoch, weight) in epochs.iter().zip(weights) {
+            let observed = counts[epoch] as f32 / rounds as f32;
+            let expected = weight as f32 / total as f32;
+            assert!((observed - expected).abs() < 0.02, "epoch {} drawn {:.3}, expected {:.3}", epoch, observed, expected);
+        }
+
+        // Without replacement: asking for more than exist returns each once
+        let mut all = stage1.sample_weighted(10, &mut rng);
+        all.sort_unstable();
+        assert_eq!(all, epochs);
+        assert!(excluded.iter().all(|epoch| !counts.contains_key(epoch)));
+    }
+
+    #[test]
+    fn test_concepts_through_dictionary_codec() -> Result<(), Stage1Error> {
+        let codec = Arc::new(crate::memory::token_codec::DictionaryCodec::new());
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            codec: codec.clone(),
+            ..Stage1Config::default()
+        });
+
+        let coffee = stage1.add_concept("coffee", 1000);
+        let rain = stage1.add_concept("rain", 800);
+        let more_coffee = stage1.add_concept("coffee", 500);
+
+        let found: Vec<u32> = stage1.find_by_concept("coffee").iter().map(|e| e.epoch()).collect();
+        assert_eq!(found, vec![coffee, more_coffee]);
+        assert_eq!(stage1.concept(rain)?.as_deref(), Some("rain"));
+        assert_eq!(stage1.get_memory(rain)?.token(), codec.encode("rain"));
+        assert!(stage1.find_by_concept("snow").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backward_clock_skips_decay() {
+        let clock = Arc::new(MockClock::new(10_000));
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            clock: clock.clone(),
+            ..Stage1Config::default()
+        });
+        let epoch = stage1.add_memory(100, 1000);
+
+        clock.set(5_000);
+        assert!(stage1.maintain().is_empty());
+        assert_eq!(stage1.get_memory(epoch).unwrap().weight(), 1000);
+
+        // Back at the last cleanup no time has passed either
+        clock.set(10_000);
+        stage1.maintain();
+        assert_eq!(stage1.get_memory(epoch).unwrap().weight(), 1000);
+
+        clock.advance(3600);
+        stage1.maintain();
+        assert_eq!(stage1.get_memory(epoch).unwrap().weight(), 950);
+    }
+
+    #[test]
+    fn test_nan_similarity_ranks_lowest() -> Result<(), Stage1Error> {
+        struct NanForOdd;
+        impl SimilarityMetric for NanForOdd {
+            fn similarity(&self, _a: u16, b: u16) -> f32 {
+                if b % 2 == 1 { f32::NAN } else { 0.5 }
+            }
+        }
+
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            similarity_threshold: f32::NEG_INFINITY,
+            max_auto_links: 3,
+            ..Stage1Config::default()
+        });
+        stage1.metric = Box::new(NanForOdd);
+        let source = stage1.add_memory(0, 500);
+        let odd: Vec<u32> = [1, 3].iter().map(|&token| stage1.add_memory(token, 500)).collect();
+        let even: Vec<u32> = [2, 4].iter().map(|&token| stage1.add_memory(token, 500)).collect();
+
+        // Equal scores fall back to epoch order, and NaN sorts after them
+        let suggestions = stage1.suggest_links(source, 10);
+        let ranked: Vec<u32> = suggestions.iter().map(|&(epoch, _)| epoch).collect();
+        assert_eq!(ranked, [even.clone(), odd.clone()].concat());
+        assert!(suggestions[2..].iter().all(|&(_, similarity)| similarity == f32::NEG_INFINITY));
+        assert_eq!(stage1.suggest_links(source, 10), suggestions);
+
+        stage1.update_automatic_links();
+        assert_eq!(stage1.all_links(source)?, vec![even[0], even[1], odd[0]]);
+
+        // Any finite threshold keeps NaN scores out entirely
+        stage1.config.similarity_threshold = 0.0;
+        assert_eq!(stage1.suggest_links(source, 10).len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_suggest_links_matches_automatic_links() -> Result<(), Stage1Error> {
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            similarity_threshold: 0.999,
+            ..Stage1Config::default()
+        });
+        let source = stage1.add_memory(1000, 500);
+        let near = stage1.add_memory(1001, 500);
+        let nearer = stage1.add_memory(1000, 500);
+        let farther = stage1.add_memory(1020, 500);
+        stage1.add_memory(9000, 500);
+
+        let suggestions = stage1.suggest_links(source, 10);
+        let ranked: Vec<u32> = suggestions.iter().map(|&(epoch, _)| epoch).collect();
+        assert_eq!(ranked, vec![nearer, near, farther]);
+        assert!(suggestions.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert_eq!(stage1.get_memory(source)?.links(), (0, 0));
+        assert!(stage1.suggest_links(12345, 10).is_empty());
+
+        stage1.update_automatic_links();
+        assert_eq!(stage1.get_memory(source)?.links(), (ranked[0], ranked[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_weight_histogram() {
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            weight_histogram_edges: vec![500, 0],
+            ..Stage1Config::default()
+        });
+        for weight in [-20, 0, 10, 499, 500, 501, 3000] {
+            stage1.add_memory(100, weight);
+        }
+
+        let histogram = stage1.stats().weight_histogram;
+        assert_eq!(histogram.edges, vec![0, 500]);
+        assert_eq!(histogram.counts, vec![1, 3, 3]);
+        assert_eq!(histogram.total(), 7);
+
+        let default = Stage1::new().stats().weight_histogram;
+        assert_eq!(default.counts, vec![0; DEFAULT_WEIGHT_EDGES.len() + 1]);
+    }
+
+    #[test]
+    fn test_rate_limit_per_token() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let limit = RateLimit {
+            max_per_window: 3,
+            window_seconds: 60,
+            action: RateLimitAction::Reject,
+        };
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            clock: clock.clone(),
+            rate_limit: Some(limit),
+            ..Stage1Config::default()
+        });
+
+        let outcomes: Vec<AddOutcome> = (0..10).map(|_| stage1.try_add_memory(7, 100)).collect();
+        assert!(outcomes[..3].iter().all(|outcome| matches!(outcome, AddOutcome::Inserted(_))));
+        assert!(outcomes[3..].iter().all(|&outcome| outcome == AddOutcome::RateLimited));
+        assert_eq!(stage1.find_by_token(7).len(), 3);
+        assert!(matches!(stage1.try_add_memory(8, 100), AddOutcome::Inserted(_)));
+
+        // The window slides, and tokens idle for a window are forgotten
+        clock.advance(60);
+        assert!(matches!(stage1.try_add_memory(7, 100), AddOutcome::Inserted(_)));
+        assert_eq!(stage1.rate_limiter.as_ref().unwrap().tracked_tokens(), 1);
+
+        let mut coalescing = Stage1::with_config(Stage1Config {
+            clock: clock.clone(),
+            rate_limit: Some(RateLimit { max_per_window: 1, action: RateLimitAction::Coalesce, ..limit }),
+            ..Stage1Config::default()
+        });
+        let AddOutcome::Inserted(epoch) = coalescing.try_add_memory(7, 100) else {
+            panic!("first addition should be inserted");
+        };
+        assert_eq!(coalescing.try_add_memory(7, 50), AddOutcome::Coalesced(epoch));
+        assert_eq!(coalescing.get_memory(epoch).unwrap().weight(), 150);
+    }
+
+    #[test]
+    fn test_pinned_memory_survives_maintenance() -> Result<(), Stage1Error> {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            clock: clock.clone(),
+            max_age: 3600,
+            max_entries: Some(2),
+            ..Stage1Config::default()
+        });
+        let anchor = stage1.add_memory(1, 150);
+        let loose = stage1.add_memory(2, 150);
+        stage1.pin(anchor)?;
+
+        // The lighter pinned memory is passed over by the capacity limit
+        stage1.add_memory(3, 5000);
+        assert!(stage1.get_memory(loose).is_err());
+
+        for _ in 0..48 {
+            clock.advance(3600);
+            stage1.maintain();
+        }
+        assert_eq!(stage1.get_memory(anchor)?.weight(), 150);
+        assert!(stage1.get_memory(anchor)?.is_pinned());
+
+        stage1.unpin(anchor)?;
+        clock.advance(3600);
+        let aged: Vec<u32> = stage1.maintain().iter().map(MemoryEntry::epoch).collect();
+        assert_eq!(aged, vec![anchor]);
+        assert!(matches!(stage1.pin(anchor), Err(Stage1Error::EntryNotFound(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decay_of_extreme_weights_never_wraps() {
+        let clock = Arc::new(MockClock::new(10_000));
+        let mut stage1 = Stage1::with_config(Stage1Config {
+            clock: clock.clone(),
+            min_weight: i16::MIN,
+            ..Stage1Config::default()
+        });
+        let strong = stage1.add_memory(100, i16::MAX);
+        let weak = stage1.add_memory(200, i16::MIN);
+
+        let (mut last_strong, mut last_weak) = (i16::MAX, i16::MIN);
+        for _ in 0..20 {
+            clock.advance(3600);
+            stage1.maintain();
+            let strong_weight = stage1.get_memory(strong).unwrap().weight();
+            let weak_weight = stage1.get_memory(weak).unwrap().weight();
+            assert!((0..last_strong).contains(&strong_weight), "{} after {}", strong_weight, last_strong);
+            assert!((last_weak + 1..=0).contains(&weak_weight), "{} after {}", weak_weight, last_weak);
+            (last_strong, last_weak) = (strong_weight, weak_weight);
+        }
+
+        assert_eq!(decayed_weight(i16::MAX, 2.0), i16::MAX);
+        assert_eq!(decayed_weight(i16::MIN, f32::NAN), 0);
+    }
+}
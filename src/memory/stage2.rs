@@ -1,9 +1,17 @@
+use super::checksum::ChecksumAlgorithm;
+use super::clock::{Clock, MemoryClock};
+use super::compression::{CompressionAlgorithm, CompressionError, Compressor, DEFAULT_MAX_COMPRESSION_RATIO};
 use super::entry::MemoryEntry;
+use super::interop;
+use super::token_index::TokenIndex;
+use crate::storage::{BlockStore, FileBlockStore};
 use bincode::{deserialize, serialize};
-use std::collections::BTreeMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -16,17 +24,88 @@ pub enum Stage2Error {
     NotFound(u32),
     #[error("Invalid checksum for entry: {0}")]
     ChecksumMismatch(u32),
+    #[error("Compression error: {0}")]
+    Compression(#[from] CompressionError),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
 }
 
+/// Size of the little-endian length prefix written before each block
+pub(super) const LENGTH_PREFIX_SIZE: u64 = 8;
+
+/// Name of the index `Stage2::close` leaves under `storage_path`
+pub(super) const INDEX_FILE_NAME: &str = "index.bin";
+
+/// Name of the log recording which epochs share another epoch's block
+pub(super) const ALIAS_FILE_NAME: &str = "aliases.log";
+
 /// Configuration for Stage2 memory management
 #[derive(Debug, Clone)]
 pub struct Stage2Config {
     /// Base directory for storing Stage 2 memories
     pub storage_path: PathBuf,
-    /// Maximum entries per storage file
+    /// Maximum entries per storage file; ignored when sharding by token
     pub entries_per_file: usize,
+    /// How new entries are spread across storage files
+    pub shard_by: ShardBy,
     /// Minimum age (seconds) before compression
     pub compression_age: u32,
+    /// Algorithm used by `compress_old_entries`; blocks record their own
+    /// algorithm, so changing this leaves existing blocks readable
+    pub compression_algorithm: CompressionAlgorithm,
+    /// Checksum written into new blocks; blocks keep their own algorithm
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// When appended entries are forced to disk
+    pub sync_mode: SyncMode,
+    /// Source of the current epoch when judging entry age
+    pub clock: Arc<dyn Clock>,
+    /// Blocks that compress to more than this fraction of their size are
+    /// left uncompressed
+    pub max_compression_ratio: f32,
+    /// Store an entry whose content (everything but its epoch) matches an
+    /// existing block as a reference to that block instead of a new one
+    ///
+    /// The block survives `compact` while any epoch still points at it, so
+    /// removing the epoch it was first written for only holds across a
+    /// reopen if the store was `close`d.
+    pub dedup_by_content: bool,
+}
+
+/// How `Stage2` picks the file a new entry is appended to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShardBy {
+    /// Fill one time-named file at a time, starting the next after
+    /// `entries_per_file` entries
+    #[default]
+    Time,
+    /// Append each entry to `shard_{token % n}.bin`, so every entry for a
+    /// token lives in one file
+    ///
+    /// An epoch stays in the shard it was first stored in, even if it is
+    /// later stored again under another token. Zero is treated as one.
+    TokenRange(u16),
+}
+
+/// The file under `storage_path` that shard `shard` is appended to, shared
+/// with `AsyncStage2`
+pub(super) fn shard_path(storage_path: &Path, shard: u16) -> PathBuf {
+    storage_path.join(format!("shard_{}.bin", shard))
+}
+
+/// When `Stage2` forces newly stored entries to disk
+///
+/// Only `PerWrite` goes through the write-ahead log. With the other modes a
+/// crash can lose unsynced entries; a torn final block is dropped when the
+/// store is reopened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Every entry is synced before `accept_entries` moves on
+    #[default]
+    PerWrite,
+    /// Sync after every N entries
+    Batched(usize),
+    /// Sync only when `Stage2::sync` is called
+    Manual,
 }
 
 impl Default for Stage2Config {
@@ -34,63 +113,358 @@ impl Default for Stage2Config {
         Self {
             storage_path: PathBuf::from("storage/stage2"),
             entries_per_file: 1000,
+            shard_by: ShardBy::Time,
             compression_age: 3600 * 24 * 7, // 1 week
+            compression_algorithm: CompressionAlgorithm::LZ4,
+            checksum_algorithm: ChecksumAlgorithm::Crc32,
+            sync_mode: SyncMode::PerWrite,
+            clock: Arc::new(MemoryClock::default()),
+            max_compression_ratio: DEFAULT_MAX_COMPRESSION_RATIO,
+            dedup_by_content: false,
         }
     }
 }
 
 /// Represents a memory block in Stage 2 storage
 #[derive(Serialize, Deserialize)]
-struct MemoryBlock {
-    entry: MemoryEntry,
-    checksum: u32,
-    compressed: bool,
+pub(super) struct MemoryBlock {
+    /// Serialized entry, compressed with `algorithm`
+    payload: Vec<u8>,
+    /// Checksum of the uncompressed serialized entry
+    checksum: u64,
+    checksum_algorithm: ChecksumAlgorithm,
+    /// `None` until the block is compressed
+    algorithm: CompressionAlgorithm,
 }
 
 impl MemoryBlock {
-    fn new(entry: MemoryEntry) -> Self {
-        let checksum = Self::calculate_checksum(&entry);
-        Self {
-            entry,
+    pub(super) fn new(entry: &MemoryEntry, checksum_algorithm: ChecksumAlgorithm) -> Result<Self, Stage2Error> {
+        let payload = serialize(entry)?;
+        let checksum = checksum_algorithm.compute(&payload);
+        Ok(Self {
+            payload,
             checksum,
-            compressed: false,
+            checksum_algorithm,
+            algorithm: CompressionAlgorithm::None,
+        })
+    }
+
+    fn is_compressed(&self) -> bool {
+        self.algorithm != CompressionAlgorithm::None
+    }
+
+    /// Compresses the payload in place, returning whether anything changed;
+    /// the checksum is left untouched since it always covers the uncompressed
+    /// bytes
+    fn compress(&mut self, compressor: &Compressor) -> bool {
+        if self.is_compressed() || compressor.algorithm() == CompressionAlgorithm::None {
+            return false;
+        }
+
+        let (compressed, metrics) = compressor.compress(&self.payload);
+        if metrics.algorithm == CompressionAlgorithm::None {
+            // Not worth compressing; leave the block as it is
+            return false;
+        }
+        self.payload = compressed;
+        self.algorithm = metrics.algorithm;
+        true
+    }
+
+    /// Returns the uncompressed serialized entry, using whichever algorithm
+    /// the block was written with
+    pub(super) fn raw_payload(&self) -> Result<Vec<u8>, Stage2Error> {
+        Compressor::new(self.algorithm)
+            .decompress(&self.payload)
+            .map_err(Stage2Error::from)
+    }
+
+    /// Returns the uncompressed serialized entry if it matches the checksum
+    fn verified_payload(&self) -> Result<Option<Vec<u8>>, Stage2Error> {
+        let raw = self.raw_payload()?;
+        Ok((self.checksum_algorithm.compute(&raw) == self.checksum).then_some(raw))
+    }
+
+    /// Decodes the entry, verifying the checksum over the uncompressed bytes
+    pub(super) fn verified_entry(&self) -> Result<Option<MemoryEntry>, Stage2Error> {
+        match self.verified_payload()? {
+            Some(raw) => Ok(Some(MemoryEntry::from_bytes(&raw)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Describes a block write that has started but may not have reached disk
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct WalRecord {
+    pub(super) epoch: u32,
+    pub(super) file: PathBuf,
+    /// File length before the write began
+    pub(super) offset: u64,
+    /// Bytes being appended, including the length prefix
+    pub(super) len: u64,
+}
+
+/// Single-record write-ahead log guarding the block currently being appended
+pub(super) struct WriteAheadLog {
+    pub(super) path: PathBuf,
+}
+
+impl WriteAheadLog {
+    pub(super) fn new(storage_path: &Path) -> Self {
+        Self { path: storage_path.join("stage2.wal") }
+    }
+
+    /// Durably records a pending write before any block bytes are written
+    fn begin(&self, store: &impl BlockStore, record: &WalRecord) -> Result<(), Stage2Error> {
+        store.put(&self.path, &serialize(record)?)?;
+        Ok(())
+    }
+
+    /// Marks the pending write as complete
+    fn commit(&self, store: &impl BlockStore) -> io::Result<()> {
+        store.put(&self.path, &[])
+    }
+
+    /// Truncates a torn block left behind by a crash mid-write
+    fn recover(&self, store: &impl BlockStore) -> io::Result<()> {
+        let bytes = match store.get(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        // An unreadable record means the crash hit the log itself, before
+        // any block bytes were written
+        if let Ok(record) = deserialize::<WalRecord>(&bytes) {
+            if let Ok(file_len) = store.len(&record.file) {
+                if file_len > record.offset && file_len < record.offset + record.len {
+                    store.truncate(&record.file, record.offset)?;
+                }
+            }
         }
+
+        self.commit(store)
+    }
+}
+
+/// A block other epochs can be pointed at when content dedup is on
+struct SharedBlock {
+    location: (PathBuf, u64, u64),
+    alias_target: AliasTarget,
+    /// Indexed epochs pointing at the block
+    refs: usize,
+}
+
+/// Identifies a block across rescans, compaction and compression: the
+/// epoch it was written for and the checksum over its uncompressed entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct AliasTarget {
+    epoch: u32,
+    checksum: u64,
+}
+
+impl AliasTarget {
+    fn of(block: &MemoryBlock, epoch: u32) -> Self {
+        Self { epoch, checksum: block.checksum }
+    }
+}
+
+/// Size of one alias log record: the aliased epoch, then the target's
+/// epoch and checksum, all little-endian
+const ALIAS_RECORD_SIZE: usize = 16;
+
+fn encode_alias(alias: u32, target: AliasTarget) -> Vec<u8> {
+    let mut record = Vec::with_capacity(ALIAS_RECORD_SIZE);
+    record.extend_from_slice(&alias.to_le_bytes());
+    record.extend_from_slice(&target.epoch.to_le_bytes());
+    record.extend_from_slice(&target.checksum.to_le_bytes());
+    record
+}
+
+fn decode_alias(record: &[u8]) -> (u32, AliasTarget) {
+    let (alias, rest) = record.split_at(4);
+    let (epoch, checksum) = rest.split_at(4);
+    (
+        u32::from_le_bytes(alias.try_into().unwrap()),
+        AliasTarget {
+            epoch: u32::from_le_bytes(epoch.try_into().unwrap()),
+            checksum: u64::from_le_bytes(checksum.try_into().unwrap()),
+        },
+    )
+}
+
+/// Hash of everything in an entry but its epoch
+fn content_hash(entry: &MemoryEntry) -> Result<u64, Stage2Error> {
+    let content = serialize(&entry.clone().with_epoch(0))?;
+    Ok(xxhash_rust::xxh64::xxh64(&content, 0))
+}
+
+/// Index written by `Stage2::close` so the next open can skip the rescan
+#[derive(Serialize, Deserialize)]
+struct SavedIndex {
+    /// Every storage file and its length when the index was written
+    files: Vec<(PathBuf, u64)>,
+    entries: Vec<(u32, PathBuf, u64, u64)>,
+    tokens: Vec<(u16, u32)>,
+}
+
+impl SavedIndex {
+    /// Serializes behind a CRC32 of the body
+    fn encode(&self) -> Result<Vec<u8>, Stage2Error> {
+        let body = serialize(self)?;
+        let mut bytes = crc32fast::hash(&body).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
     }
 
-    fn calculate_checksum(entry: &MemoryEntry) -> u32 {
-        // Simple CRC32 implementation
-        let data = serialize(entry).unwrap();
-        crc32fast::hash(&data)
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (crc, body) = bytes.split_first_chunk::<4>()?;
+        if u32::from_le_bytes(*crc) != crc32fast::hash(body) {
+            return None;
+        }
+        deserialize(body).ok()
     }
+}
+
+/// Outcome of a `Stage2::compact` run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactStats {
+    /// Bytes freed across all rewritten and deleted files
+    pub bytes_reclaimed: u64,
+    /// Live blocks that ended up at a new offset
+    pub blocks_moved: usize,
+    /// Files deleted because they held no live blocks
+    pub files_removed: usize,
+}
+
+/// Space taken by Stage2 storage files
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// How two Stage2 stores differ, each list in ascending epoch order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreDiff {
+    pub only_in_a: Vec<u32>,
+    pub only_in_b: Vec<u32>,
+    /// Epochs in both stores whose entries do not match
+    pub differing: Vec<u32>,
+}
+
+/// Which copy `Stage2::merge_into` keeps when both stores hold an epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Keep the heavier entry; ties keep ours
+    PreferHigherWeight,
+    /// Keep the entry whose backing file was modified last; ties keep ours
+    PreferNewer,
+}
 
-    fn verify(&self) -> bool {
-        self.checksum == Self::calculate_checksum(&self.entry)
+/// Compares two stores epoch by epoch
+///
+/// Shared epochs are compared by their decoded entries, so blocks
+/// compressed or deduplicated differently still match.
+pub fn diff<A: BlockStore, B: BlockStore>(a: &Stage2<A>, b: &Stage2<B>) -> Result<StoreDiff, Stage2Error> {
+    let mut diff = StoreDiff::default();
+    for epoch in a.iter_epochs() {
+        if !b.contains(epoch) {
+            diff.only_in_a.push(epoch);
+        } else if a.differs_from(b, epoch)? {
+            diff.differing.push(epoch);
+        }
     }
+    diff.only_in_b = b.iter_epochs().filter(|&epoch| !a.contains(epoch)).collect();
+    Ok(diff)
 }
 
-pub struct Stage2 {
+/// Mid-term memory storage, keeping its files in a `BlockStore` under
+/// `storage_path`
+pub struct Stage2<S: BlockStore = FileBlockStore> {
     config: Stage2Config,
-    // In-memory index of epoch -> file location
-    index: BTreeMap<u32, (PathBuf, u64)>,
-    current_file: Option<File>,
+    store: S,
+    // In-memory index of epoch -> (file, offset, serialized length)
+    index: BTreeMap<u32, (PathBuf, u64, u64)>,
+    tokens: TokenIndex,
+    // File new entries are appended to, if one has been started
+    current_file_path: Option<PathBuf>,
     current_file_entries: usize,
+    /// Entries appended since the last sync, and the files they went to
+    unsynced_writes: usize,
+    unsynced_files: BTreeSet<PathBuf>,
+    compressor: Compressor,
+    wal: WriteAheadLog,
+    /// Blocks new entries can share, by content hash; empty unless
+    /// `dedup_by_content` is set
+    shared: HashMap<u64, SharedBlock>,
 }
 
 impl Stage2 {
     pub fn new(config: Stage2Config) -> io::Result<Self> {
         std::fs::create_dir_all(&config.storage_path)?;
+        Self::with_store(config, FileBlockStore)
+    }
+}
+
+impl<S: BlockStore> Stage2<S> {
+    /// Opens a store whose files live in `store` instead of on the local
+    /// filesystem
+    pub fn with_store(config: Stage2Config, store: S) -> io::Result<Self> {
+        // Undo any write that was interrupted before the index is rebuilt
+        let wal = WriteAheadLog::new(&config.storage_path);
+        wal.recover(&store)?;
         
         let mut stage2 = Self {
+            wal,
+            compressor: Compressor::new(config.compression_algorithm)
+                .with_max_ratio(config.max_compression_ratio),
             config,
+            store,
             index: BTreeMap::new(),
-            current_file: None,
+            tokens: TokenIndex::new(),
+            current_file_path: None,
             current_file_entries: 0,
+            unsynced_writes: 0,
+            unsynced_files: BTreeSet::new(),
+            shared: HashMap::new(),
         };
         
-        stage2.load_index()?;
+        if !stage2.load_saved_index()? {
+            stage2.load_index()?;
+        }
+        if stage2.config.dedup_by_content {
+            stage2.load_shared_blocks()?;
+        }
         Ok(stage2)
     }
 
+    /// Syncs outstanding writes and saves the index, so the next open can
+    /// load it instead of rescanning every file
+    ///
+    /// Dropping a store without closing it is safe; the next open just
+    /// rebuilds the index from the files.
+    pub fn close(mut self) -> Result<(), Stage2Error> {
+        self.sync()?;
+
+        let mut files = Vec::new();
+        for path in self.data_files()? {
+            let len = self.store.len(&path)?;
+            files.push((path, len));
+        }
+        let saved = SavedIndex {
+            files,
+            entries: self.index
+                .iter()
+                .map(|(&epoch, (path, pos, len))| (epoch, path.clone(), *pos, *len))
+                .collect(),
+            tokens: self.tokens.iter().collect(),
+        };
+        self.store.put(&self.index_path(), &saved.encode()?)?;
+        Ok(())
+    }
+
     /// Accepts aged entries from Stage 1
     pub fn accept_entries(&mut self, entries: Vec<MemoryEntry>) -> Result<(), Stage2Error> {
         for entry in entries {
@@ -101,162 +475,1802 @@ impl Stage2 {
 
     /// Stores a single memory entry
     fn store_entry(&mut self, entry: MemoryEntry) -> Result<(), Stage2Error> {
-        // Create new file if needed
-        if self.current_file.is_none() || 
-           self.current_file_entries >= self.config.entries_per_file {
-            self.rotate_file()?;
+        // Read before writing, so the old token can be dropped below
+        let previous = self.index
+            .contains_key(&entry.epoch())
+            .then(|| self.get_entry(entry.epoch()));
+
+        if self.config.dedup_by_content {
+            // A replaced entry gets a block of its own, so a rescan, which
+            // prefers real blocks over aliases, cannot bring the old one back
+            match previous {
+                Some(Ok(ref previous)) => self.release_shared(previous)?,
+                Some(Err(err)) => return Err(err),
+                None => {
+                    if self.store_as_alias(&entry)? {
+                        return Ok(());
+                    }
+                }
+            }
         }
 
-        let file = self.current_file.as_mut().unwrap();
-        let block = MemoryBlock::new(entry);
-        
-        // Get current position for index
-        let pos = file.seek(SeekFrom::End(0))?;
-        
-        // Write block
-        let encoded = serialize(&block)?;
-        file.write_all(&encoded)?;
-        file.flush()?;
-
-        // Update index
-        let current_path = self.current_file_path();
-        self.index.insert(block.entry.epoch(), (current_path, pos));
+        let current_path = match self.config.shard_by {
+            ShardBy::Time => {
+                // Create new file if needed
+                if self.current_file_path.is_none() ||
+                   self.current_file_entries >= self.config.entries_per_file {
+                    self.rotate_file()?;
+                }
+                self.current_file_path.clone().unwrap()
+            }
+            // Rescans keep the last block per epoch within a file, so a
+            // stored epoch is never moved to another shard
+            ShardBy::TokenRange(shards) => match self.index.get(&entry.epoch()) {
+                Some((path, _, _)) => path.clone(),
+                None => self.shard_path(entry.token() % shards.max(1)),
+            },
+        };
+
+        let block = MemoryBlock::new(&entry, self.config.checksum_algorithm)?;
+        let (pos, len) = match self.config.sync_mode {
+            SyncMode::PerWrite => self.append_block(&current_path, entry.epoch(), &block)?,
+            SyncMode::Batched(_) | SyncMode::Manual => {
+                let appended = self.write_block(&current_path, &block)?;
+                self.unsynced_writes += 1;
+                self.unsynced_files.insert(current_path.clone());
+                appended
+            }
+        };
+
+        self.register_shared(&entry, &block, (current_path.clone(), pos, len))?;
+
+        // Update index to point past the prefix at the block itself
+        self.index.insert(entry.epoch(), (current_path, pos, len));
+        match previous {
+            Some(Ok(previous)) => self.tokens.remove(previous.token(), entry.epoch()),
+            // The old block is unreadable, so its token is unknown
+            Some(Err(_)) => self.tokens.remove_epoch(entry.epoch()),
+            None => {}
+        }
+        self.tokens.insert(entry.token(), entry.epoch());
         self.current_file_entries += 1;
 
+        if let SyncMode::Batched(batch) = self.config.sync_mode {
+            if self.unsynced_writes >= batch {
+                self.sync()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Offers a newly written block for later entries to share, unless a
+    /// block with the same content already is
+    fn register_shared(
+        &mut self,
+        entry: &MemoryEntry,
+        block: &MemoryBlock,
+        location: (PathBuf, u64, u64),
+    ) -> Result<(), Stage2Error> {
+        if self.config.dedup_by_content {
+            self.shared.entry(content_hash(entry)?).or_insert_with(|| SharedBlock {
+                location,
+                alias_target: AliasTarget::of(block, entry.epoch()),
+                refs: 1,
+            });
+        }
+        Ok(())
+    }
+
+    /// Points `entry`'s epoch at an existing block with the same content,
+    /// returning false if there is none
+    fn store_as_alias(&mut self, entry: &MemoryEntry) -> Result<bool, Stage2Error> {
+        let hash = content_hash(entry)?;
+        let Some(shared) = self.shared.get(&hash) else {
+            return Ok(false);
+        };
+
+        // Equal hashes alone could be a collision
+        let (path, pos, len) = shared.location.clone();
+        let existing = self.read_block_at(&path, pos, len)?.verified_entry()?;
+        let same = match existing {
+            Some(existing) => serialize(&existing.with_epoch(0))? == serialize(&entry.clone().with_epoch(0))?,
+            None => false,
+        };
+        if !same {
+            return Ok(false);
+        }
+
+        let record = encode_alias(entry.epoch(), shared.alias_target);
+        let alias_path = self.alias_path();
+        self.store.append(&alias_path, &record)?;
+        self.store.sync(&alias_path)?;
+
+        self.index.insert(entry.epoch(), (path, pos, len));
+        self.tokens.insert(entry.token(), entry.epoch());
+        if let Some(shared) = self.shared.get_mut(&hash) {
+            shared.refs += 1;
+        }
+        Ok(true)
+    }
+
+    /// Drops one reference to the shared block holding `entry`, forgetting
+    /// the block once nothing points at it
+    ///
+    /// Call before the epoch leaves the index.
+    fn release_shared(&mut self, entry: &MemoryEntry) -> Result<(), Stage2Error> {
+        if !self.config.dedup_by_content {
+            return Ok(());
+        }
+        let hash = content_hash(entry)?;
+        let location = self.index.get(&entry.epoch());
+        if let Some(shared) = self.shared.get_mut(&hash) {
+            if Some(&shared.location) == location {
+                shared.refs -= 1;
+                if shared.refs == 0 {
+                    self.shared.remove(&hash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `f` to a stored entry and appends the result as a new block
+    /// in the same file, repointing the index at it
+    ///
+    /// The old block is dead space until the next `compact`. The update is
+    /// compressed if the old block was, and the epoch cannot change; `f`
+    /// moving the entry to another epoch is undone.
+    pub fn update_entry(&mut self, epoch: u32, f: impl FnOnce(&mut MemoryEntry)) -> Result<(), Stage2Error> {
+        let old_block = self.read_block(epoch)?;
+        let previous = old_block.verified_entry()?
+            .ok_or(Stage2Error::ChecksumMismatch(epoch))?
+            .with_epoch(epoch);
+
+        let mut entry = previous.clone();
+        f(&mut entry);
+        let entry = entry.with_epoch(epoch);
+
+        let mut block = MemoryBlock::new(&entry, self.config.checksum_algorithm)?;
+        if old_block.is_compressed() {
+            block.compress(&self.compressor);
+        }
+        let path = self.index[&epoch].0.clone();
+        let (pos, len) = self.append_block(&path, epoch, &block)?;
+
+        self.release_shared(&previous)?;
+        self.register_shared(&entry, &block, (path.clone(), pos, len))?;
+        self.index.insert(epoch, (path, pos, len));
+        if entry.token() != previous.token() {
+            self.tokens.remove(previous.token(), epoch);
+            self.tokens.insert(entry.token(), epoch);
+        }
+        Ok(())
+    }
+
+    /// Forces every entry stored so far to disk
+    pub fn sync(&mut self) -> Result<(), Stage2Error> {
+        for path in std::mem::take(&mut self.unsynced_files) {
+            self.store.sync(&path)?;
+        }
+        self.unsynced_writes = 0;
         Ok(())
     }
 
+    /// Returns how many stored entries have not been synced to disk yet
+    pub fn unsynced_writes(&self) -> usize {
+        self.unsynced_writes
+    }
+
     /// Retrieves a memory entry by epoch
-    pub fn get_entry(&mut self, epoch: u32) -> Result<MemoryEntry, Stage2Error> {
-        let (path, pos) = self.index.get(&epoch)
-            .ok_or(Stage2Error::NotFound(epoch))?;
+    pub fn get_entry(&self, epoch: u32) -> Result<MemoryEntry, Stage2Error> {
+        let block = self.read_block(epoch)?;
+
+        // A shared block decodes under the epoch it was first written for
+        block.verified_entry()?
+            .map(|entry| entry.with_epoch(epoch))
+            .ok_or(Stage2Error::ChecksumMismatch(epoch))
+    }
 
-        let mut file = File::open(path)?;
-        file.seek(SeekFrom::Start(*pos))?;
+    /// Reads just the weight of a stored entry
+    ///
+    /// The block is verified as in `get_entry`, but only the leading fields
+    /// are decoded, so no full entry is built or cloned.
+    pub fn get_weight(&self, epoch: u32) -> Result<i16, Stage2Error> {
+        self.get_header(epoch).map(|(_, _, weight)| weight)
+    }
 
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+    /// Reads just the token of a stored entry, like `get_weight`
+    pub fn get_token(&self, epoch: u32) -> Result<u16, Stage2Error> {
+        self.get_header(epoch).map(|(_, token, _)| token)
+    }
 
-        let block: MemoryBlock = deserialize(&buffer)?;
-        
-        if !block.verify() {
-            return Err(Stage2Error::ChecksumMismatch(epoch));
+    fn get_header(&self, epoch: u32) -> Result<(u32, u16, i16), Stage2Error> {
+        let raw = self.read_block(epoch)?
+            .verified_payload()?
+            .ok_or(Stage2Error::ChecksumMismatch(epoch))?;
+        Ok(MemoryEntry::header_from_bytes(&raw)?)
+    }
+
+    /// Retrieves many entries at once, reading each backing file only once
+    ///
+    /// Epochs that are not stored are skipped; found entries are returned in
+    /// the order they were requested.
+    pub fn get_entries(&self, epochs: &[u32]) -> Result<Vec<(u32, MemoryEntry)>, Stage2Error> {
+        let mut by_file: BTreeMap<&PathBuf, Vec<(u64, u64, u32)>> = BTreeMap::new();
+        for &epoch in epochs {
+            if let Some((path, pos, len)) = self.index.get(&epoch) {
+                by_file.entry(path).or_default().push((*pos, *len, epoch));
+            }
+        }
+
+        let mut found = HashMap::new();
+        for (path, mut blocks) in by_file {
+            blocks.sort_unstable();
+            blocks.dedup();
+
+            let bytes = self.store.get(path)?;
+            for (pos, len, epoch) in blocks {
+                let block = bytes.get(pos as usize..(pos + len) as usize)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                let entry = deserialize::<MemoryBlock>(block)?
+                    .verified_entry()?
+                    .ok_or(Stage2Error::ChecksumMismatch(epoch))?;
+                found.insert(epoch, entry.with_epoch(epoch));
+            }
         }
 
-        Ok(block.entry)
+        Ok(epochs
+            .iter()
+            .filter_map(|epoch| found.get(epoch).map(|entry| (*epoch, entry.clone())))
+            .collect())
     }
 
-    /// Compresses old entries to save space
-    pub fn compress_old_entries(&mut self) -> Result<(), Stage2Error> {
-        let current_epoch = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
+    /// Reads and checks every indexed block, returning the epochs that fail
+    ///
+    /// Unreadable and corrupt blocks are both reported; the sweep never stops
+    /// at the first failure.
+    pub fn verify_all(&mut self) -> Result<Vec<u32>, Stage2Error> {
+        let mut failed = Vec::new();
+        for &epoch in self.index.keys() {
+            let verified = self.read_block(epoch)
+                .and_then(|block| block.verified_entry());
+            if !matches!(verified, Ok(Some(_))) {
+                failed.push(epoch);
+            }
+        }
+        Ok(failed)
+    }
 
-        let compression_threshold = current_epoch - self.config.compression_age;
-        
-        for (&epoch, &(ref path, pos)) in self.index.iter() {
-            if epoch < compression_threshold {
-                let mut file = File::open(path)?;
-                file.seek(SeekFrom::Start(pos))?;
-                
-                let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer)?;
-                
-                let mut block: MemoryBlock = deserialize(&buffer)?;
-                if !block.compressed {
-                    // Implement compression logic here
-                    block.compressed = true;
-                    
-                    // Write back compressed block
-                    file.seek(SeekFrom::Start(pos))?;
-                    let encoded = serialize(&block)?;
-                    file.write_all(&encoded)?;
-                }
+    /// Passes every stored entry to `f` in epoch order, one block read at a
+    /// time, until `f` breaks
+    ///
+    /// Unlike `get_entries`, nothing is collected, so a scan of any size runs
+    /// in constant memory. A block that cannot be read or verified ends the
+    /// scan with its error.
+    pub fn for_each_entry(
+        &mut self,
+        mut f: impl FnMut(u32, &MemoryEntry) -> ControlFlow<()>,
+    ) -> Result<(), Stage2Error> {
+        for &epoch in self.index.keys() {
+            let entry = self.read_block(epoch)?
+                .verified_entry()?
+                .ok_or(Stage2Error::ChecksumMismatch(epoch))?
+                .with_epoch(epoch);
+            if f(epoch, &entry).is_break() {
+                break;
             }
         }
-        
         Ok(())
     }
 
-    // Helper methods
-    fn rotate_file(&mut self) -> io::Result<()> {
-        let path = self.current_file_path();
-        self.current_file = Some(OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(path)?);
-        self.current_file_entries = 0;
-        Ok(())
+    /// Dumps every stored entry, in epoch order, as JSON
+    pub fn export_json(&mut self) -> Result<String, Stage2Error> {
+        let epochs: Vec<u32> = self.iter_epochs().collect();
+        let entries: Vec<MemoryEntry> = self.get_entries(&epochs)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect();
+        Ok(interop::export_json(&entries))
     }
 
-    fn current_file_path(&self) -> PathBuf {
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        self.config.storage_path.join(format!("mem_{}.bin", timestamp))
+    /// Stores every entry from a JSON dump, returning how many were loaded
+    pub fn import_json(&mut self, json: &str) -> Result<usize, Stage2Error> {
+        let entries = interop::import_json(json)?;
+        let count = entries.len();
+        self.accept_entries(entries)?;
+        Ok(count)
     }
 
-    fn load_index(&mut self) -> io::Result<()> {
-        // Scan directory and rebuild index
-        for entry in std::fs::read_dir(&self.config.storage_path)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.extension().map_or(false, |ext| ext == "bin") {
-                let mut file = File::open(&path)?;
-                let mut pos = 0;
-                
-                loop {
-                    let mut buffer = Vec::new();
-                    match file.read_to_end(&mut buffer) {
-                        Ok(0) => break,
-                        Ok(_) => {
-                            if let Ok(block) = deserialize::<MemoryBlock>(&buffer) {
-                                self.index.insert(block.entry.epoch(), (path.clone(), pos));
-                            }
-                            pos = file.seek(SeekFrom::Current(0))?;
-                        }
-                        Err(_) => break,
+    /// Returns every stored memory that encodes `token`, in epoch order
+    pub fn find_by_token(&self, token: u16) -> Result<Vec<MemoryEntry>, Stage2Error> {
+        let epochs: Vec<u32> = self.tokens.epochs(token).collect();
+        Ok(self.get_entries(&epochs)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    /// Counts storage files and their total size on disk
+    pub fn disk_usage(&self) -> io::Result<DiskUsage> {
+        let mut usage = DiskUsage::default();
+        for path in self.data_files()? {
+            usage.files += 1;
+            usage.bytes += self.store.len(&path)?;
+        }
+        Ok(usage)
+    }
+
+    /// Iterates over every stored epoch in ascending order
+    pub fn iter_epochs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.index.keys().copied()
+    }
+
+    /// Iterates over stored epochs in `start..end`, in ascending order
+    pub fn range(&self, start: u32, end: u32) -> impl Iterator<Item = u32> + '_ {
+        // An inverted window is simply empty rather than a BTreeMap panic
+        self.index.range(start..end.max(start)).map(|(&epoch, _)| epoch)
+    }
+
+    /// Lazily reads the entries with epochs in `start..end`, in epoch order
+    /// across every storage file
+    ///
+    /// The index is ordered by epoch whichever file holds each block, so the
+    /// scan walks it and reads one block per step, holding nothing else. A
+    /// block that cannot be read or verified yields its error in place.
+    pub fn scan_ordered(&self, start: u32, end: u32) -> impl Iterator<Item = Result<MemoryEntry, Stage2Error>> + '_ {
+        self.range(start, end).map(|epoch| self.get_entry(epoch))
+    }
+
+    /// Copies entries from `other` that this store lacks, and resolves
+    /// differing copies of shared epochs by `strategy`
+    ///
+    /// Returns how many entries were taken from `other`. Replaced blocks
+    /// stay on disk until the next `compact`.
+    pub fn merge_into<T: BlockStore>(&mut self, other: &Stage2<T>, strategy: MergeStrategy) -> Result<usize, Stage2Error> {
+        let diff = diff(self, other)?;
+        let mut taken = Vec::new();
+
+        for epoch in diff.only_in_b {
+            taken.push(other.get_entry(epoch)?);
+        }
+        for epoch in diff.differing {
+            let theirs = other.get_entry(epoch)?;
+            let take_theirs = match strategy {
+                MergeStrategy::PreferHigherWeight => theirs.weight() > self.get_entry(epoch)?.weight(),
+                MergeStrategy::PreferNewer => other.modified(epoch)? > self.modified(epoch)?,
+            };
+            if take_theirs {
+                taken.push(theirs);
+            }
+        }
+
+        let count = taken.len();
+        self.accept_entries(taken)?;
+        Ok(count)
+    }
+
+    /// Whether `other` holds a different entry at `epoch` than this store
+    fn differs_from<T: BlockStore>(&self, other: &Stage2<T>, epoch: u32) -> Result<bool, Stage2Error> {
+        let ours = serialize(&self.get_entry(epoch)?)?;
+        let theirs = serialize(&other.get_entry(epoch)?)?;
+        Ok(ours != theirs)
+    }
+
+    /// Last modification time of the file holding `epoch`
+    fn modified(&self, epoch: u32) -> Result<std::time::SystemTime, Stage2Error> {
+        let (path, _, _) = self.index.get(&epoch).ok_or(Stage2Error::NotFound(epoch))?;
+        Ok(self.store.modified(path)?)
+    }
+
+    /// Returns whether an entry is stored for this epoch
+    pub fn contains(&self, epoch: u32) -> bool {
+        self.index.contains_key(&epoch)
+    }
+
+    /// Returns the number of stored entries
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns whether no entries are stored
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Drops an entry from the index, returning it
+    ///
+    /// The block stays on disk as dead space until the next `compact`, so a
+    /// reopen before then will index it again unless the store was `close`d.
+    pub fn remove_entry(&mut self, epoch: u32) -> Result<MemoryEntry, Stage2Error> {
+        let entry = self.get_entry(epoch)?;
+        self.release_shared(&entry)?;
+        self.index.remove(&epoch);
+        self.tokens.remove(entry.token(), epoch);
+        Ok(entry)
+    }
+
+    /// Removes every unpinned entry whose hard expiry has passed at `now`,
+    /// returning the removed entries
+    ///
+    /// Like `remove_entry`, the space is reclaimed by the next `compact`.
+    pub fn sweep_expired(&mut self, now: u32) -> Result<Vec<MemoryEntry>, Stage2Error> {
+        let epochs: Vec<u32> = self.iter_epochs().collect();
+        let expired: Vec<MemoryEntry> = self.get_entries(&epochs)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.is_expired(now) && !entry.is_pinned())
+            .collect();
+
+        for entry in &expired {
+            self.release_shared(entry)?;
+            self.index.remove(&entry.epoch());
+            self.tokens.remove(entry.token(), entry.epoch());
+        }
+        Ok(expired)
+    }
+
+    /// Rewrites every storage file to hold only its live blocks, deleting
+    /// files that have none left
+    ///
+    /// Each file is rebuilt in memory and replaced with a single `put`, so a
+    /// crash leaves either the old or the compacted file. A block shared by
+    /// several epochs is kept once.
+    pub fn compact(&mut self) -> Result<CompactStats, Stage2Error> {
+        let mut stats = CompactStats::default();
+        self.compact_aliases()?;
+
+        let mut live: BTreeMap<PathBuf, Vec<(u64, u64, u32)>> = BTreeMap::new();
+        for (&epoch, (path, pos, len)) in &self.index {
+            live.entry(path.clone()).or_default().push((*pos, *len, epoch));
+        }
+
+        for path in self.data_files()? {
+            let old_size = self.store.len(&path)?;
+            let is_current = self.current_file_path.as_ref() == Some(&path);
+
+            let Some(mut blocks) = live.remove(&path) else {
+                if is_current {
+                    self.current_file_path = None;
+                }
+                self.unsynced_files.remove(&path);
+                self.store.delete(&path)?;
+                stats.bytes_reclaimed += old_size;
+                stats.files_removed += 1;
+                continue;
+            };
+
+            blocks.sort_unstable();
+            let mut unique = blocks.clone();
+            unique.dedup_by_key(|(pos, _, _)| *pos);
+            let live_size: u64 = unique.iter().map(|(_, len, _)| LENGTH_PREFIX_SIZE + len).sum();
+            if live_size == old_size {
+                continue;
+            }
+
+            let source = self.store.get(&path)?;
+            let mut target = Vec::with_capacity(live_size as usize);
+            let mut pos = 0;
+            let mut new_positions = HashMap::new();
+
+            for (old_pos, len, _) in unique {
+                let block = source.get(old_pos as usize..(old_pos + len) as usize)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                target.extend_from_slice(&len.to_le_bytes());
+                target.extend_from_slice(block);
+
+                let new_pos = pos + LENGTH_PREFIX_SIZE;
+                if new_pos != old_pos {
+                    stats.blocks_moved += 1;
+                }
+                new_positions.insert(old_pos, new_pos);
+                pos = new_pos + len;
+            }
+
+            self.store.put(&path, &target)?;
+
+            for (old_pos, len, epoch) in blocks {
+                self.index.insert(epoch, (path.clone(), new_positions[&old_pos], len));
+            }
+            for shared in self.shared.values_mut() {
+                let (shared_path, shared_pos, _) = &mut shared.location;
+                if *shared_path == path {
+                    if let Some(&new_pos) = new_positions.get(shared_pos) {
+                        *shared_pos = new_pos;
                     }
                 }
             }
+            stats.bytes_reclaimed += old_size - live_size;
+        }
+
+        Ok(stats)
+    }
+
+    /// Compresses old entries to save space
+    pub fn compress_old_entries(&mut self) -> Result<(), Stage2Error> {
+        let current_epoch = self.config.clock.now_epoch();
+        let compression_threshold = current_epoch.saturating_sub(self.config.compression_age);
+        let candidates: Vec<u32> = self.index
+            .range(..compression_threshold)
+            .map(|(&epoch, _)| epoch)
+            .collect();
+        
+        for epoch in candidates {
+            let mut block = self.read_block(epoch)?;
+            if !block.compress(&self.compressor) {
+                continue;
+            }
+
+            // The rewritten block may not fit the old slot, so append it to
+            // the same file and repoint the index; the old copy becomes dead
+            let old = self.index[&epoch].clone();
+            let (pos, len) = self.append_block(&old.0, epoch, &block)?;
+            let new = (old.0.clone(), pos, len);
+            if self.config.dedup_by_content {
+                self.relocate_shared(&old, &new);
+            } else {
+                self.index.insert(epoch, new);
+            }
         }
+        
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
+    /// Repoints every epoch and shared block at `old` to `new`
+    fn relocate_shared(&mut self, old: &(PathBuf, u64, u64), new: &(PathBuf, u64, u64)) {
+        for location in self.index.values_mut().filter(|location| *location == old) {
+            *location = new.clone();
+        }
+        for shared in self.shared.values_mut().filter(|shared| shared.location == *old) {
+            shared.location = new.clone();
+        }
+    }
 
-    #[test]
-    fn test_stage2_storage() -> Result<(), Stage2Error> {
-        let temp_dir = tempdir().unwrap();
-        let config = Stage2Config {
-            storage_path: temp_dir.path().to_path_buf(),
-            entries_per_file: 10,
-            compression_age: 3600,
+    /// Appends a length-prefixed block under the write-ahead log, returning
+    /// the offset and length of the block itself
+    fn append_block(&self, path: &Path, epoch: u32, block: &MemoryBlock) -> Result<(u64, u64), Stage2Error> {
+        let pos = match self.store.len(path) {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
         };
+        let framed = Self::frame(block)?;
 
-        let mut stage2 = Stage2::new(config)?;
-        
-        // Store some entries
-        let entries = vec![
-            MemoryEntry::new(100, 500),
-            MemoryEntry::new(101, 600),
-        ];
-        
-        stage2.accept_entries(entries)?;
-        
-        // Retrieve and verify
-        let entry = stage2.get_entry(100)?;
-        assert_eq!(entry.token(), 100);
-        assert_eq!(entry.weight(), 500);
+        self.wal.begin(&self.store, &WalRecord {
+            epoch,
+            file: path.to_path_buf(),
+            offset: pos,
+            len: framed.len() as u64,
+        })?;
 
-        Ok(())
-    }
-} 
\ No newline at end of file
+        let pos = self.store.append(path, &framed)?;
+        self.store.sync(path)?;
+
+        self.wal.commit(&self.store)?;
+
+        Ok((pos + LENGTH_PREFIX_SIZE, framed.len() as u64 - LENGTH_PREFIX_SIZE))
+    }
+
+    /// Appends a length-prefixed block without syncing it, returning the
+    /// offset and length of the block itself
+    fn write_block(&self, path: &Path, block: &MemoryBlock) -> Result<(u64, u64), Stage2Error> {
+        let framed = Self::frame(block)?;
+        let pos = self.store.append(path, &framed)?;
+        Ok((pos + LENGTH_PREFIX_SIZE, framed.len() as u64 - LENGTH_PREFIX_SIZE))
+    }
+
+    /// Serializes a block behind its little-endian length prefix
+    fn frame(block: &MemoryBlock) -> Result<Vec<u8>, Stage2Error> {
+        let encoded = serialize(block)?;
+        let mut framed = Vec::with_capacity(LENGTH_PREFIX_SIZE as usize + encoded.len());
+        framed.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+        framed.extend_from_slice(&encoded);
+        Ok(framed)
+    }
+
+    /// Reads the raw block for an epoch without verifying it
+    fn read_block(&self, epoch: u32) -> Result<MemoryBlock, Stage2Error> {
+        let (path, pos, len) = self.index.get(&epoch)
+            .ok_or(Stage2Error::NotFound(epoch))?;
+        self.read_block_at(path, *pos, *len)
+    }
+
+    fn read_block_at(&self, path: &Path, pos: u64, len: u64) -> Result<MemoryBlock, Stage2Error> {
+        // Read exactly one block; later blocks in the file are not ours
+        let buffer = self.store.get_range(path, pos, len)?;
+        Ok(deserialize(&buffer)?)
+    }
+
+    /// Lists the `.bin` storage files under `storage_path`
+    fn data_files(&self) -> io::Result<Vec<PathBuf>> {
+        Ok(self.store
+            .list(&self.config.storage_path)?
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+            .filter(|path| path.file_name().is_some_and(|name| name != INDEX_FILE_NAME))
+            .collect())
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.config.storage_path.join(INDEX_FILE_NAME)
+    }
+
+    fn alias_path(&self) -> PathBuf {
+        self.config.storage_path.join(ALIAS_FILE_NAME)
+    }
+
+    /// Reads the alias log, keeping the latest record for each aliased
+    /// epoch and ignoring a torn final record
+    fn read_aliases(&self) -> io::Result<BTreeMap<u32, AliasTarget>> {
+        let bytes = match self.store.get(&self.alias_path()) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(bytes.chunks_exact(ALIAS_RECORD_SIZE).map(decode_alias).collect())
+    }
+
+    /// Rewrites the alias log to hold only the records for epochs still
+    /// indexed
+    fn compact_aliases(&mut self) -> io::Result<()> {
+        let aliases = self.read_aliases()?;
+        if aliases.is_empty() {
+            return Ok(());
+        }
+
+        let bytes: Vec<u8> = aliases
+            .into_iter()
+            .filter(|(alias, _)| self.index.contains_key(alias))
+            .flat_map(|(alias, target)| encode_alias(alias, target))
+            .collect();
+        self.store.put(&self.alias_path(), &bytes)
+    }
+
+    /// Rebuilds the shareable blocks from the index, one per distinct
+    /// content; where a content is stored more than once, the most shared
+    /// copy wins
+    fn load_shared_blocks(&mut self) -> io::Result<()> {
+        let mut refs: HashMap<&(PathBuf, u64, u64), usize> = HashMap::new();
+        for location in self.index.values() {
+            *refs.entry(location).or_default() += 1;
+        }
+
+        let mut shared: HashMap<u64, SharedBlock> = HashMap::new();
+        for (location, refs) in refs {
+            let (path, pos, len) = location;
+            let Ok(block) = self.read_block_at(path, *pos, *len) else {
+                continue;
+            };
+            let Ok(Some(entry)) = block.verified_entry() else {
+                continue;
+            };
+            let candidate = SharedBlock {
+                location: location.clone(),
+                alias_target: AliasTarget::of(&block, entry.epoch()),
+                refs,
+            };
+            let Ok(hash) = content_hash(&entry) else {
+                continue;
+            };
+            match shared.entry(hash) {
+                std::collections::hash_map::Entry::Vacant(slot) => {
+                    slot.insert(candidate);
+                }
+                std::collections::hash_map::Entry::Occupied(mut slot) => {
+                    if (refs, &candidate.location) > (slot.get().refs, &slot.get().location) {
+                        slot.insert(candidate);
+                    }
+                }
+            }
+        }
+        self.shared = shared;
+        Ok(())
+    }
+
+    // Helper methods
+    fn rotate_file(&mut self) -> Result<(), Stage2Error> {
+        // Nothing left behind in the old file may stay unsynced
+        self.sync()?;
+
+        let path = self.new_file_path()?;
+        self.store.put(&path, &[])?;
+        self.current_file_path = Some(path);
+        self.current_file_entries = 0;
+        Ok(())
+    }
+
+    /// The file every entry in shard `shard` is appended to
+    fn shard_path(&self, shard: u16) -> PathBuf {
+        shard_path(&self.config.storage_path, shard)
+    }
+
+    fn new_file_path(&self) -> io::Result<PathBuf> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // Several rotations can happen within one second; give each its own file
+        let mut path = self.config.storage_path.join(format!("mem_{}.bin", timestamp));
+        let mut sequence = 1;
+        while self.store.exists(&path)? {
+            path = self.config.storage_path.join(format!("mem_{}_{}.bin", timestamp, sequence));
+            sequence += 1;
+        }
+        Ok(path)
+    }
+
+    /// Loads the index saved by `close`, returning false if there is none
+    /// or it no longer matches the storage files
+    ///
+    /// The saved index is deleted once read; only a later `close` writes a
+    /// new one, so a crash can never leave a stale copy behind.
+    fn load_saved_index(&mut self) -> io::Result<bool> {
+        let path = self.index_path();
+        let bytes = match self.store.get(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        self.store.delete(&path)?;
+
+        let Some(saved) = SavedIndex::decode(&bytes) else {
+            return Ok(false);
+        };
+        let mut files = self.data_files()?;
+        files.sort();
+        let mut saved_files: Vec<&PathBuf> = saved.files.iter().map(|(path, _)| path).collect();
+        saved_files.sort();
+        if !files.iter().eq(saved_files) {
+            return Ok(false);
+        }
+        for (path, len) in &saved.files {
+            if self.store.len(path)? != *len {
+                return Ok(false);
+            }
+        }
+
+        self.index = saved.entries
+            .into_iter()
+            .map(|(epoch, path, pos, len)| (epoch, (path, pos, len)))
+            .collect();
+        for (token, epoch) in saved.tokens {
+            self.tokens.insert(token, epoch);
+        }
+        Ok(true)
+    }
+
+    fn load_index(&mut self) -> io::Result<()> {
+        // Every readable block, superseded ones included, so aliases can
+        // find the exact block they were written against
+        let mut blocks = HashMap::new();
+        // Token of the block each epoch ends up pointing at
+        let mut tokens = HashMap::new();
+
+        // Scan the storage files and rebuild the index
+        for path in self.data_files()? {
+            let bytes = self.store.get(&path)?;
+            let file_len = bytes.len() as u64;
+            let mut pos = 0;
+
+            while file_len - pos >= LENGTH_PREFIX_SIZE {
+                let prefix = &bytes[pos as usize..(pos + LENGTH_PREFIX_SIZE) as usize];
+                let len = u64::from_le_bytes(prefix.try_into().unwrap());
+                let block_pos = pos + LENGTH_PREFIX_SIZE;
+                if len > file_len - block_pos {
+                    // Trailing partial block
+                    break;
+                }
+
+                let buffer = &bytes[block_pos as usize..(block_pos + len) as usize];
+                let decoded = deserialize::<MemoryBlock>(buffer).ok().and_then(|block| {
+                    let raw = block.raw_payload().ok()?;
+                    Some((MemoryEntry::from_bytes(&raw).ok()?, block))
+                });
+                if let Some((entry, block)) = decoded {
+                    let location = (path.clone(), block_pos, len);
+                    blocks.insert(AliasTarget::of(&block, entry.epoch()), (location.clone(), entry.token()));
+                    self.index.insert(entry.epoch(), location);
+                    tokens.insert(entry.epoch(), entry.token());
+                }
+                pos = block_pos + len;
+            }
+
+            // Drop a torn final block so later appends stay reachable
+            if pos < file_len {
+                self.store.truncate(&path, pos)?;
+            }
+        }
+
+        // An epoch with a block of its own was rewritten after it was
+        // aliased, so the block wins
+        for (alias, target) in self.read_aliases()? {
+            if self.index.contains_key(&alias) {
+                continue;
+            }
+            if let Some((location, token)) = blocks.get(&target) {
+                self.index.insert(alias, location.clone());
+                tokens.insert(alias, *token);
+            }
+        }
+
+        for (epoch, token) in tokens {
+            self.tokens.insert(token, epoch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryBlockStore;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_stage2_storage() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        
+        // Store some entries
+        let entries = vec![
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+            MemoryEntry::with_links(2, 101, 600, 0, 0),
+        ];
+        
+        stage2.accept_entries(entries)?;
+        
+        // Retrieve and verify
+        let entry = stage2.get_entry(1)?;
+        assert_eq!(entry.token(), 100);
+        assert_eq!(entry.weight(), 500);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_entry_reads_single_block() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+            MemoryEntry::with_links(2, 101, 600, 1, 0),
+            MemoryEntry::with_links(3, 102, 700, 2, 1),
+        ])?;
+
+        let entry = stage2.get_entry(2)?;
+        assert_eq!(entry.epoch(), 2);
+        assert_eq!(entry.token(), 101);
+        assert_eq!(entry.weight(), 600);
+        assert_eq!(entry.links(), (1, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_rebuilt_after_reopen() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let entries: Vec<MemoryEntry> = (1..=50)
+            .map(|epoch| MemoryEntry::with_links(epoch, epoch as u16 + 100, 500, 0, 0))
+            .collect();
+
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.accept_entries(entries)?;
+        drop(stage2);
+
+        let reopened = Stage2::new(config)?;
+        for epoch in 1..=50 {
+            let entry = reopened.get_entry(epoch)?;
+            assert_eq!(entry.epoch(), epoch);
+            assert_eq!(entry.token(), epoch as u16 + 100);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_replaces_token() -> Result<(), Stage2Error> {
+        for dedup_by_content in [false, true] {
+            let temp_dir = tempdir().unwrap();
+            let config = Stage2Config {
+                storage_path: temp_dir.path().to_path_buf(),
+                dedup_by_content,
+                ..Stage2Config::default()
+            };
+
+            let mut stage2 = Stage2::new(config.clone())?;
+            stage2.accept_entries(vec![
+                MemoryEntry::with_links(10, 100, 500, 0, 0),
+                MemoryEntry::with_links(11, 100, 600, 0, 0),
+            ])?;
+            stage2.accept_entries(vec![MemoryEntry::with_links(10, 200, 700, 0, 0)])?;
+
+            let epochs = |stage2: &Stage2, token| -> Result<Vec<u32>, Stage2Error> {
+                Ok(stage2.find_by_token(token)?.iter().map(MemoryEntry::epoch).collect())
+            };
+            assert_eq!(epochs(&stage2, 100)?, vec![11]);
+            assert_eq!(epochs(&stage2, 200)?, vec![10]);
+            drop(stage2);
+
+            let reopened = Stage2::new(config)?;
+            assert_eq!(epochs(&reopened, 100)?, vec![11]);
+            assert_eq!(epochs(&reopened, 200)?, vec![10]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_old_entries() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config.clone())?;
+        // An epoch this small is far older than compression_age
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(1000, 100, 500, 0, 0),
+            MemoryEntry::with_links(2000, 101, 600, 0, 0),
+        ])?;
+
+        // Single entries do not shrink, so by default they stay as they are
+        stage2.compress_old_entries()?;
+        assert!(!stage2.read_block(1000)?.is_compressed());
+        drop(stage2);
+
+        let config = Stage2Config {
+            max_compression_ratio: f32::INFINITY,
+            ..config
+        };
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.compress_old_entries()?;
+
+        assert!(stage2.read_block(1000)?.is_compressed());
+        let entry = stage2.get_entry(1000)?;
+        assert_eq!(entry.token(), 100);
+        assert_eq!(stage2.get_entry(2000)?.token(), 101);
+
+        // The appended copy must also win after an index rebuild
+        drop(stage2);
+        let reopened = Stage2::new(config)?;
+        assert!(reopened.read_block(1000)?.is_compressed());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_payloads_round_trip() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 1000,
+            compression_age: 3600,
+            max_compression_ratio: f32::INFINITY,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        let entries: Vec<MemoryEntry> = (1..=200)
+            .map(|epoch| MemoryEntry::with_links(epoch, 42, 500, epoch - 1, 0))
+            .collect();
+        stage2.accept_entries(entries)?;
+
+        let raw_len = stage2.read_block(100)?.payload.len();
+        stage2.compress_old_entries()?;
+
+        let block = stage2.read_block(100)?;
+        assert!(block.is_compressed());
+        assert_ne!(block.payload, serialize(&stage2.get_entry(100)?)?);
+        assert_eq!(block.raw_payload()?.len(), raw_len);
+
+        for epoch in 1..=200 {
+            let entry = stage2.get_entry(epoch)?;
+            assert_eq!(entry.token(), 42);
+            assert_eq!(entry.links(), (epoch - 1, 0));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rapid_inserts_stay_indexed() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 4,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        for epoch in 1..=30 {
+            stage2.accept_entries(vec![MemoryEntry::with_links(epoch, 7, 500, 0, 0)])?;
+        }
+
+        for epoch in 1..=30 {
+            assert_eq!(stage2.get_entry(epoch)?.epoch(), epoch);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_retrieval_across_files() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 2,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        stage2.accept_entries((1..=4)
+            .map(|epoch| MemoryEntry::with_links(epoch, epoch as u16 * 10, 500, 0, 0))
+            .collect())?;
+
+        let files = std::fs::read_dir(temp_dir.path())?
+            .filter(|entry| {
+                entry.as_ref().is_ok_and(|e| e.path().extension().is_some_and(|ext| ext == "bin"))
+            })
+            .count();
+        assert_eq!(files, 2);
+
+        let found = stage2.get_entries(&[4, 99, 1, 3])?;
+        let epochs: Vec<u32> = found.iter().map(|(epoch, _)| *epoch).collect();
+        assert_eq!(epochs, vec![4, 1, 3]);
+        for (epoch, entry) in found {
+            assert_eq!(entry.token(), epoch as u16 * 10);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_epoch_iteration_and_ranges() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        assert!(stage2.is_empty());
+
+        // Insert out of order; iteration must still be ascending
+        stage2.accept_entries([30, 10, 50, 20, 40]
+            .into_iter()
+            .map(|epoch| MemoryEntry::with_links(epoch, 1, 500, 0, 0))
+            .collect())?;
+
+        assert_eq!(stage2.len(), 5);
+        assert_eq!(stage2.iter_epochs().collect::<Vec<_>>(), vec![10, 20, 30, 40, 50]);
+        assert_eq!(stage2.range(20, 40).collect::<Vec<_>>(), vec![20, 30]);
+        assert_eq!(stage2.range(0, 11).collect::<Vec<_>>(), vec![10]);
+        assert_eq!(stage2.range(41, 50).count(), 0);
+        assert_eq!(stage2.range(50, 10).count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_truncates_torn_write() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+            MemoryEntry::with_links(2, 101, 600, 0, 0),
+        ])?;
+        let path = stage2.current_file_path.clone().unwrap();
+        let intact_len = std::fs::metadata(&path)?.len();
+
+        stage2.accept_entries(vec![MemoryEntry::with_links(3, 102, 700, 0, 0)])?;
+        let full_len = std::fs::metadata(&path)?.len();
+        drop(stage2);
+
+        // Simulate a crash partway through writing the third block
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(intact_len + 5)?;
+        WriteAheadLog::new(temp_dir.path()).begin(&FileBlockStore, &WalRecord {
+            epoch: 3,
+            file: path.clone(),
+            offset: intact_len,
+            len: full_len - intact_len,
+        })?;
+
+        let mut recovered = Stage2::new(config.clone())?;
+        assert_eq!(std::fs::metadata(&path)?.len(), intact_len);
+        assert_eq!(std::fs::metadata(temp_dir.path().join("stage2.wal"))?.len(), 0);
+        assert_eq!(recovered.get_entry(1)?.token(), 100);
+        assert_eq!(recovered.get_entry(2)?.token(), 101);
+        assert!(matches!(recovered.get_entry(3), Err(Stage2Error::NotFound(3))));
+
+        // New writes land on a clean tail and survive another restart
+        recovered.accept_entries(vec![MemoryEntry::with_links(4, 103, 800, 0, 0)])?;
+        drop(recovered);
+        let reopened = Stage2::new(config)?;
+        assert_eq!(reopened.iter_epochs().collect::<Vec<_>>(), vec![1, 2, 4]);
+        assert_eq!(reopened.get_entry(4)?.token(), 103);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_dump_and_load() -> Result<(), Stage2Error> {
+        let source_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+        let config = |dir: &tempfile::TempDir| Stage2Config {
+            storage_path: dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut source = Stage2::new(config(&source_dir))?;
+        source.accept_entries(vec![
+            MemoryEntry::with_links(2, 101, 600, 1, 0),
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+        ])?;
+        let json = source.export_json()?;
+
+        let mut target = Stage2::new(config(&target_dir))?;
+        assert_eq!(target.import_json(&json)?, 2);
+        assert_eq!(target.iter_epochs().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(target.get_entry(2)?.links(), (1, 0));
+        assert!(matches!(target.import_json("not json"), Err(Stage2Error::Json(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_all_reports_corrupt_blocks() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        stage2.accept_entries((1..=10)
+            .map(|epoch| MemoryEntry::with_links(epoch, 100, 500, 0, 0))
+            .collect())?;
+        assert!(stage2.verify_all()?.is_empty());
+
+        // Flip the first payload byte, just past the payload's own length
+        for epoch in [3, 7] {
+            let (path, pos, _) = stage2.index[&epoch].clone();
+            let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let mut byte = [0u8; 1];
+            file.seek(SeekFrom::Start(pos + 8))?;
+            file.read_exact(&mut byte)?;
+            file.seek(SeekFrom::Start(pos + 8))?;
+            file.write_all(&[byte[0] ^ 0xFF])?;
+        }
+
+        assert_eq!(stage2.verify_all()?, vec![3, 7]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_each_entry_streams_in_epoch_order() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let mut stage2 = Stage2::new(Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 4,
+            ..Stage2Config::default()
+        })?;
+        stage2.accept_entries((1..=10)
+            .map(|epoch| MemoryEntry::with_links(epoch, 100, epoch as i16 * 10, 0, 0))
+            .collect())?;
+
+        let mut total = 0i32;
+        let mut seen = Vec::new();
+        stage2.for_each_entry(|epoch, entry| {
+            total += entry.weight() as i32;
+            seen.push(epoch);
+            ControlFlow::Continue(())
+        })?;
+        assert_eq!(total, 550);
+        assert_eq!(seen, (1..=10).collect::<Vec<u32>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_each_entry_stops_early() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let mut stage2 = Stage2::new(Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        })?;
+        stage2.accept_entries((1..=10)
+            .map(|epoch| MemoryEntry::with_links(epoch, 100, 500, 0, 0))
+            .collect())?;
+
+        let mut first = Vec::new();
+        stage2.for_each_entry(|epoch, _| {
+            first.push(epoch);
+            if first.len() == 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        })?;
+        assert_eq!(first, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mixed_algorithm_store_stays_readable() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let lz4_config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            shard_by: ShardBy::Time,
+            compression_age: 3600,
+            compression_algorithm: CompressionAlgorithm::LZ4,
+            checksum_algorithm: ChecksumAlgorithm::Crc32,
+            sync_mode: SyncMode::PerWrite,
+            clock: Arc::new(MemoryClock::default()),
+            // A lone entry is too small to shrink; compress it anyway
+            max_compression_ratio: f32::INFINITY,
+            dedup_by_content: false,
+        };
+
+        let mut stage2 = Stage2::new(lz4_config.clone())?;
+        stage2.accept_entries(vec![MemoryEntry::with_links(1000, 100, 500, 0, 0)])?;
+        stage2.compress_old_entries()?;
+        drop(stage2);
+
+        let zstd_config = Stage2Config {
+            compression_algorithm: CompressionAlgorithm::zstd(),
+            ..lz4_config
+        };
+        let mut stage2 = Stage2::new(zstd_config)?;
+        stage2.accept_entries(vec![MemoryEntry::with_links(2000, 101, 600, 0, 0)])?;
+        stage2.compress_old_entries()?;
+
+        assert_eq!(stage2.read_block(1000)?.algorithm, CompressionAlgorithm::LZ4);
+        assert_eq!(stage2.read_block(2000)?.algorithm, CompressionAlgorithm::zstd());
+        assert_eq!(stage2.get_entry(1000)?.token(), 100);
+        assert_eq!(stage2.get_entry(2000)?.token(), 101);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_reclaims_removed_entries() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.accept_entries((1..=10)
+            .map(|epoch| MemoryEntry::with_links(epoch, epoch as u16, 500, 0, 0))
+            .collect())?;
+        let path = stage2.current_file_path.clone().unwrap();
+        let before = std::fs::metadata(&path)?.len();
+
+        for epoch in (2..=10).step_by(2) {
+            stage2.remove_entry(epoch)?;
+        }
+        let stats = stage2.compact()?;
+
+        let after = std::fs::metadata(&path)?.len();
+        assert!(after < before);
+        assert_eq!(stats.bytes_reclaimed, before - after);
+        assert_eq!(stats.blocks_moved, 4);
+        assert_eq!(stats.files_removed, 0);
+
+        for epoch in (1..=9).step_by(2) {
+            assert_eq!(stage2.get_entry(epoch)?.token(), epoch as u16);
+        }
+
+        // Appends keep working against the rewritten file, and removed
+        // entries stay gone after a reopen
+        stage2.accept_entries(vec![MemoryEntry::with_links(11, 11, 500, 0, 0)])?;
+        drop(stage2);
+        let reopened = Stage2::new(config)?;
+        assert_eq!(reopened.iter_epochs().collect::<Vec<_>>(), vec![1, 3, 5, 7, 9, 11]);
+        assert_eq!(reopened.get_entry(11)?.token(), 11);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep_expired() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 10,
+            compression_age: 3600,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(1, 100, 30000, 0, 0).with_expiry(50),
+            MemoryEntry::with_links(2, 101, 500, 0, 0).with_expiry(500),
+            MemoryEntry::with_links(3, 102, 500, 0, 0),
+            MemoryEntry::builder().epoch(4).token(103).expires_at(50).pinned(true).build(),
+        ])?;
+        assert_eq!(stage2.get_entry(2)?.expires_at(), Some(500));
+
+        // Pinned entries outlive their expiry
+        let expired = stage2.sweep_expired(100)?;
+        assert_eq!(expired.iter().map(MemoryEntry::epoch).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(stage2.iter_epochs().collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert!(stage2.find_by_token(100)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mixed_checksum_algorithms() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let algorithms = [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::XxHash64,
+            ChecksumAlgorithm::Blake3,
+        ];
+
+        // Write one entry per algorithm, reopening with a new config each time
+        for (epoch, algorithm) in (1..).zip(algorithms) {
+            let mut stage2 = Stage2::new(Stage2Config {
+                storage_path: temp_dir.path().to_path_buf(),
+                checksum_algorithm: algorithm,
+                ..Stage2Config::default()
+            })?;
+            stage2.accept_entries(vec![MemoryEntry::with_links(epoch, epoch as u16, 500, 0, 0)])?;
+        }
+
+        let stage2 = Stage2::new(Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        })?;
+        for (epoch, algorithm) in (1..).zip(algorithms) {
+            assert_eq!(stage2.read_block(epoch)?.checksum_algorithm, algorithm);
+            assert_eq!(stage2.get_entry(epoch)?.token(), epoch as u16);
+        }
+
+        // A single flipped payload byte is caught whatever the algorithm
+        let mut stage2 = stage2;
+        for epoch in 1..=3 {
+            let (path, pos, _) = stage2.index[&epoch].clone();
+            let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let mut byte = [0u8; 1];
+            file.seek(SeekFrom::Start(pos + 8))?;
+            file.read_exact(&mut byte)?;
+            file.seek(SeekFrom::Start(pos + 8))?;
+            file.write_all(&[byte[0] ^ 0x01])?;
+        }
+        assert_eq!(stage2.verify_all()?, vec![1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manual_sync_mode() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            sync_mode: SyncMode::Manual,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.accept_entries((1..=5).map(|epoch| MemoryEntry::with_links(epoch, 100, 500, 0, 0)).collect())?;
+        assert_eq!(stage2.unsynced_writes(), 5);
+        assert_eq!(stage2.get_entry(3)?.token(), 100);
+
+        stage2.sync()?;
+        assert_eq!(stage2.unsynced_writes(), 0);
+        drop(stage2);
+
+        // Simulate a crash that tore the last unsynced block
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.accept_entries(vec![MemoryEntry::with_links(6, 101, 500, 0, 0)])?;
+        assert_eq!(stage2.unsynced_writes(), 1);
+        let path = stage2.current_file_path.clone().unwrap();
+        drop(stage2);
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(file.metadata()?.len() - 3)?;
+
+        let stage2 = Stage2::new(config)?;
+        assert_eq!(stage2.len(), 5);
+        assert!(matches!(stage2.get_entry(6), Err(Stage2Error::NotFound(6))));
+        assert_eq!(std::fs::metadata(&path)?.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_sync_mode() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let mut stage2 = Stage2::new(Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            sync_mode: SyncMode::Batched(3),
+            ..Stage2Config::default()
+        })?;
+
+        stage2.accept_entries((1..=7).map(|epoch| MemoryEntry::with_links(epoch, 100, 500, 0, 0)).collect())?;
+        assert_eq!(stage2.unsynced_writes(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_and_merge() -> Result<(), Stage2Error> {
+        let dir_a = tempdir().unwrap();
+        let dir_b = tempdir().unwrap();
+        let open = |dir: &tempfile::TempDir| Stage2::new(Stage2Config {
+            storage_path: dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        });
+
+        let mut a = open(&dir_a)?;
+        let mut b = open(&dir_b)?;
+        a.accept_entries(vec![
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+            MemoryEntry::with_links(2, 101, 300, 0, 0),
+            MemoryEntry::with_links(3, 102, 900, 0, 0),
+        ])?;
+        b.accept_entries(vec![
+            MemoryEntry::with_links(2, 101, 700, 0, 0),
+            MemoryEntry::with_links(3, 102, 900, 0, 0),
+            MemoryEntry::with_links(4, 103, 400, 0, 0),
+        ])?;
+        // A compressed but identical copy still matches
+        b.compress_old_entries()?;
+
+        assert_eq!(diff(&a, &b)?, StoreDiff {
+            only_in_a: vec![1],
+            only_in_b: vec![4],
+            differing: vec![2],
+        });
+
+        assert_eq!(a.merge_into(&b, MergeStrategy::PreferHigherWeight)?, 2);
+        assert_eq!(a.get_entry(2)?.weight(), 700);
+        assert_eq!(a.get_entry(4)?.token(), 103);
+        assert_eq!(a.find_by_token(101)?.len(), 1);
+        assert!(diff(&a, &b)?.differing.is_empty());
+
+        // Nothing left to take, and the lighter copy never wins
+        assert_eq!(a.merge_into(&b, MergeStrategy::PreferHigherWeight)?, 0);
+        assert_eq!(b.merge_into(&a, MergeStrategy::PreferHigherWeight)?, 1);
+        assert_eq!(b.get_entry(2)?.weight(), 700);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_block_store() -> Result<(), Stage2Error> {
+        let store = InMemoryBlockStore::new();
+        let config = Stage2Config {
+            storage_path: PathBuf::from("stage2"),
+            entries_per_file: 2,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::with_store(config.clone(), store)?;
+        stage2.accept_entries((1..=5).map(|epoch| MemoryEntry::with_links(epoch, 100 + epoch as u16, 500, 0, 0)).collect())?;
+        stage2.compress_old_entries()?;
+        assert_eq!(stage2.get_entry(4)?.token(), 104);
+        assert_eq!(stage2.disk_usage()?.files, 3);
+        assert!(!config.storage_path.exists());
+
+        stage2.remove_entry(1)?;
+        let stats = stage2.compact()?;
+        assert!(stats.bytes_reclaimed > 0);
+
+        // Reopening over the same store rebuilds the index from it
+        let reopened = Stage2::with_store(config, stage2.store)?;
+        assert_eq!(reopened.len(), 4);
+        assert_eq!(reopened.find_by_token(103)?[0].epoch(), 3);
+        assert!(matches!(reopened.get_entry(1), Err(Stage2Error::NotFound(1))));
+
+        Ok(())
+    }
+
+    /// File store that counts whole-file reads of storage files
+    struct CountingStore {
+        data_reads: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl BlockStore for CountingStore {
+        fn put(&self, key: &Path, bytes: &[u8]) -> io::Result<()> {
+            FileBlockStore.put(key, bytes)
+        }
+
+        fn get(&self, key: &Path) -> io::Result<Vec<u8>> {
+            let is_data = key.extension().is_some_and(|ext| ext == "bin")
+                && key.file_name().is_some_and(|name| name != INDEX_FILE_NAME);
+            if is_data {
+                self.data_reads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            FileBlockStore.get(key)
+        }
+
+        fn delete(&self, key: &Path) -> io::Result<()> {
+            FileBlockStore.delete(key)
+        }
+
+        fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            FileBlockStore.list(dir)
+        }
+
+        fn modified(&self, key: &Path) -> io::Result<std::time::SystemTime> {
+            FileBlockStore.modified(key)
+        }
+
+        fn append(&self, key: &Path, bytes: &[u8]) -> io::Result<u64> {
+            FileBlockStore.append(key, bytes)
+        }
+
+        fn get_range(&self, key: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+            FileBlockStore.get_range(key, offset, len)
+        }
+
+        fn len(&self, key: &Path) -> io::Result<u64> {
+            FileBlockStore.len(key)
+        }
+
+        fn truncate(&self, key: &Path, len: u64) -> io::Result<()> {
+            FileBlockStore.truncate(key, len)
+        }
+
+        fn sync(&self, key: &Path) -> io::Result<()> {
+            FileBlockStore.sync(key)
+        }
+    }
+
+    #[test]
+    fn test_saved_index_skips_rescan() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 2,
+            ..Stage2Config::default()
+        };
+        let data_reads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let open = || Stage2::with_store(config.clone(), CountingStore { data_reads: data_reads.clone() });
+        let reads = || data_reads.swap(0, std::sync::atomic::Ordering::Relaxed);
+
+        let mut stage2 = open()?;
+        stage2.accept_entries((1..=5).map(|epoch| MemoryEntry::with_links(epoch, 100 + epoch as u16, 500, 0, 0)).collect())?;
+        stage2.close()?;
+        assert!(temp_dir.path().join(INDEX_FILE_NAME).exists());
+        reads();
+
+        // A clean close lets the next open skip every storage file
+        let stage2 = open()?;
+        assert_eq!(reads(), 0);
+        assert_eq!(stage2.len(), 5);
+        assert_eq!(stage2.get_entry(4)?.token(), 104);
+        assert_eq!(stage2.find_by_token(103)?[0].epoch(), 3);
+        assert!(!temp_dir.path().join(INDEX_FILE_NAME).exists());
+
+        // Without a close, or with a file changed since, it rescans
+        drop(stage2);
+        reads();
+        assert_eq!(open()?.len(), 5);
+        assert_eq!(reads(), 3);
+
+        let stage2 = open()?;
+        let path = stage2.index[&5].0.clone();
+        stage2.close()?;
+        OpenOptions::new().append(true).open(&path)?.write_all(&[1, 2, 3])?;
+        reads();
+        assert_eq!(open()?.len(), 5);
+        assert_eq!(reads(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedup_by_content_shares_blocks() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            dedup_by_content: true,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(1, 100, 500, 7, 0),
+            MemoryEntry::with_links(2, 100, 500, 7, 0),
+            MemoryEntry::with_links(3, 100, 501, 7, 0),
+        ])?;
+
+        let block_size = |stage2: &Stage2, epoch| LENGTH_PREFIX_SIZE + stage2.index[&epoch].2;
+        assert_eq!(stage2.index[&1], stage2.index[&2]);
+        assert_eq!(stage2.disk_usage()?.bytes, block_size(&stage2, 1) + block_size(&stage2, 3));
+        assert_eq!(stage2.get_entry(2)?.epoch(), 2);
+        assert_eq!(stage2.get_entry(2)?.links(), (7, 0));
+        assert_eq!(stage2.find_by_token(100)?.len(), 3);
+
+        // The shared block outlives the epoch it was written for
+        stage2.remove_entry(1)?;
+        assert_eq!(stage2.compact()?.files_removed, 0);
+        assert_eq!(stage2.get_entry(2)?.weight(), 500);
+
+        // A rescan finds the alias through the log
+        drop(stage2);
+        let mut reopened = Stage2::new(config)?;
+        assert_eq!(reopened.get_entry(2)?.epoch(), 2);
+        assert!(reopened.find_by_token(100)?.iter().any(|entry| entry.epoch() == 2));
+
+        // Dropping the last reference frees the block
+        reopened.remove_entry(2)?;
+        reopened.remove_entry(1)?;
+        reopened.compact()?;
+        assert_eq!(reopened.disk_usage()?.bytes, block_size(&reopened, 3));
+        assert!(reopened.shared.values().all(|shared| shared.alias_target.epoch == 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_entry_supersedes_block() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(1, 100, 500, 0, 0),
+            MemoryEntry::with_links(2, 101, 600, 0, 0),
+        ])?;
+        let old_location = stage2.index[&1].clone();
+        let before = stage2.disk_usage()?.bytes;
+
+        stage2.update_entry(1, |entry| {
+            entry.adjust_weight(250);
+            *entry = MemoryEntry::with_links(99, 102, entry.weight(), 2, 0);
+        })?;
+
+        let entry = stage2.get_entry(1)?;
+        assert_eq!((entry.epoch(), entry.token(), entry.weight(), entry.links()), (1, 102, 750, (2, 0)));
+        assert_ne!(stage2.index[&1], old_location);
+        assert!(stage2.find_by_token(100)?.is_empty());
+        assert_eq!(stage2.find_by_token(102)?.len(), 1);
+        assert!(!stage2.contains(99));
+        assert!(matches!(stage2.update_entry(5, |_| {}), Err(Stage2Error::NotFound(5))));
+
+        // The superseded block is dead space until compaction
+        let grown = stage2.disk_usage()?.bytes;
+        assert!(grown > before);
+        assert_eq!(stage2.compact()?.bytes_reclaimed, grown - before);
+
+        // A rescan picks the newer block
+        drop(stage2);
+        assert_eq!(Stage2::new(config)?.get_entry(1)?.weight(), 750);
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_range_sharding() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            entries_per_file: 2,
+            shard_by: ShardBy::TokenRange(4),
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config.clone())?;
+        stage2.accept_entries((0..12).map(|epoch| MemoryEntry::with_links(epoch, 100 + epoch as u16, 500, 0, 0)).collect())?;
+        // A stored epoch keeps its shard under a new token
+        stage2.accept_entries(vec![MemoryEntry::with_links(1, 102, 900, 0, 0)])?;
+
+        let mut files = stage2.data_files()?;
+        files.sort();
+        let expected: Vec<PathBuf> = (0..4).map(|shard| temp_dir.path().join(format!("shard_{}.bin", shard))).collect();
+        assert_eq!(files, expected);
+        for epoch in 0..12 {
+            assert_eq!(stage2.index[&epoch].0, expected[((100 + epoch) % 4) as usize]);
+        }
+
+        // Every entry for a token is still found in its shard after a rescan
+        drop(stage2);
+        let stage2 = Stage2::new(config)?;
+        assert_eq!(stage2.len(), 12);
+        assert_eq!(stage2.get_entry(1)?.weight(), 900);
+        assert_eq!(stage2.index[&1].0, expected[1]);
+        let epochs: Vec<u32> = stage2.find_by_token(102)?.iter().map(|entry| entry.epoch()).collect();
+        assert_eq!(epochs, vec![1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_ordered_across_files() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let mut stage2 = Stage2::new(Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            shard_by: ShardBy::TokenRange(2),
+            ..Stage2Config::default()
+        })?;
+        // Odd and even tokens land in different files, interleaving epochs
+        stage2.accept_entries((1..=10).rev()
+            .map(|epoch| MemoryEntry::with_links(epoch, epoch as u16, 500, 0, 0))
+            .collect())?;
+        assert_eq!(stage2.data_files()?.len(), 2);
+        assert_ne!(stage2.index[&3].0, stage2.index[&4].0);
+
+        let scanned: Vec<u32> = stage2.scan_ordered(2, 9)
+            .map(|entry| entry.map(|entry| entry.epoch()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(scanned, (2..9).collect::<Vec<u32>>());
+        assert_eq!(stage2.scan_ordered(9, 2).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_weight_and_token_projections() -> Result<(), Stage2Error> {
+        let temp_dir = tempdir().unwrap();
+        let config = Stage2Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            compression_age: 3600,
+            max_compression_ratio: f32::INFINITY,
+            ..Stage2Config::default()
+        };
+
+        let mut stage2 = Stage2::new(config)?;
+        stage2.accept_entries(vec![
+            MemoryEntry::with_links(1, 7, -250, 3, 4).with_expiry(50_000),
+            MemoryEntry::with_links(u32::MAX, 9, 1200, 0, 0),
+        ])?;
+        // Projections read compressed blocks too
+        stage2.compress_old_entries()?;
+        assert!(stage2.read_block(1)?.is_compressed());
+
+        assert_eq!(stage2.get_weight(1)?, -250);
+        assert_eq!(stage2.get_token(1)?, 7);
+        assert!(!stage2.read_block(u32::MAX)?.is_compressed());
+        assert_eq!(stage2.get_weight(u32::MAX)?, 1200);
+        assert_eq!(stage2.get_token(u32::MAX)?, 9);
+        assert!(matches!(stage2.get_weight(2), Err(Stage2Error::NotFound(2))));
+        assert!(matches!(stage2.get_token(2), Err(Stage2Error::NotFound(2))));
+        Ok(())
+    }
+}
@@ -1,12 +1,26 @@
+use super::checksum::ChecksumAlgorithm;
 use super::entry::MemoryEntry;
 use super::compression::{Compressor, CompressionAlgorithm, CompressionMetrics};
+use super::error_correction::{ErrorCorrectionError, ErrorCorrectionMetrics, ReedSolomonEC};
+use super::token_index::TokenIndex;
 use bincode::{deserialize, serialize};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use crate::storage::{BlockStore, FileBlockStore};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use thiserror::Error;
 
+/// Number of locks epoch writes are spread over; writes to epochs in
+/// different stripes run in parallel
+const WRITE_LOCK_STRIPES: usize = 64;
+
+/// Most shards a Reed-Solomon layout over GF(2^8) can have; bounds the
+/// search for an erasure-coded memory's shards when none have been read yet
+const MAX_ERASURE_SHARDS: usize = 256;
+
 #[derive(Error, Debug)]
 pub enum Stage3Error {
     #[error("IO error: {0}")]
@@ -17,15 +31,77 @@ pub enum Stage3Error {
     NotFound(u32),
     #[error("Redundancy check failed: {0}")]
     RedundancyError(String),
+    #[error("Error correction failed: {0}")]
+    ErrorCorrection(#[from] ErrorCorrectionError),
+    #[error("Promoted {stored} core memories before failing: {source}")]
+    PartialPromotion {
+        stored: usize,
+        source: Box<Stage3Error>,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct Stage3Config {
     pub storage_path: PathBuf,
     pub redundancy_path: PathBuf,
+    /// Directories that each hold a full copy of every core memory, tried
+    /// in order on reads. Empty means a primary in `storage_path` and a
+    /// backup in `redundancy_path`.
+    pub replica_paths: Vec<PathBuf>,
     pub compression_algorithm: CompressionAlgorithm,
-    pub min_weight_threshold: u16,
+    pub min_weight_threshold: i16,
     pub min_age_days: u32,
+    /// Number of Reed-Solomon data shards per core memory
+    pub data_shards: usize,
+    /// Number of Reed-Solomon parity shards per core memory
+    pub parity_shards: usize,
+    /// Checksum written into new core memory blocks
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Sync every replica and shard, and the directories holding them,
+    /// before a store or repair returns
+    pub durable: bool,
+    /// How new core memories are made redundant; memories already stored
+    /// keep the mode they were written with
+    pub redundancy: RedundancyMode,
+    /// Whether reads may rebuild a memory from Reed-Solomon shards
+    pub recovery_policy: RecoveryPolicy,
+}
+
+/// What a read does when no intact copy of a core memory is left
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecoveryPolicy {
+    /// Fail with a `RedundancyError` unless a full replica, or for an
+    /// erasure-coded memory every shard, verifies
+    Strict,
+    /// Rebuild from whatever shards survive and repair the damaged files
+    ///
+    /// A rebuilt entry is checked against the checksum its shards carry, so
+    /// it is either exactly what was stored or not returned at all.
+    #[default]
+    BestEffort,
+}
+
+/// How `Stage3` protects a core memory against lost or damaged files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RedundancyMode {
+    /// A full copy in every replica directory, plus `data_shards` and
+    /// `parity_shards` Reed-Solomon shards to fall back on
+    #[default]
+    Mirror,
+    /// Only Reed-Solomon shards, dealt round-robin over the replica
+    /// directories
+    ///
+    /// Takes `(data + parity) / data` times the entry's size instead of a
+    /// full copy per directory. Losing a directory is survivable only if
+    /// `parity` is at least the number of shards each one holds.
+    Erasure { data: usize, parity: usize },
+}
+
+/// Which layout a stored core memory was written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StoredAs {
+    Mirror,
+    Erasure,
 }
 
 impl Default for Stage3Config {
@@ -33,9 +109,16 @@ impl Default for Stage3Config {
         Self {
             storage_path: PathBuf::from("storage/stage3"),
             redundancy_path: PathBuf::from("storage/stage3_backup"),
+            replica_paths: Vec::new(),
             compression_algorithm: CompressionAlgorithm::LZ4,
             min_weight_threshold: 800,  // High importance memories only
             min_age_days: 30,          // At least a month old
+            data_shards: 4,
+            parity_shards: 2,          // Survives losing any two shards
+            checksum_algorithm: ChecksumAlgorithm::Crc32,
+            durable: true,
+            redundancy: RedundancyMode::Mirror,
+            recovery_policy: RecoveryPolicy::BestEffort,
         }
     }
 }
@@ -44,58 +127,213 @@ impl Default for Stage3Config {
 struct CoreMemoryBlock {
     entry: MemoryEntry,
     metrics: CompressionMetrics,
-    checksum: u32,
-    parity: Vec<u8>,  // For error correction
+    checksum: u64,
+    checksum_algorithm: ChecksumAlgorithm,
+    ec_metrics: ErrorCorrectionMetrics,
 }
 
 impl CoreMemoryBlock {
-    fn new(entry: MemoryEntry, metrics: CompressionMetrics) -> Self {
-        let checksum = Self::calculate_checksum(&entry);
-        let parity = Self::generate_parity(&entry);
+    /// Wraps an entry whose serialized form, `serialized`, the caller has
+    /// already produced; the checksum is taken over those bytes
+    fn new(
+        entry: MemoryEntry,
+        serialized: &[u8],
+        checksum_algorithm: ChecksumAlgorithm,
+        metrics: CompressionMetrics,
+        ec_metrics: ErrorCorrectionMetrics,
+    ) -> Self {
+        let checksum = checksum_algorithm.compute(serialized);
         Self {
             entry,
             metrics,
             checksum,
-            parity,
+            checksum_algorithm,
+            ec_metrics,
         }
     }
 
-    fn calculate_checksum(entry: &MemoryEntry) -> u32 {
-        let data = serialize(entry).unwrap();
-        crc32fast::hash(&data)
+    fn calculate_checksum(entry: &MemoryEntry, algorithm: ChecksumAlgorithm) -> Result<u64, Stage3Error> {
+        Ok(algorithm.compute(&serialize(entry)?))
+    }
+
+    /// Whether the checksum matches; an entry that no longer serializes
+    /// does not verify
+    fn verify(&self) -> bool {
+        Self::calculate_checksum(&self.entry, self.checksum_algorithm)
+            .is_ok_and(|checksum| checksum == self.checksum)
     }
+}
+
+/// One Reed-Solomon shard of a serialized core memory entry
+#[derive(Serialize, Deserialize)]
+struct CoreShard {
+    /// Length of the serialized entry before padding into shards
+    original_len: u64,
+    /// CRC32 of the serialized entry, used to check a reconstruction
+    entry_checksum: u32,
+    /// CRC32 over the header fields and `data`, so damaged shards can be
+    /// treated as missing
+    shard_checksum: u32,
+    data: Vec<u8>,
+}
 
-    fn generate_parity(entry: &MemoryEntry) -> Vec<u8> {
-        let data = serialize(entry).unwrap();
-        // Simple XOR-based parity for now
-        let mut parity = vec![0u8; 16];  // 128-bit parity
-        for (i, &byte) in data.iter().enumerate() {
-            parity[i % 16] ^= byte;
+impl CoreShard {
+    fn new(original_len: u64, entry_checksum: u32, data: Vec<u8>) -> Self {
+        let shard_checksum = Self::calculate_checksum(original_len, entry_checksum, &data);
+        Self {
+            original_len,
+            entry_checksum,
+            shard_checksum,
+            data,
         }
-        parity
+    }
+
+    fn calculate_checksum(original_len: u64, entry_checksum: u32, data: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&original_len.to_le_bytes());
+        hasher.update(&entry_checksum.to_le_bytes());
+        hasher.update(data);
+        hasher.finalize()
     }
 
     fn verify(&self) -> bool {
-        self.checksum == Self::calculate_checksum(&self.entry)
+        self.shard_checksum == Self::calculate_checksum(self.original_len, self.entry_checksum, &self.data)
+    }
+}
+
+/// A shard of an erasure-coded core memory, which has no full copies, along
+/// with the layout needed to rebuild it
+#[derive(Serialize, Deserialize)]
+struct ErasureShard {
+    data_shards: u32,
+    parity_shards: u32,
+    shard: CoreShard,
+}
+
+/// How many full copies of the stored core memories still verify
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicaHealth {
+    pub core_memories: usize,
+    /// Copies expected: one per replica for every core memory
+    pub replicas_total: usize,
+    pub replicas_healthy: usize,
+}
+
+/// What `Stage3::recover` found and fixed for one core memory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub replicas_checked: usize,
+    /// Replicas rewritten because they were missing or failed verification
+    pub replicas_repaired: usize,
+    /// Whether no replica verified and the entry came from the shards;
+    /// always the case for erasure-coded memories
+    pub rebuilt_from_shards: bool,
+    /// Erasure-coded shards rewritten because they were missing or damaged
+    pub shards_repaired: usize,
+}
+
+/// What `Stage3::health_check` found for one core memory
+///
+/// For an erasure-coded memory the shards stand in for replicas: it is
+/// healthy when all of them verify and otherwise always needs repair from
+/// shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EpochHealth {
+    /// Every replica verifies
+    Healthy,
+    /// Some replicas are missing or damaged but the entry can be restored,
+    /// from a good replica or, with `from_shards`, only from the shards
+    NeedsRepair {
+        damaged_replicas: usize,
+        from_shards: bool,
+    },
+    /// No replica verifies and the shards cannot rebuild the entry
+    Unrecoverable,
+}
+
+/// Per-epoch result of `Stage3::health_check`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HealthReport {
+    pub epochs: BTreeMap<u32, EpochHealth>,
+}
+
+impl HealthReport {
+    /// Whether every core memory has all its replicas intact
+    pub fn is_healthy(&self) -> bool {
+        self.epochs.values().all(|health| *health == EpochHealth::Healthy)
+    }
+
+    /// Epochs whose entry is lost
+    pub fn unrecoverable(&self) -> Vec<u32> {
+        self.epochs
+            .iter()
+            .filter(|(_, health)| **health == EpochHealth::Unrecoverable)
+            .map(|(&epoch, _)| epoch)
+            .collect()
     }
 }
 
-pub struct Stage3 {
+/// Long-term core memory storage, keeping replicas and shards in a
+/// `BlockStore`
+pub struct Stage3<S: BlockStore = FileBlockStore> {
     config: Stage3Config,
-    index: BTreeMap<u32, (PathBuf, u64)>,
+    store: S,
+    index: RwLock<BTreeMap<u32, StoredAs>>,
+    tokens: RwLock<TokenIndex>,
     compressor: Compressor,
+    ec: ReedSolomonEC,
+    /// Codec for `RedundancyMode::Erasure` writes
+    erasure: Option<ReedSolomonEC>,
+    /// Serializes writes to the same epoch's replicas and shards
+    write_locks: Vec<Mutex<()>>,
+    /// Replica repairs and shard reconstructions since startup
+    corrections: AtomicU64,
 }
 
 impl Stage3 {
     pub fn new(config: Stage3Config) -> io::Result<Self> {
         std::fs::create_dir_all(&config.storage_path)?;
         std::fs::create_dir_all(&config.redundancy_path)?;
+        for dir in &config.replica_paths {
+            std::fs::create_dir_all(dir)?;
+        }
+        Self::with_store(config, FileBlockStore)
+    }
+}
+
+impl<S: BlockStore> Stage3<S> {
+    /// Opens a store whose replicas and shards live in `store` instead of on
+    /// the local filesystem
+    pub fn with_store(config: Stage3Config, store: S) -> io::Result<Self> {
+        let ec = ReedSolomonEC::new(config.data_shards, config.parity_shards)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let erasure = match config.redundancy {
+            RedundancyMode::Mirror => None,
+            RedundancyMode::Erasure { data, parity } => Some(
+                ReedSolomonEC::new(data, parity)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            ),
+        };
         
-        Ok(Self {
+        let mut stage3 = Self {
             compressor: Compressor::new(config.compression_algorithm),
-            index: BTreeMap::new(),
+            index: RwLock::new(BTreeMap::new()),
+            tokens: RwLock::new(TokenIndex::new()),
+            ec,
+            erasure,
+            write_locks: (0..WRITE_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
             config,
-        })
+            store,
+            corrections: AtomicU64::new(0),
+        };
+
+        stage3.load_index()?;
+        Ok(stage3)
+    }
+
+    /// Youngest age, in days, at which `evaluate_promotion` accepts an entry
+    pub fn min_age_days(&self) -> u32 {
+        self.config.min_age_days
     }
 
     /// Evaluates Stage 2 entries for promotion to Stage 3
@@ -104,90 +342,565 @@ impl Stage3 {
         entry.weight() >= self.config.min_weight_threshold
     }
 
+    /// Returns the candidates that qualify for promotion, each given with
+    /// its age in days
+    pub fn evaluate_batch(&self, candidates: &[(MemoryEntry, u32)]) -> Vec<MemoryEntry> {
+        candidates
+            .iter()
+            .filter(|(entry, age_days)| self.evaluate_promotion(entry, *age_days))
+            .map(|(entry, _)| entry.clone())
+            .collect()
+    }
+
+    /// Stores every entry as a core memory, returning how many were stored
+    ///
+    /// Entries are stored in order and the first failure stops the batch;
+    /// it is reported as `PartialPromotion` with the count stored before it.
+    pub fn promote_batch(&self, entries: Vec<MemoryEntry>) -> Result<usize, Stage3Error> {
+        let mut stored = 0;
+        for entry in entries {
+            self.store_core_memory(entry).map_err(|e| Stage3Error::PartialPromotion {
+                stored,
+                source: Box::new(e),
+            })?;
+            stored += 1;
+        }
+        Ok(stored)
+    }
+
     /// Stores a core memory with redundancy
-    pub fn store_core_memory(&mut self, entry: MemoryEntry) -> Result<(), Stage3Error> {
-        let data = serialize(&entry)?;
-        let (compressed_data, metrics) = self.compressor.compress(&data);
-        
-        let block = CoreMemoryBlock::new(entry, metrics);
-        let encoded = serialize(&block)?;
+    ///
+    /// Safe to call from several threads at once; writes to the same epoch
+    /// are serialized.
+    pub fn store_core_memory(&self, entry: MemoryEntry) -> Result<(), Stage3Error> {
+        let epoch = entry.epoch();
+        let token = entry.token();
+        let _guard = self.write_lock(epoch);
+        let previous = self.index.read().get(&epoch).copied();
+        let previous_token = previous.map(|stored_as| self.stored_token(epoch, stored_as));
+        let stored_as = self.persist(entry)?;
 
-        // Store primary copy
-        let primary_path = self.get_storage_path(block.entry.epoch());
-        let mut primary_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(primary_path.clone())?;
+        // Update index
+        self.index.write().insert(epoch, stored_as);
+        let mut tokens = self.tokens.write();
+        match previous_token {
+            Some(Some(old)) => tokens.remove(old, epoch),
+            // The old copy is unreadable, so its token is unknown
+            Some(None) => tokens.remove_epoch(epoch),
+            None => {}
+        }
+        tokens.insert(token, epoch);
 
-        primary_file.write_all(&encoded)?;
+        Ok(())
+    }
 
-        // Store backup copy
-        let backup_path = self.get_backup_path(block.entry.epoch());
-        let mut backup_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(backup_path)?;
+    /// Returns every core memory that encodes `token`, in epoch order
+    pub fn find_by_token(&self, token: u16) -> Result<Vec<MemoryEntry>, Stage3Error> {
+        let epochs: Vec<u32> = self.tokens.read().epochs(token).collect();
+        epochs.into_iter()
+            .map(|epoch| self.get_core_memory(epoch))
+            .collect()
+    }
 
-        backup_file.write_all(&encoded)?;
+    /// Number of replica repairs and shard reconstructions since startup
+    pub fn corrections_performed(&self) -> u64 {
+        self.corrections.load(Ordering::Relaxed)
+    }
 
-        // Update index
-        self.index.insert(block.entry.epoch(), (primary_path, 0));
+    /// Counts how many replica copies currently verify, without repairing
+    /// anything
+    ///
+    /// Erasure-coded memories have no copies and only count towards
+    /// `core_memories`.
+    pub fn replica_health(&self) -> ReplicaHealth {
+        let index = self.index.read().clone();
+        let mut health = ReplicaHealth {
+            core_memories: index.len(),
+            ..ReplicaHealth::default()
+        };
 
-        Ok(())
+        let mirrored = index.iter().filter(|(_, stored_as)| **stored_as == StoredAs::Mirror);
+        for (&epoch, _) in mirrored {
+            for path in self.replica_paths(epoch) {
+                health.replicas_total += 1;
+                if self.read_memory_block(&path).is_ok_and(|block| block.verify()) {
+                    health.replicas_healthy += 1;
+                }
+            }
+        }
+        health
+    }
+
+    /// Classifies every stored core memory by the state of its replicas,
+    /// without repairing anything
+    ///
+    /// Shards are only read for epochs with no verifying replica, to check
+    /// that they can still rebuild the entry.
+    pub fn health_check(&self) -> HealthReport {
+        let index = self.index.read().clone();
+        let mut report = HealthReport::default();
+
+        for (epoch, stored_as) in index {
+            if stored_as == StoredAs::Erasure {
+                let health = match self.reconstruct_erasure(epoch) {
+                    Ok((_, _, 0)) => EpochHealth::Healthy,
+                    Ok((_, _, damaged_replicas)) => EpochHealth::NeedsRepair { damaged_replicas, from_shards: true },
+                    Err(_) => EpochHealth::Unrecoverable,
+                };
+                report.epochs.insert(epoch, health);
+                continue;
+            }
+
+            let paths = self.replica_paths(epoch);
+            let damaged_replicas = paths
+                .iter()
+                .filter(|path| !self.read_memory_block(path).is_ok_and(|block| block.verify()))
+                .count();
+
+            let health = if damaged_replicas == 0 {
+                EpochHealth::Healthy
+            } else if damaged_replicas < paths.len() {
+                EpochHealth::NeedsRepair { damaged_replicas, from_shards: false }
+            } else if self.reconstruct_from_shards(epoch).is_ok() {
+                EpochHealth::NeedsRepair { damaged_replicas, from_shards: true }
+            } else {
+                EpochHealth::Unrecoverable
+            };
+            report.epochs.insert(epoch, health);
+        }
+        report
+    }
+
+    /// Returns the core memories with epochs in `start_epoch..=end_epoch`,
+    /// in epoch order
+    ///
+    /// Entries that cannot be recovered from any replica or the shards are
+    /// skipped; use `range_with_errors` to see which ones failed.
+    pub fn range(&self, start_epoch: u32, end_epoch: u32) -> Result<Vec<MemoryEntry>, Stage3Error> {
+        let (entries, _) = self.range_with_errors(start_epoch, end_epoch);
+        Ok(entries)
+    }
+
+    /// Like `range`, but also returns the error for each epoch that could
+    /// not be read
+    pub fn range_with_errors(
+        &self,
+        start_epoch: u32,
+        end_epoch: u32,
+    ) -> (Vec<MemoryEntry>, Vec<(u32, Stage3Error)>) {
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        if start_epoch > end_epoch {
+            return (entries, errors);
+        }
+
+        let epochs: Vec<u32> = self.index.read()
+            .range(start_epoch..=end_epoch)
+            .map(|(&epoch, _)| epoch)
+            .collect();
+        for epoch in epochs {
+            match self.get_core_memory(epoch) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => errors.push((epoch, e)),
+            }
+        }
+        (entries, errors)
+    }
+
+    /// Returns whether a core memory is stored for this epoch
+    pub fn contains(&self, epoch: u32) -> bool {
+        self.index.read().contains_key(&epoch)
     }
 
     /// Retrieves a core memory with redundancy check
+    ///
+    /// Replicas are tried in order; any that fail verification are rewritten
+    /// from the first good copy. Whether the shards may stand in when none
+    /// verifies is up to the configured `RecoveryPolicy`.
     pub fn get_core_memory(&self, epoch: u32) -> Result<MemoryEntry, Stage3Error> {
-        let (primary_path, _) = self.index.get(&epoch)
-            .ok_or(Stage3Error::NotFound(epoch))?;
-
-        let backup_path = self.get_backup_path(epoch);
-
-        // Try primary first
-        match self.read_memory_block(primary_path) {
-            Ok(block) if block.verify() => Ok(block.entry),
-            _ => {
-                // Try backup if primary fails
-                match self.read_memory_block(&backup_path) {
-                    Ok(block) if block.verify() => {
-                        // Repair primary from backup
-                        self.repair_primary(epoch, &block)?;
-                        Ok(block.entry)
+        self.get_core_memory_with_report(epoch).map(|(entry, _)| entry)
+    }
+
+    /// Like `get_core_memory`, also reporting whether the entry had to be
+    /// rebuilt from shards and what was repaired along the way
+    pub fn get_core_memory_with_report(&self, epoch: u32) -> Result<(MemoryEntry, RecoveryReport), Stage3Error> {
+        self.check_and_repair(epoch, self.config.recovery_policy)
+    }
+
+    /// Checks every replica of a core memory and rewrites the bad ones from
+    /// a good copy, or from the Reed-Solomon shards if none verify
+    ///
+    /// An erasure-coded memory is rebuilt from its shards, and every shard
+    /// is rewritten if any was missing or damaged. Recovery always uses the
+    /// shards, whatever the configured `RecoveryPolicy`.
+    pub fn recover(&self, epoch: u32) -> Result<RecoveryReport, Stage3Error> {
+        self.check_and_repair(epoch, RecoveryPolicy::BestEffort).map(|(_, report)| report)
+    }
+
+    /// Damages the primary copy of a replica so recovery paths can be
+    /// exercised
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn corrupt_replica(&self, epoch: u32, replica: usize) -> Result<(), Stage3Error> {
+        let path = self.get_replica_path(epoch, replica);
+        let mut bytes = self.store.get(&path)?;
+        // The token follows the 4-byte epoch, so flipping it always breaks
+        // the checksum without making the block unreadable
+        if let Some(byte) = bytes.get_mut(4) {
+            *byte ^= 0xFF;
+        }
+        self.store.put(&path, &bytes)?;
+        Ok(())
+    }
+
+    fn check_and_repair(&self, epoch: u32, policy: RecoveryPolicy) -> Result<(MemoryEntry, RecoveryReport), Stage3Error> {
+        let stored_as = *self.index.read().get(&epoch).ok_or(Stage3Error::NotFound(epoch))?;
+
+        // Repairs rewrite replicas, so they must not race a store
+        let _guard = self.write_lock(epoch);
+
+        if stored_as == StoredAs::Erasure {
+            let (entry, ec, damaged) = self.reconstruct_erasure(epoch)?;
+            if damaged > 0 && policy == RecoveryPolicy::Strict {
+                return Err(Stage3Error::RedundancyError(
+                    format!("{} shards of epoch {} are damaged", damaged, epoch)
+                ));
+            }
+            if damaged > 0 {
+                // Keep the layout the memory was written with
+                self.persist_erasure(entry.clone(), &ec)?;
+                self.corrections.fetch_add(1, Ordering::Relaxed);
+            }
+            return Ok((entry, RecoveryReport {
+                rebuilt_from_shards: true,
+                shards_repaired: damaged,
+                ..RecoveryReport::default()
+            }));
+        }
+
+        let paths = self.replica_paths(epoch);
+        let mut report = RecoveryReport {
+            replicas_checked: paths.len(),
+            ..RecoveryReport::default()
+        };
+        let mut good = None;
+        let mut damaged = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            match self.read_memory_block(path) {
+                Ok(block) if block.verify() => {
+                    if good.is_none() {
+                        good = Some(block);
+                    }
+                }
+                _ => damaged.push(i),
+            }
+        }
+
+        match good {
+            Some(block) => {
+                for i in damaged {
+                    self.repair_replica(epoch, i, &block)?;
+                    self.corrections.fetch_add(1, Ordering::Relaxed);
+                    report.replicas_repaired += 1;
+                }
+                Ok((block.entry, report))
+            }
+            None if policy == RecoveryPolicy::Strict => Err(Stage3Error::RedundancyError(
+                format!("No replica of epoch {} verifies", epoch)
+            )),
+            None => {
+                // Every full copy is gone; rebuild from the shards and
+                // rewrite every copy from the recovered entry
+                let entry = self.reconstruct_from_shards(epoch)?;
+                self.persist_mirrored(entry.clone())?;
+                self.corrections.fetch_add(1, Ordering::Relaxed);
+                report.replicas_repaired = paths.len();
+                report.rebuilt_from_shards = true;
+                Ok((entry, report))
+            }
+        }
+    }
+
+    /// Writes an entry in the configured redundancy mode, clearing out any
+    /// copy of it stored in the other one
+    fn persist(&self, entry: MemoryEntry) -> Result<StoredAs, Stage3Error> {
+        let epoch = entry.epoch();
+        let previous = self.index.read().get(&epoch).copied();
+        match &self.erasure {
+            None => {
+                self.persist_mirrored(entry)?;
+                if previous == Some(StoredAs::Erasure) {
+                    for path in self.erasure_shard_files(epoch)? {
+                        self.store.delete(&path)?;
                     }
-                    _ => Err(Stage3Error::RedundancyError(
-                        format!("Both primary and backup copies corrupted for epoch {}", epoch)
-                    )),
                 }
+                Ok(StoredAs::Mirror)
+            }
+            Some(ec) => {
+                self.persist_erasure(entry, ec)?;
+                if previous == Some(StoredAs::Mirror) {
+                    let shards = self.config.data_shards + self.config.parity_shards;
+                    let mirror_files = self.replica_paths(epoch)
+                        .into_iter()
+                        .chain((0..shards).map(|i| self.get_shard_path(epoch, i)));
+                    for path in mirror_files {
+                        self.store.delete(&path)?;
+                    }
+                }
+                Ok(StoredAs::Erasure)
+            }
+        }
+    }
+
+    /// Writes every replica plus Reed-Solomon shards
+    fn persist_mirrored(&self, entry: MemoryEntry) -> Result<(), Stage3Error> {
+        let data = serialize(&entry)?;
+        let (_compressed_data, metrics) = self.compressor.compress(&data);
+        let (shards, ec_metrics) = self.ec.encode(&data)?;
+
+        let epoch = entry.epoch();
+        let block = CoreMemoryBlock::new(entry, &data, self.config.checksum_algorithm, metrics, ec_metrics);
+        let encoded = serialize(&block)?;
+
+        for path in self.replica_paths(epoch) {
+            self.write_file(&path, &encoded)?;
+        }
+
+        // Spread shards across both locations
+        let entry_checksum = crc32fast::hash(&data);
+        for (i, data_shard) in shards.into_iter().enumerate() {
+            let shard = CoreShard::new(data.len() as u64, entry_checksum, data_shard);
+            self.write_file(&self.get_shard_path(epoch, i), &serialize(&shard)?)?;
+        }
+
+        let mut dirs = self.replica_dirs();
+        dirs.extend([&self.config.storage_path, &self.config.redundancy_path]);
+        dirs.sort();
+        dirs.dedup();
+        self.sync_dirs(dirs)?;
+        Ok(())
+    }
+
+    /// Writes only the Reed-Solomon shards of an entry, dealt over the
+    /// replica directories
+    fn persist_erasure(&self, entry: MemoryEntry, ec: &ReedSolomonEC) -> Result<(), Stage3Error> {
+        let data = serialize(&entry)?;
+        let (shards, _) = ec.encode(&data)?;
+
+        let entry_checksum = crc32fast::hash(&data);
+        for (i, data_shard) in shards.into_iter().enumerate() {
+            let shard = ErasureShard {
+                data_shards: ec.data_shards() as u32,
+                parity_shards: ec.parity_shards() as u32,
+                shard: CoreShard::new(data.len() as u64, entry_checksum, data_shard),
+            };
+            self.write_file(&self.get_erasure_shard_path(entry.epoch(), i), &serialize(&shard)?)?;
+        }
+        self.sync_dirs(self.replica_dirs())?;
+        Ok(())
+    }
+
+    /// Writes a replica or shard, synced unless the store is not durable
+    fn write_file(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        if self.config.durable {
+            self.store.put(path, bytes)
+        } else {
+            self.store.put_unsynced(path, bytes)
+        }
+    }
+
+    /// Makes files just written under `dirs` survive a crash
+    fn sync_dirs<'a>(&self, dirs: impl IntoIterator<Item = &'a PathBuf>) -> io::Result<()> {
+        if self.config.durable {
+            for dir in dirs {
+                self.store.sync_dir(dir)?;
             }
         }
+        Ok(())
+    }
+
+    /// Reads an erasure-coded memory's shards, leaving `None` for any that
+    /// are missing or fail verification
+    ///
+    /// The layout comes from the first shard that verifies, so memories
+    /// written under other erasure settings stay readable.
+    fn read_erasure_shards(&self, epoch: u32) -> Vec<Option<ErasureShard>> {
+        let mut shards = Vec::new();
+        let mut total = MAX_ERASURE_SHARDS;
+        while shards.len() < total {
+            let shard = self.store.get(&self.get_erasure_shard_path(epoch, shards.len()))
+                .ok()
+                .and_then(|bytes| deserialize::<ErasureShard>(&bytes).ok())
+                .filter(|shard| shard.shard.verify());
+            if let (Some(shard), MAX_ERASURE_SHARDS) = (&shard, total) {
+                total = (shard.data_shards + shard.parity_shards) as usize;
+            }
+            shards.push(shard);
+        }
+        shards.truncate(total);
+        shards
+    }
+
+    /// Rebuilds an erasure-coded memory, also returning the codec it was
+    /// written with and how many of its shards are missing or damaged
+    fn reconstruct_erasure(&self, epoch: u32) -> Result<(MemoryEntry, ReedSolomonEC, usize), Stage3Error> {
+        let shards = self.read_erasure_shards(epoch);
+        let layout = shards.iter().flatten().next().ok_or_else(|| Stage3Error::RedundancyError(
+            format!("No usable shards remain for epoch {}", epoch)
+        ))?;
+        let ec = ReedSolomonEC::new(layout.data_shards as usize, layout.parity_shards as usize)?;
+
+        let damaged = shards.iter().filter(|shard| shard.is_none()).count();
+        let entry = self.rebuild(epoch, &ec, shards.into_iter().map(|s| s.map(|s| s.shard)).collect())?;
+        Ok((entry, ec, damaged))
+    }
+
+    /// Rebuilds an entry from whichever shards still verify
+    fn reconstruct_from_shards(&self, epoch: u32) -> Result<MemoryEntry, Stage3Error> {
+        let total = self.config.data_shards + self.config.parity_shards;
+        let shards: Vec<Option<CoreShard>> = (0..total)
+            .map(|i| {
+                self.store.get(&self.get_shard_path(epoch, i))
+                    .ok()
+                    .and_then(|bytes| deserialize::<CoreShard>(&bytes).ok())
+                    .filter(CoreShard::verify)
+            })
+            .collect();
+        self.rebuild(epoch, &self.ec, shards)
+    }
+
+    /// Decodes an entry from the shards that survive, checking the result
+    /// against the checksum they carry
+    fn rebuild(&self, epoch: u32, ec: &ReedSolomonEC, shards: Vec<Option<CoreShard>>) -> Result<MemoryEntry, Stage3Error> {
+        let (original_len, entry_checksum) = shards.iter()
+            .flatten()
+            .map(|shard| (shard.original_len as usize, shard.entry_checksum))
+            .next()
+            .ok_or_else(|| Stage3Error::RedundancyError(
+                format!("No usable shards remain for epoch {}", epoch)
+            ))?;
+
+        let (data, _) = ec
+            .reconstruct(shards.into_iter().map(|s| s.map(|s| s.data)).collect(), original_len)
+            .map_err(|e| Stage3Error::RedundancyError(
+                format!("Copies and shards unrecoverable for epoch {}: {}", epoch, e)
+            ))?;
+
+        if crc32fast::hash(&data) != entry_checksum {
+            return Err(Stage3Error::RedundancyError(
+                format!("Reconstructed data failed verification for epoch {}", epoch)
+            ));
+        }
+
+        Ok(MemoryEntry::from_bytes(&data)?)
     }
 
     // Helper methods
-    fn get_storage_path(&self, epoch: u32) -> PathBuf {
-        self.config.storage_path.join(format!("core_{}.bin", epoch))
+    fn replica_dirs(&self) -> Vec<&PathBuf> {
+        if self.config.replica_paths.is_empty() {
+            vec![&self.config.storage_path, &self.config.redundancy_path]
+        } else {
+            self.config.replica_paths.iter().collect()
+        }
     }
 
-    fn get_backup_path(&self, epoch: u32) -> PathBuf {
-        self.config.redundancy_path.join(format!("core_{}.bin", epoch))
+    fn replica_paths(&self, epoch: u32) -> Vec<PathBuf> {
+        self.replica_dirs()
+            .into_iter()
+            .map(|dir| dir.join(format!("core_{}.bin", epoch)))
+            .collect()
     }
 
-    fn read_memory_block(&self, path: &PathBuf) -> Result<CoreMemoryBlock, Stage3Error> {
-        let mut file = File::open(path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        Ok(deserialize(&buffer)?)
+    fn get_replica_path(&self, epoch: u32, replica: usize) -> PathBuf {
+        self.replica_dirs()[replica].join(format!("core_{}.bin", epoch))
     }
 
-    fn repair_primary(&self, epoch: u32, block: &CoreMemoryBlock) -> Result<(), Stage3Error> {
-        let primary_path = self.get_storage_path(epoch);
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(primary_path)?;
-        
+    fn get_erasure_shard_path(&self, epoch: u32, shard: usize) -> PathBuf {
+        let dirs = self.replica_dirs();
+        dirs[shard % dirs.len()].join(format!("core_{}.ec{}", epoch, shard))
+    }
+
+    /// Every erasure-coded shard file stored for `epoch`
+    fn erasure_shard_files(&self, epoch: u32) -> io::Result<Vec<PathBuf>> {
+        let prefix = format!("core_{}.ec", epoch);
+        let mut files = Vec::new();
+        for dir in self.replica_dirs() {
+            files.extend(self.store.list(dir)?.into_iter().filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.strip_prefix(&prefix).is_some_and(|i| i.parse::<usize>().is_ok()))
+            }));
+        }
+        Ok(files)
+    }
+
+    fn get_shard_path(&self, epoch: u32, shard: usize) -> PathBuf {
+        // Alternate shards between locations so losing one keeps half
+        let dir = if shard.is_multiple_of(2) {
+            &self.config.storage_path
+        } else {
+            &self.config.redundancy_path
+        };
+        dir.join(format!("core_{}.shard{}", epoch, shard))
+    }
+
+    /// Rebuilds the epoch and token indexes from the replica directories
+    ///
+    /// An epoch is indexed if any replica holds a copy or, failing that, any
+    /// erasure-coded shard is present; its token is taken from the first
+    /// copy that verifies, or from the rebuilt entry.
+    fn load_index(&mut self) -> io::Result<()> {
+        let mut epochs = BTreeMap::new();
+        for dir in self.replica_dirs() {
+            for path in self.store.list(dir)? {
+                let Some(name) = path.file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_prefix("core_")) else {
+                    continue;
+                };
+                if let Some(epoch) = name.strip_suffix(".bin").and_then(|epoch| epoch.parse::<u32>().ok()) {
+                    epochs.insert(epoch, StoredAs::Mirror);
+                } else if let Some((epoch, shard)) = name.split_once(".ec") {
+                    if let (Ok(epoch), Ok(_)) = (epoch.parse::<u32>(), shard.parse::<usize>()) {
+                        epochs.entry(epoch).or_insert(StoredAs::Erasure);
+                    }
+                }
+            }
+        }
+
+        for (epoch, stored_as) in epochs {
+            if let Some(token) = self.stored_token(epoch, stored_as) {
+                self.tokens.get_mut().insert(token, epoch);
+            }
+            self.index.get_mut().insert(epoch, stored_as);
+        }
+        Ok(())
+    }
+
+    /// Token of the copy stored for `epoch`, read without repairing
+    /// anything, or `None` if no copy verifies
+    fn stored_token(&self, epoch: u32, stored_as: StoredAs) -> Option<u16> {
+        match stored_as {
+            StoredAs::Mirror => self.replica_paths(epoch)
+                .iter()
+                .filter_map(|path| self.read_memory_block(path).ok())
+                .find(CoreMemoryBlock::verify)
+                .map(|block| block.entry.token()),
+            StoredAs::Erasure => self.reconstruct_erasure(epoch).ok().map(|(entry, _, _)| entry.token()),
+        }
+    }
+
+    fn write_lock(&self, epoch: u32) -> MutexGuard<'_, ()> {
+        self.write_locks[epoch as usize % self.write_locks.len()].lock()
+    }
+
+    fn read_memory_block(&self, path: &Path) -> Result<CoreMemoryBlock, Stage3Error> {
+        Ok(deserialize(&self.store.get(path)?)?)
+    }
+
+    fn repair_replica(&self, epoch: u32, replica: usize, block: &CoreMemoryBlock) -> Result<(), Stage3Error> {
         let encoded = serialize(block)?;
-        file.write_all(&encoded)?;
+        self.write_file(&self.get_replica_path(epoch, replica), &encoded)?;
+        self.sync_dirs([self.replica_dirs()[replica]])?;
         Ok(())
     }
 }
@@ -195,6 +908,9 @@ impl Stage3 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::InMemoryBlockStore;
+    use std::fs::OpenOptions;
+    use std::io::Write;
     use tempfile::tempdir;
 
     #[test]
@@ -208,7 +924,7 @@ mod tests {
             ..Stage3Config::default()
         };
 
-        let mut stage3 = Stage3::new(config)?;
+        let stage3 = Stage3::new(config)?;
         
         // Create a high-weight memory
         let entry = MemoryEntry::new(100, 900);
@@ -235,14 +951,14 @@ mod tests {
             ..Stage3Config::default()
         };
 
-        let mut stage3 = Stage3::new(config)?;
+        let stage3 = Stage3::new(config)?;
         
         // Store a memory
         let entry = MemoryEntry::new(100, 900);
         stage3.store_core_memory(entry.clone())?;
         
         // Corrupt primary file
-        let primary_path = stage3.get_storage_path(entry.epoch());
+        let primary_path = stage3.get_replica_path(entry.epoch(), 0);
         let mut file = OpenOptions::new()
             .write(true)
             .open(primary_path)?;
@@ -254,4 +970,561 @@ mod tests {
 
         Ok(())
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_reed_solomon_reconstruction() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let config = Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        };
+
+        let stage3 = Stage3::new(config)?;
+        let entry = MemoryEntry::with_links(1234, 100, 900, 7, 8);
+        stage3.store_core_memory(entry.clone())?;
+
+        // Lose both full copies and zero out one shard
+        std::fs::remove_file(stage3.get_replica_path(entry.epoch(), 0))?;
+        std::fs::remove_file(stage3.get_replica_path(entry.epoch(), 1))?;
+        let shard_path = stage3.get_shard_path(entry.epoch(), 0);
+        let shard_len = std::fs::metadata(&shard_path)?.len() as usize;
+        std::fs::write(&shard_path, vec![0u8; shard_len])?;
+
+        let retrieved = stage3.get_core_memory(entry.epoch())?;
+        assert_eq!(retrieved.token(), entry.token());
+        assert_eq!(retrieved.weight(), entry.weight());
+        assert_eq!(retrieved.links(), entry.links());
+
+        // Recovery rewrote the primary copy
+        assert!(stage3.read_memory_block(&stage3.get_replica_path(entry.epoch(), 0))?.verify());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_replica_recovery() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let replica_dirs: Vec<_> = (0..3).map(|_| tempdir().unwrap()).collect();
+
+        let config = Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            replica_paths: replica_dirs.iter().map(|d| d.path().to_path_buf()).collect(),
+            ..Stage3Config::default()
+        };
+
+        let stage3 = Stage3::new(config)?;
+        let entry = MemoryEntry::with_links(42, 100, 900, 0, 0);
+        stage3.store_core_memory(entry.clone())?;
+
+        for replica in 0..3 {
+            assert!(stage3.get_replica_path(42, replica).exists());
+        }
+
+        // Damage the first and last replicas, leaving only the middle one
+        std::fs::write(stage3.get_replica_path(42, 0), [0u8; 16])?;
+        std::fs::remove_file(stage3.get_replica_path(42, 2))?;
+
+        let retrieved = stage3.get_core_memory(42)?;
+        assert_eq!(retrieved.token(), entry.token());
+
+        for replica in 0..3 {
+            let block = stage3.read_memory_block(&stage3.get_replica_path(42, replica))?;
+            assert!(block.verify(), "replica {} was not repaired", replica);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_rebuilt_on_reopen() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let config = Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        };
+
+        let stage3 = Stage3::new(config.clone())?;
+        stage3.store_core_memory(MemoryEntry::with_links(1, 100, 900, 0, 0))?;
+        stage3.store_core_memory(MemoryEntry::with_links(2, 100, 950, 0, 0))?;
+        stage3.store_core_memory(MemoryEntry::with_links(3, 200, 990, 0, 0))?;
+        drop(stage3);
+
+        let reopened = Stage3::new(config)?;
+        assert!(reopened.contains(1) && reopened.contains(2) && reopened.contains(3));
+        let epochs: Vec<u32> = reopened.find_by_token(100)?
+            .iter()
+            .map(MemoryEntry::epoch)
+            .collect();
+        assert_eq!(epochs, vec![1, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_replaces_token() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+
+        let config = Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        };
+
+        let stage3 = Stage3::new(config.clone())?;
+        stage3.store_core_memory(MemoryEntry::with_links(10, 100, 900, 0, 0))?;
+        stage3.store_core_memory(MemoryEntry::with_links(10, 200, 950, 0, 0))?;
+        assert!(stage3.find_by_token(100)?.is_empty());
+        assert_eq!(stage3.find_by_token(200)?.len(), 1);
+        drop(stage3);
+
+        let reopened = Stage3::new(config)?;
+        assert!(reopened.find_by_token(100)?.is_empty());
+        assert_eq!(reopened.find_by_token(200)?[0].weight(), 950);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_algorithms_detect_flip() -> Result<(), Stage3Error> {
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::XxHash64,
+            ChecksumAlgorithm::Blake3,
+        ] {
+            let temp_dir = tempdir().unwrap();
+            let backup_dir = tempdir().unwrap();
+            let stage3 = Stage3::new(Stage3Config {
+                storage_path: temp_dir.path().to_path_buf(),
+                redundancy_path: backup_dir.path().to_path_buf(),
+                checksum_algorithm: algorithm,
+                ..Stage3Config::default()
+            })?;
+            stage3.store_core_memory(MemoryEntry::with_links(7, 100, 900, 0, 0))?;
+
+            let path = stage3.get_replica_path(7, 0);
+            let block = stage3.read_memory_block(&path)?;
+            assert_eq!(block.checksum_algorithm, algorithm);
+            assert!(block.verify());
+
+            // The token sits just after the 4-byte epoch at the start
+            let mut bytes = std::fs::read(&path)?;
+            bytes[4] ^= 0x01;
+            std::fs::write(&path, bytes)?;
+            assert!(!stage3.read_memory_block(&path)?.verify());
+
+            assert_eq!(stage3.get_core_memory(7)?.token(), 100);
+            assert_eq!(stage3.corrections_performed(), 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_scan() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        })?;
+        for epoch in [10, 20, 30, 40, 50] {
+            stage3.store_core_memory(MemoryEntry::with_links(epoch, epoch as u16, 900, 0, 0))?;
+        }
+
+        let epochs: Vec<u32> = stage3.range(15, 40)?.iter().map(|e| e.epoch()).collect();
+        assert_eq!(epochs, vec![20, 30, 40]);
+        assert!(stage3.range(40, 15)?.is_empty());
+
+        // A primary-only failure is repaired transparently
+        std::fs::write(stage3.get_replica_path(20, 0), b"garbage")?;
+        // Losing every copy of 30 fails that entry alone
+        for path in stage3.replica_paths(30) {
+            std::fs::remove_file(path)?;
+        }
+        for shard in 0..stage3.config.data_shards + stage3.config.parity_shards {
+            std::fs::remove_file(stage3.get_shard_path(30, shard))?;
+        }
+
+        let (entries, errors) = stage3.range_with_errors(15, 40);
+        let epochs: Vec<u32> = entries.iter().map(|e| e.epoch()).collect();
+        assert_eq!(epochs, vec![20, 40]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 30);
+        assert_eq!(stage3.range(15, 40)?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_reports_repairs() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        })?;
+        stage3.store_core_memory(MemoryEntry::with_links(5, 100, 900, 0, 0))?;
+
+        assert_eq!(stage3.recover(5)?.replicas_repaired, 0);
+
+        stage3.corrupt_replica(5, 1)?;
+        let report = stage3.recover(5)?;
+        assert_eq!(report, RecoveryReport {
+            replicas_checked: 2,
+            replicas_repaired: 1,
+            rebuilt_from_shards: false,
+            shards_repaired: 0,
+        });
+        assert!(stage3.read_memory_block(&stage3.get_replica_path(5, 1))?.verify());
+
+        stage3.corrupt_replica(5, 0)?;
+        stage3.corrupt_replica(5, 1)?;
+        let report = stage3.recover(5)?;
+        assert!(report.rebuilt_from_shards);
+        assert_eq!(report.replicas_repaired, 2);
+        assert_eq!(stage3.replica_health().replicas_healthy, 2);
+        assert!(matches!(stage3.recover(6), Err(Stage3Error::NotFound(6))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_policy_gates_shard_rebuild() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let mut stage3 = Stage3::new(Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            recovery_policy: RecoveryPolicy::Strict,
+            ..Stage3Config::default()
+        })?;
+        stage3.store_core_memory(MemoryEntry::with_links(5, 100, 900, 0, 0))?;
+        let (_, report) = stage3.get_core_memory_with_report(5)?;
+        assert!(!report.rebuilt_from_shards);
+
+        // Both copies are gone, but the shards still hold the memory
+        stage3.corrupt_replica(5, 0)?;
+        stage3.corrupt_replica(5, 1)?;
+        let err = stage3.get_core_memory(5).unwrap_err();
+        assert!(matches!(err, Stage3Error::RedundancyError(_)), "{}", err);
+        assert_eq!(stage3.replica_health().replicas_healthy, 0);
+
+        stage3.config.recovery_policy = RecoveryPolicy::BestEffort;
+        let (entry, report) = stage3.get_core_memory_with_report(5)?;
+        assert_eq!((entry.token(), entry.weight()), (100, 900));
+        assert!(report.rebuilt_from_shards);
+        assert_eq!(stage3.replica_health().replicas_healthy, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_health_check_classifies_epochs() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        })?;
+        for epoch in 1..=4 {
+            stage3.store_core_memory(MemoryEntry::with_links(epoch, 100, 900, 0, 0))?;
+        }
+        assert!(stage3.health_check().is_healthy());
+
+        stage3.corrupt_replica(2, 1)?;
+        stage3.corrupt_replica(3, 0)?;
+        std::fs::remove_file(stage3.get_replica_path(3, 1))?;
+        stage3.corrupt_replica(4, 0)?;
+        stage3.corrupt_replica(4, 1)?;
+        // More shards lost than there is parity for
+        for shard in 0..=stage3.config.parity_shards {
+            std::fs::remove_file(stage3.get_shard_path(4, shard))?;
+        }
+
+        let report = stage3.health_check();
+        let expected = BTreeMap::from([
+            (1, EpochHealth::Healthy),
+            (2, EpochHealth::NeedsRepair { damaged_replicas: 1, from_shards: false }),
+            (3, EpochHealth::NeedsRepair { damaged_replicas: 2, from_shards: true }),
+            (4, EpochHealth::Unrecoverable),
+        ]);
+        assert_eq!(report.epochs, expected);
+        assert!(!report.is_healthy());
+        assert_eq!(report.unrecoverable(), vec![4]);
+
+        // Nothing was repaired
+        assert_eq!(stage3.health_check(), report);
+        assert_eq!(stage3.corrections_performed(), 0);
+        assert!(!stage3.get_replica_path(3, 1).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_checksum_covers_serialized_entry() -> Result<(), Stage3Error> {
+        let entry = MemoryEntry::with_links(5, 100, 900, 1, 2).with_expiry(50);
+        let data = serialize(&entry)?;
+        let ec = ReedSolomonEC::new(4, 2)?;
+        let (_, metrics) = Compressor::new(CompressionAlgorithm::LZ4).compress(&data);
+        let (_, ec_metrics) = ec.encode(&data)?;
+
+        let block = CoreMemoryBlock::new(entry.clone(), &data, ChecksumAlgorithm::XxHash64, metrics.clone(), ec_metrics.clone());
+        assert!(block.verify());
+        assert_eq!(CoreMemoryBlock::calculate_checksum(&entry, ChecksumAlgorithm::XxHash64)?, block.checksum);
+
+        // The checksum is over the bytes given, not a fresh serialization
+        let mismatched = CoreMemoryBlock::new(entry, b"other bytes", ChecksumAlgorithm::XxHash64, metrics, ec_metrics);
+        assert!(!mismatched.verify());
+        Ok(())
+    }
+
+    fn clear_dir(dir: &Path) -> io::Result<()> {
+        for file in std::fs::read_dir(dir)? {
+            std::fs::remove_file(file?.path())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_mirror_survives_lost_location() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir().unwrap();
+        let backup_dir = tempdir().unwrap();
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            redundancy: RedundancyMode::Mirror,
+            ..Stage3Config::default()
+        })?;
+        stage3.store_core_memory(MemoryEntry::with_links(5, 100, 900, 0, 0))?;
+
+        clear_dir(temp_dir.path())?;
+        let report = stage3.recover(5)?;
+        assert_eq!((report.replicas_repaired, report.rebuilt_from_shards), (1, false));
+        assert_eq!(stage3.get_core_memory(5)?.token(), 100);
+        assert!(stage3.health_check().is_healthy());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_erasure_survives_lost_location() -> Result<(), Stage3Error> {
+        let dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let config = Stage3Config {
+            storage_path: dirs[0].path().to_path_buf(),
+            redundancy_path: dirs[1].path().to_path_buf(),
+            replica_paths: dirs.iter().map(|dir| dir.path().to_path_buf()).collect(),
+            // Two shards per directory, covered by two parity shards
+            redundancy: RedundancyMode::Erasure { data: 4, parity: 2 },
+            ..Stage3Config::default()
+        };
+        let stage3 = Stage3::new(config.clone())?;
+        stage3.store_core_memory(MemoryEntry::with_links(5, 100, 900, 0, 0))?;
+
+        // No full copies anywhere, just two shards in each directory
+        for dir in &dirs {
+            let mut names: Vec<String> = std::fs::read_dir(dir.path())?
+                .map(|file| file.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            assert_eq!(names.len(), 2);
+            assert!(names.iter().all(|name| name.starts_with("core_5.ec")));
+        }
+        assert_eq!(stage3.replica_health().replicas_total, 0);
+
+        clear_dir(dirs[1].path())?;
+        assert_eq!(
+            stage3.health_check().epochs[&5],
+            EpochHealth::NeedsRepair { damaged_replicas: 2, from_shards: true }
+        );
+        let report = stage3.recover(5)?;
+        assert_eq!(report, RecoveryReport {
+            rebuilt_from_shards: true,
+            shards_repaired: 2,
+            ..RecoveryReport::default()
+        });
+        assert!(stage3.health_check().is_healthy());
+        assert_eq!(stage3.corrections_performed(), 1);
+        drop(stage3);
+
+        // Stored memories are read in the mode they were written in
+        let stage3 = Stage3::new(Stage3Config {
+            redundancy: RedundancyMode::Mirror,
+            ..config
+        })?;
+        assert_eq!(stage3.find_by_token(100)?.len(), 1);
+        stage3.store_core_memory(MemoryEntry::with_links(6, 101, 900, 0, 0))?;
+        assert!(stage3.get_replica_path(6, 2).exists());
+
+        // Storing again in the other mode replaces the shards
+        stage3.store_core_memory(MemoryEntry::with_links(5, 102, 900, 0, 0))?;
+        assert!(stage3.erasure_shard_files(5)?.is_empty());
+        assert_eq!(stage3.get_core_memory(5)?.token(), 102);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_block_store() -> Result<(), Stage3Error> {
+        let config = Stage3Config {
+            storage_path: PathBuf::from("stage3"),
+            redundancy_path: PathBuf::from("stage3_backup"),
+            ..Stage3Config::default()
+        };
+
+        let stage3 = Stage3::with_store(config.clone(), InMemoryBlockStore::new())?;
+        stage3.store_core_memory(MemoryEntry::with_links(9, 100, 900, 0, 0))?;
+        stage3.corrupt_replica(9, 0)?;
+        assert_eq!(stage3.get_core_memory(9)?.token(), 100);
+        assert_eq!(stage3.corrections_performed(), 1);
+        assert!(!config.storage_path.exists());
+
+        let reopened = Stage3::with_store(config, stage3.store)?;
+        assert_eq!(reopened.find_by_token(100)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_stores() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir()?;
+        let backup_dir = tempdir()?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        })?;
+
+        std::thread::scope(|scope| {
+            for thread in 0..4u32 {
+                let stage3 = &stage3;
+                scope.spawn(move || {
+                    for i in 0..25 {
+                        let epoch = thread * 100 + i;
+                        let entry = MemoryEntry::with_links(epoch, thread as u16, 900, 0, 0);
+                        stage3.store_core_memory(entry).unwrap();
+                    }
+                });
+            }
+        });
+
+        for thread in 0..4u32 {
+            for i in 0..25 {
+                let entry = stage3.get_core_memory(thread * 100 + i)?;
+                assert_eq!(entry.token(), thread as u16);
+            }
+            assert_eq!(stage3.find_by_token(thread as u16)?.len(), 25);
+        }
+        assert_eq!(stage3.replica_health().core_memories, 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_promotion() -> Result<(), Stage3Error> {
+        let temp_dir = tempdir()?;
+        let backup_dir = tempdir()?;
+        let stage3 = Stage3::new(Stage3Config {
+            storage_path: temp_dir.path().to_path_buf(),
+            redundancy_path: backup_dir.path().to_path_buf(),
+            ..Stage3Config::default()
+        })?;
+
+        let candidates = vec![
+            (MemoryEntry::with_links(1, 100, 900, 0, 0), 45),
+            (MemoryEntry::with_links(2, 101, 900, 0, 0), 5),
+            (MemoryEntry::with_links(3, 102, 200, 0, 0), 45),
+            (MemoryEntry::with_links(4, 103, 800, 0, 0), 30),
+        ];
+        let qualifying = stage3.evaluate_batch(&candidates);
+        assert_eq!(qualifying.iter().map(MemoryEntry::epoch).collect::<Vec<_>>(), vec![1, 4]);
+
+        assert_eq!(stage3.promote_batch(qualifying)?, 2);
+        assert_eq!(stage3.range(0, 10)?.iter().map(MemoryEntry::epoch).collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(stage3.promote_batch(Vec::new())?, 0);
+
+        Ok(())
+    }
+
+    /// Counts synced and unsynced writes and directory syncs on the way
+    /// through to the filesystem
+    #[derive(Default)]
+    struct SyncCountingStore {
+        synced: AtomicU64,
+        unsynced: AtomicU64,
+        dir_syncs: AtomicU64,
+    }
+
+    impl BlockStore for SyncCountingStore {
+        fn put(&self, key: &Path, bytes: &[u8]) -> io::Result<()> {
+            self.synced.fetch_add(1, Ordering::Relaxed);
+            FileBlockStore.put(key, bytes)
+        }
+
+        fn put_unsynced(&self, key: &Path, bytes: &[u8]) -> io::Result<()> {
+            self.unsynced.fetch_add(1, Ordering::Relaxed);
+            FileBlockStore.put_unsynced(key, bytes)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            self.dir_syncs.fetch_add(1, Ordering::Relaxed);
+            FileBlockStore.sync_dir(dir)
+        }
+
+        fn get(&self, key: &Path) -> io::Result<Vec<u8>> {
+            FileBlockStore.get(key)
+        }
+
+        fn delete(&self, key: &Path) -> io::Result<()> {
+            FileBlockStore.delete(key)
+        }
+
+        fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            FileBlockStore.list(dir)
+        }
+
+        fn modified(&self, key: &Path) -> io::Result<std::time::SystemTime> {
+            FileBlockStore.modified(key)
+        }
+    }
+
+    #[test]
+    fn test_durable_stores_sync() -> Result<(), Stage3Error> {
+        for durable in [true, false] {
+            let temp_dir = tempdir()?;
+            let backup_dir = tempdir()?;
+            let config = Stage3Config {
+                storage_path: temp_dir.path().to_path_buf(),
+                redundancy_path: backup_dir.path().to_path_buf(),
+                durable,
+                ..Stage3Config::default()
+            };
+
+            let stage3 = Stage3::with_store(config.clone(), SyncCountingStore::default())?;
+            stage3.store_core_memory(MemoryEntry::with_links(7, 100, 900, 0, 0))?;
+
+            // Two replicas plus six shards
+            let store = &stage3.store;
+            let (synced, unsynced) = if durable { (8, 0) } else { (0, 8) };
+            assert_eq!(store.synced.load(Ordering::Relaxed), synced);
+            assert_eq!(store.unsynced.load(Ordering::Relaxed), unsynced);
+            assert_eq!(store.dir_syncs.load(Ordering::Relaxed) > 0, durable);
+
+            let reopened = Stage3::new(config)?;
+            assert_eq!(reopened.get_core_memory(7)?.weight(), 900);
+            assert_eq!(reopened.replica_health().replicas_healthy, 2);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! Mapping between string concepts and 16-bit tokens.
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Turns string concepts into the `u16` tokens memories are stored under
+pub trait TokenCodec: Send + Sync + fmt::Debug {
+    fn encode(&self, s: &str) -> u16;
+    /// The concept a token stands for, if the codec knows it
+    fn decode(&self, t: u16) -> Option<String>;
+}
+
+/// Hashes concepts onto tokens with xxHash64, folded to 16 bits
+///
+/// Any string encodes without setup, but distinct concepts can share a
+/// token. The codec remembers the first concept seen for each token, which
+/// is what `decode` returns, and records every later concept that lands on
+/// a claimed token so collisions can be detected.
+#[derive(Debug, Default)]
+pub struct HashingCodec {
+    seen: RwLock<HashMap<u16, String>>,
+    collisions: RwLock<Vec<(u16, String)>>,
+}
+
+impl HashingCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The token `s` hashes to, without recording it
+    pub fn token_for(s: &str) -> u16 {
+        let hash = xxhash_rust::xxh64::xxh64(s.as_bytes(), 0);
+        (hash ^ (hash >> 16) ^ (hash >> 32) ^ (hash >> 48)) as u16
+    }
+
+    /// Concept already holding the token `s` hashes to, if it is a
+    /// different one
+    pub fn collides_with(&self, s: &str) -> Option<String> {
+        self.seen
+            .read()
+            .get(&Self::token_for(s))
+            .filter(|existing| existing.as_str() != s)
+            .cloned()
+    }
+
+    /// Every (token, concept) encoded after another concept claimed the
+    /// token, in the order they were seen
+    pub fn collisions(&self) -> Vec<(u16, String)> {
+        self.collisions.read().clone()
+    }
+}
+
+impl TokenCodec for HashingCodec {
+    fn encode(&self, s: &str) -> u16 {
+        let token = Self::token_for(s);
+        let mut seen = self.seen.write();
+        match seen.get(&token) {
+            None => {
+                seen.insert(token, s.to_owned());
+            }
+            Some(existing) if existing != s => {
+                let mut collisions = self.collisions.write();
+                if !collisions.iter().any(|(t, concept)| *t == token && concept == s) {
+                    collisions.push((token, s.to_owned()));
+                }
+            }
+            Some(_) => {}
+        }
+        token
+    }
+
+    fn decode(&self, t: u16) -> Option<String> {
+        self.seen.read().get(&t).cloned()
+    }
+}
+
+/// Assigns each new concept the next free token, so distinct concepts never
+/// share one until all 65,536 are taken
+///
+/// Once the dictionary is full, unknown concepts fall back to
+/// `HashingCodec::token_for` and are not added; check `is_full`.
+#[derive(Debug, Default)]
+pub struct DictionaryCodec {
+    inner: RwLock<Dictionary>,
+}
+
+#[derive(Debug, Default)]
+struct Dictionary {
+    tokens: HashMap<String, u16>,
+    concepts: Vec<String>,
+}
+
+impl DictionaryCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a dictionary with `concepts` assigned tokens in order
+    pub fn with_concepts<I, S>(concepts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let codec = Self::new();
+        for concept in concepts {
+            codec.encode(concept.as_ref());
+        }
+        codec
+    }
+
+    /// The token for `s`, if it has been assigned one
+    pub fn get(&self, s: &str) -> Option<u16> {
+        self.inner.read().tokens.get(s).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().concepts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether every token has been assigned
+    pub fn is_full(&self) -> bool {
+        self.len() > u16::MAX as usize
+    }
+}
+
+impl TokenCodec for DictionaryCodec {
+    fn encode(&self, s: &str) -> u16 {
+        if let Some(token) = self.get(s) {
+            return token;
+        }
+
+        let mut inner = self.inner.write();
+        // Another writer may have added it between the two locks
+        if let Some(&token) = inner.tokens.get(s) {
+            return token;
+        }
+        if inner.concepts.len() > u16::MAX as usize {
+            return HashingCodec::token_for(s);
+        }
+
+        let token = inner.concepts.len() as u16;
+        inner.tokens.insert(s.to_owned(), token);
+        inner.concepts.push(s.to_owned());
+        token
+    }
+
+    fn decode(&self, t: u16) -> Option<String> {
+        self.inner.read().concepts.get(t as usize).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dictionary_round_trip() {
+        let codec = DictionaryCodec::with_concepts(["sunrise", "coffee"]);
+        let token = codec.encode("rain");
+
+        assert_eq!(token, 2);
+        assert_eq!(codec.encode("coffee"), 1);
+        assert_eq!(codec.len(), 3);
+        for concept in ["sunrise", "coffee", "rain"] {
+            assert_eq!(codec.decode(codec.encode(concept)).as_deref(), Some(concept));
+        }
+        assert_eq!(codec.decode(3), None);
+    }
+
+    #[test]
+    fn test_hashing_collisions_are_detected() {
+        let codec = HashingCodec::new();
+        let mut by_token = HashMap::new();
+        let (first, second) = (0..)
+            .map(|i| format!("concept-{i}"))
+            .find_map(|concept| {
+                let token = HashingCodec::token_for(&concept);
+                by_token.insert(token, concept.clone()).map(|first| (first, concept))
+            })
+            .unwrap();
+
+        let token = codec.encode(&first);
+        assert_eq!(codec.collides_with(&first), None);
+        assert_eq!(codec.collides_with(&second), Some(first.clone()));
+        assert!(codec.collisions().is_empty());
+
+        assert_eq!(codec.encode(&second), token);
+        assert_eq!(codec.collisions(), vec![(token, second)]);
+        // The first concept keeps the token
+        assert_eq!(codec.decode(token), Some(first));
+    }
+}
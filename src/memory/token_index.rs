@@ -0,0 +1,80 @@
+//! Token to epoch lookup shared by every stage.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Maps each token to the epochs of the memories that encode it
+#[derive(Debug, Clone, Default)]
+pub struct TokenIndex {
+    epochs: BTreeMap<u16, BTreeSet<u32>>,
+}
+
+impl TokenIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the memory at `epoch` encodes `token`
+    pub fn insert(&mut self, token: u16, epoch: u32) {
+        self.epochs.entry(token).or_default().insert(epoch);
+    }
+
+    /// Forgets a memory, dropping the token once no memories remain for it
+    pub fn remove(&mut self, token: u16, epoch: u32) {
+        if let Some(epochs) = self.epochs.get_mut(&token) {
+            epochs.remove(&epoch);
+            if epochs.is_empty() {
+                self.epochs.remove(&token);
+            }
+        }
+    }
+
+    /// Forgets a memory whose token is unknown, checking every token
+    pub fn remove_epoch(&mut self, epoch: u32) {
+        self.epochs.retain(|_, epochs| {
+            epochs.remove(&epoch);
+            !epochs.is_empty()
+        });
+    }
+
+    /// Iterates over the epochs stored for a token in ascending order
+    pub fn epochs(&self, token: u16) -> impl Iterator<Item = u32> + '_ {
+        self.epochs.get(&token).into_iter().flatten().copied()
+    }
+
+    /// Iterates over every `(token, epoch)` pair, ordered by token then epoch
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u32)> + '_ {
+        self.epochs
+            .iter()
+            .flat_map(|(&token, epochs)| epochs.iter().map(move |&epoch| (token, epoch)))
+    }
+
+    pub fn clear(&mut self) {
+        self.epochs.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut index = TokenIndex::new();
+        index.insert(100, 3);
+        index.insert(100, 1);
+        index.insert(200, 2);
+
+        assert_eq!(index.epochs(100).collect::<Vec<_>>(), vec![1, 3]);
+
+        index.remove(100, 1);
+        index.remove(100, 3);
+        index.remove(300, 1);
+        assert_eq!(index.epochs(100).count(), 0);
+        assert!(!index.epochs.contains_key(&100));
+        assert_eq!(index.epochs(200).collect::<Vec<_>>(), vec![2]);
+
+        index.insert(300, 2);
+        index.remove_epoch(2);
+        assert_eq!(index.iter().count(), 0);
+    }
+}
@@ -0,0 +1,300 @@
+//! Keyed byte storage used by the stages for their block IO.
+
+use super::StorageManager;
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Backend holding the files Stage 2 and Stage 3 read and write
+///
+/// Keys are paths; a backend that is not a filesystem treats them as plain
+/// names. Only `put`, `get`, `delete`, `list` and `modified` are required;
+/// the rest fall back to whole-value reads and writes, which file-backed
+/// stores override.
+pub trait BlockStore: Send + Sync {
+    /// Replaces the value at `key`; a reader sees the old bytes or the new
+    /// ones, never a mix
+    fn put(&self, key: &Path, bytes: &[u8]) -> io::Result<()>;
+
+    fn get(&self, key: &Path) -> io::Result<Vec<u8>>;
+
+    /// Removes `key`; removing a missing key is not an error
+    fn delete(&self, key: &Path) -> io::Result<()>;
+
+    /// Returns the keys directly under `dir`, in no particular order
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// When `key` was last written
+    fn modified(&self, key: &Path) -> io::Result<SystemTime>;
+
+    /// Appends to `key`, creating it if needed, and returns the offset the
+    /// bytes were written at
+    fn append(&self, key: &Path, bytes: &[u8]) -> io::Result<u64> {
+        let mut value = match self.get(key) {
+            Ok(value) => value,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let offset = value.len() as u64;
+        value.extend_from_slice(bytes);
+        self.put(key, &value)?;
+        Ok(offset)
+    }
+
+    /// Reads `len` bytes of `key` starting at `offset`
+    fn get_range(&self, key: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let value = self.get(key)?;
+        let start = offset as usize;
+        value.get(start..start + len as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+
+    fn len(&self, key: &Path) -> io::Result<u64> {
+        Ok(self.get(key)?.len() as u64)
+    }
+
+    fn exists(&self, key: &Path) -> io::Result<bool> {
+        match self.len(key) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Cuts `key` down to `len` bytes
+    fn truncate(&self, key: &Path, len: u64) -> io::Result<()> {
+        let mut value = self.get(key)?;
+        value.truncate(len as usize);
+        self.put(key, &value)
+    }
+
+    /// Makes earlier appends to `key` durable
+    fn sync(&self, _key: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Like `put`, but may return before the bytes are durable
+    fn put_unsynced(&self, key: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.put(key, bytes)
+    }
+
+    /// Makes the creation and replacement of keys under `dir` durable
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stores each key as the file at that path
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileBlockStore;
+
+impl FileBlockStore {
+    fn create_parent(key: &Path) -> io::Result<()> {
+        match key.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes a `.tmp` sibling and renames it over `key`, syncing the bytes
+    /// first if asked
+    fn write_and_rename(key: &Path, bytes: &[u8], sync: bool) -> io::Result<()> {
+        Self::create_parent(key)?;
+        let tmp = StorageManager::tmp_path(key);
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        if sync {
+            file.sync_all()?;
+        }
+        drop(file);
+
+        fs::rename(&tmp, key)
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn put(&self, key: &Path, bytes: &[u8]) -> io::Result<()> {
+        Self::write_and_rename(key, bytes, true)
+    }
+
+    fn put_unsynced(&self, key: &Path, bytes: &[u8]) -> io::Result<()> {
+        Self::write_and_rename(key, bytes, false)
+    }
+
+    fn get(&self, key: &Path) -> io::Result<Vec<u8>> {
+        fs::read(key)
+    }
+
+    fn delete(&self, key: &Path) -> io::Result<()> {
+        match fs::remove_file(key) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        entries.map(|entry| entry.map(|e| e.path())).collect()
+    }
+
+    fn modified(&self, key: &Path) -> io::Result<SystemTime> {
+        fs::metadata(key)?.modified()
+    }
+
+    fn append(&self, key: &Path, bytes: &[u8]) -> io::Result<u64> {
+        Self::create_parent(key)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(key)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(bytes)?;
+        Ok(offset)
+    }
+
+    fn get_range(&self, key: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(key)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0u8; len as usize];
+        file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn len(&self, key: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(key)?.len())
+    }
+
+    fn truncate(&self, key: &Path, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(key)?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+
+    fn sync(&self, key: &Path) -> io::Result<()> {
+        OpenOptions::new().append(true).open(key)?.sync_data()
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+}
+
+/// Keeps every key in memory; nothing survives the process
+#[derive(Debug, Default)]
+pub struct InMemoryBlockStore {
+    values: RwLock<BTreeMap<PathBuf, (Vec<u8>, SystemTime)>>,
+}
+
+impl InMemoryBlockStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn not_found(key: &Path) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("{} not found", key.display()))
+    }
+}
+
+impl BlockStore for InMemoryBlockStore {
+    fn put(&self, key: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.values.write().insert(key.to_path_buf(), (bytes.to_vec(), SystemTime::now()));
+        Ok(())
+    }
+
+    fn get(&self, key: &Path) -> io::Result<Vec<u8>> {
+        self.values.read()
+            .get(key)
+            .map(|(value, _)| value.clone())
+            .ok_or_else(|| Self::not_found(key))
+    }
+
+    fn delete(&self, key: &Path) -> io::Result<()> {
+        self.values.write().remove(key);
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self.values.read()
+            .keys()
+            .filter(|key| key.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn modified(&self, key: &Path) -> io::Result<SystemTime> {
+        self.values.read()
+            .get(key)
+            .map(|(_, modified)| *modified)
+            .ok_or_else(|| Self::not_found(key))
+    }
+
+    fn append(&self, key: &Path, bytes: &[u8]) -> io::Result<u64> {
+        let mut values = self.values.write();
+        let (value, modified) = values
+            .entry(key.to_path_buf())
+            .or_insert_with(|| (Vec::new(), SystemTime::now()));
+        let offset = value.len() as u64;
+        value.extend_from_slice(bytes);
+        *modified = SystemTime::now();
+        Ok(offset)
+    }
+
+    fn len(&self, key: &Path) -> io::Result<u64> {
+        self.values.read()
+            .get(key)
+            .map(|(value, _)| value.len() as u64)
+            .ok_or_else(|| Self::not_found(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn exercise(store: &dyn BlockStore, dir: &Path) -> io::Result<()> {
+        let key = dir.join("blocks.bin");
+        assert!(!store.exists(&key)?);
+
+        assert_eq!(store.append(&key, b"abc")?, 0);
+        assert_eq!(store.append(&key, b"defg")?, 3);
+        store.sync(&key)?;
+        assert_eq!(store.get_range(&key, 3, 4)?, b"defg");
+        assert!(store.get_range(&key, 5, 4).is_err());
+        assert_eq!(store.len(&key)?, 7);
+
+        store.truncate(&key, 2)?;
+        assert_eq!(store.get(&key)?, b"ab");
+        store.put(&dir.join("other.bin"), b"x")?;
+        store.put_unsynced(&dir.join("other.bin"), b"y")?;
+        store.sync_dir(dir)?;
+        assert_eq!(store.get(&dir.join("other.bin"))?, b"y");
+
+        let mut keys = store.list(dir)?;
+        keys.sort();
+        assert_eq!(keys, vec![key.clone(), dir.join("other.bin")]);
+
+        store.delete(&key)?;
+        store.delete(&key)?;
+        assert_eq!(store.get(&key).unwrap_err().kind(), io::ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store() -> io::Result<()> {
+        let dir = tempdir()?;
+        exercise(&FileBlockStore, &dir.path().join("nested"))
+    }
+
+    #[test]
+    fn test_in_memory_store() -> io::Result<()> {
+        exercise(&InMemoryBlockStore::new(), Path::new("memory"))
+    }
+}
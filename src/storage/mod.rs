@@ -1,11 +1,94 @@
 //! Logic for file storage and RAID-like redundancy.
 
-pub struct StorageManager;
+mod block_store;
+
+pub use block_store::{BlockStore, FileBlockStore, InMemoryBlockStore};
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Reads and writes memory files under a base directory
+pub struct StorageManager {
+    base_dir: PathBuf,
+}
 
 impl StorageManager {
-    pub fn save_memory(&self, data: &str) -> bool {
-        // Placeholder logic for saving memory to disk
-        println!("Saving data: {}", data);
-        true
+    /// Creates a manager rooted at `base_dir`, creating the directory if
+    /// needed
+    pub fn new(base_dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let base_dir = base_dir.into();
+        fs::create_dir_all(&base_dir)?;
+        Ok(Self { base_dir })
+    }
+
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// Writes `data` to `path` under the base directory
+    ///
+    /// The bytes go to a `.tmp` sibling first and are renamed into place once
+    /// synced, so a crash leaves either the old file or the new one, never a
+    /// partial write. The directory is synced after the rename so the rename
+    /// itself survives a crash.
+    pub fn save_memory(&self, path: impl AsRef<Path>, data: &[u8]) -> io::Result<()> {
+        let target = self.base_dir.join(path);
+        let tmp = Self::tmp_path(&target);
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&tmp, &target)?;
+        File::open(target.parent().unwrap_or(&self.base_dir))?.sync_all()
+    }
+
+    /// Reads the file at `path` under the base directory
+    pub fn load_memory(&self, path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+        fs::read(self.base_dir.join(path))
+    }
+
+    fn tmp_path(target: &Path) -> PathBuf {
+        let mut name = target.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        target.with_file_name(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_save_and_load() -> io::Result<()> {
+        let dir = tempdir()?;
+        let storage = StorageManager::new(dir.path().join("memories"))?;
+
+        storage.save_memory("core.bin", b"first")?;
+        assert_eq!(storage.load_memory("core.bin")?, b"first");
+
+        storage.save_memory("core.bin", b"second")?;
+        assert_eq!(storage.load_memory("core.bin")?, b"second");
+        assert!(storage.load_memory("missing.bin").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_tmp_file_left_behind() -> io::Result<()> {
+        let dir = tempdir()?;
+        let storage = StorageManager::new(dir.path())?;
+
+        storage.save_memory("core.bin", b"data")?;
+
+        let names: Vec<_> = fs::read_dir(storage.base_dir())?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(names, vec!["core.bin"]);
+
+        Ok(())
     }
 }
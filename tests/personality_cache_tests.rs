@@ -4,10 +4,10 @@ use std::collections::HashSet;
 
 #[test]
 fn test_cache_add_and_retrieve() {
-    let mut cache = PersonalityCache::new(3, 0.5);
+    let cache = PersonalityCache::new(3, 0.5);
 
-    let entry1 = MemoryEntry::new(100, 500);
-    let entry2 = MemoryEntry::new(101, 600);
+    let entry1 = MemoryEntry::with_links(1, 100, 500, 0, 0);
+    let entry2 = MemoryEntry::with_links(2, 101, 600, 0, 0);
 
     let related1: HashSet<u16> = [200, 201].into_iter().collect();
     let related2: HashSet<u16> = [202].into_iter().collect();
@@ -17,11 +17,11 @@ fn test_cache_add_and_retrieve() {
 
     // Test retrieval
     assert_eq!(cache.get_memory(entry1.epoch()).unwrap().token(), entry1.token());
-    assert_eq!(cache.get_related_tokens(entry1.epoch()).unwrap(), &related1);
+    assert_eq!(cache.get_related_tokens(entry1.epoch()).unwrap(), related1);
 
     // Test eviction policy
-    let entry3 = MemoryEntry::new(102, 700);
-    let entry4 = MemoryEntry::new(103, 800);
+    let entry3 = MemoryEntry::with_links(3, 102, 700, 0, 0);
+    let entry4 = MemoryEntry::with_links(4, 103, 800, 0, 0);
 
     cache.add_memory(entry3.clone(), HashSet::new());
     cache.add_memory(entry4.clone(), HashSet::new());